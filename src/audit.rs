@@ -0,0 +1,370 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::Write,
+    os::unix::prelude::{ExitStatusExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    caller::Caller,
+    rpc::{Exec, Limit, Rejection},
+};
+
+const REDACTED: &str = "***";
+
+/// Appends one JSON object per line to `path`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// Replace every argument with `***`.
+    #[serde(default)]
+    pub redact_args: bool,
+    /// Replace the values (but not the names) of environment variables.
+    #[serde(default = "default_redact_env")]
+    pub redact_env: bool,
+}
+
+fn default_redact_env() -> bool {
+    true
+}
+
+/// Records are written by a thread of their own, so that a slow disk holds
+/// up neither the runtime nor whoever records next.
+pub struct Auditor {
+    /// Lines for the writer; only `None` while dropped.
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+    redact_args: bool,
+    redact_env: bool,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    caller: &'a Caller,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exec: Option<ExecRecord<'a>>,
+    #[serde(flatten)]
+    outcome: Outcome<'a>,
+}
+
+/// Byte strings that are not UTF-8 are recorded lossily.
+#[derive(Serialize)]
+struct ExecRecord<'a> {
+    cmd: Cow<'a, str>,
+    args: Vec<Cow<'a, str>>,
+    env: BTreeMap<Cow<'a, str>, Cow<'a, str>>,
+    cwd: Option<Cow<'a, str>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Outcome<'a> {
+    Rejected(&'a Rejection),
+    SpawnFailed {
+        error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        errno: Option<i32>,
+    },
+    Spawned {
+        pid: Option<u32>,
+    },
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+        /// Set if the command was killed for exceeding a limit.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<Limit>,
+        #[serde(flatten)]
+        usage: Usage,
+    },
+    /// The client went away and the command was killed.
+    Disconnected {
+        #[serde(flatten)]
+        usage: Usage,
+    },
+    /// Nothing was run because the agent is in dry-run mode.
+    DryRun {
+        /// What the request would have been refused with.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rejection: Option<&'a Rejection>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub duration_ms: u64,
+    pub stdin_bytes: u64,
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+}
+
+impl Auditor {
+    pub fn open(config: AuditConfig) -> Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&config.path)
+            .with_context(|| format!("open audit log {}", config.path.display()))?;
+        let (lines, lines_rx) = mpsc::channel::<Vec<u8>>();
+        let writer = thread::Builder::new()
+            .name("audit".into())
+            .spawn(move || {
+                let mut file = file;
+                for line in lines_rx {
+                    if let Err(err) = file.write_all(&line) {
+                        log::error!("Failed to write audit log: {}", err);
+                    }
+                }
+            })
+            .context("start audit log writer")?;
+        Ok(Self {
+            lines: Some(lines),
+            writer: Some(writer),
+            redact_args: config.redact_args,
+            redact_env: config.redact_env,
+        })
+    }
+
+    pub fn record(&self, caller: &Caller, exec: Option<&Exec>, outcome: Outcome) {
+        let record = Record {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            caller,
+            exec: exec.map(|exec| self.exec_record(exec)),
+            outcome,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                log::error!("Failed to write audit log: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Some(lines) = &self.lines {
+            // the writer only stops once the sender is gone
+            let _ = lines.send(line);
+        }
+    }
+
+    fn exec_record<'a>(&self, exec: &'a Exec) -> ExecRecord<'a> {
+        ExecRecord {
+            cmd: exec.cmd.to_string_lossy(),
+            args: exec
+                .args
+                .iter()
+                .map(|arg| {
+                    if self.redact_args {
+                        REDACTED.into()
+                    } else {
+                        arg.to_string_lossy()
+                    }
+                })
+                .collect(),
+            env: exec
+                .envs
+                .iter()
+                .map(|(key, value)| {
+                    let value = if self.redact_env {
+                        REDACTED.into()
+                    } else {
+                        value.to_string_lossy()
+                    };
+                    (key.to_string_lossy(), value)
+                })
+                .collect(),
+            cwd: exec.cwd.as_deref().map(Path::to_string_lossy),
+        }
+    }
+}
+
+impl Drop for Auditor {
+    /// Waits for what was recorded to be written.
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Tracks one spawned command, and records it as `disconnected` if the
+/// last clone is dropped before it was seen to exit.
+#[derive(Clone)]
+pub struct ExecAudit(Arc<Mutex<Tracked>>);
+
+struct Tracked {
+    auditor: Arc<Auditor>,
+    caller: Caller,
+    exec: Exec,
+    started: Instant,
+    usage: Usage,
+    finished: bool,
+}
+
+impl ExecAudit {
+    pub fn new(auditor: Arc<Auditor>, caller: Caller, exec: Exec) -> Self {
+        Self(Arc::new(Mutex::new(Tracked {
+            auditor,
+            caller,
+            exec,
+            started: Instant::now(),
+            usage: Usage::default(),
+            finished: false,
+        })))
+    }
+
+    pub fn stdin(&self, len: usize) {
+        self.0.lock().unwrap().usage.stdin_bytes += len as u64;
+    }
+
+    pub fn stdout(&self, len: usize) {
+        self.0.lock().unwrap().usage.stdout_bytes += len as u64;
+    }
+
+    pub fn stderr(&self, len: usize) {
+        self.0.lock().unwrap().usage.stderr_bytes += len as u64;
+    }
+
+    pub fn exited(&self, status: ExitStatus, limit: Option<Limit>) {
+        self.0.lock().unwrap().exited(status, limit);
+    }
+}
+
+impl Tracked {
+    fn exited(&mut self, status: ExitStatus, limit: Option<Limit>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        let usage = self.usage();
+        self.auditor.record(
+            &self.caller,
+            Some(&self.exec),
+            Outcome::Exited {
+                code: status.code(),
+                signal: status.signal(),
+                limit,
+                usage,
+            },
+        );
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            ..self.usage
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if !self.finished {
+            let usage = self.usage();
+            self.auditor.record(
+                &self.caller,
+                Some(&self.exec),
+                Outcome::Disconnected { usage },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::rpc::RejectionKind;
+
+    fn exec() -> Exec {
+        Exec {
+            envs: [("TOKEN".into(), "secret".into())].into_iter().collect(),
+            cwd: Some("/src".into()),
+            ..Exec::new("git", &["push"])
+        }
+    }
+
+    /// Records with `record` and returns the lines written.
+    fn audit(name: &str, redact_args: bool, record: impl FnOnce(Arc<Auditor>)) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!(
+            "ssh-rev-audit-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let config = AuditConfig {
+            path: path.clone(),
+            redact_args,
+            redact_env: true,
+        };
+        let auditor = Arc::new(Auditor::open(config).unwrap());
+        record(auditor);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_records() {
+        let lines = audit("records", false, |auditor| {
+            let rejection = Rejection {
+                rule: Some("no-push".into()),
+                ..Rejection::new(RejectionKind::Policy, "denied")
+            };
+            auditor.record(
+                &Caller::default(),
+                Some(&exec()),
+                Outcome::Rejected(&rejection),
+            );
+            let tracked = ExecAudit::new(auditor.clone(), Caller::default(), exec());
+            tracked.stdin(3);
+            tracked.stdout(5);
+            tracked.exited(ExitStatus::from_raw(3 << 8), None);
+            let lost = ExecAudit::new(auditor, Caller::default(), exec());
+            lost.stderr(7);
+        });
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["event"], "rejected");
+        assert_eq!(lines[0]["rule"], "no-push");
+        assert_eq!(lines[0]["exec"]["args"][0], "push");
+        assert_eq!(lines[0]["exec"]["env"]["TOKEN"], REDACTED);
+        assert_eq!(lines[0]["exec"]["cwd"], "/src");
+        assert_eq!(lines[1]["event"], "exited");
+        assert_eq!(lines[1]["code"], 3);
+        assert_eq!(
+            (&lines[1]["stdin_bytes"], &lines[1]["stdout_bytes"]),
+            (&3.into(), &5.into())
+        );
+        assert_eq!(lines[2]["event"], "disconnected");
+        assert_eq!(lines[2]["stderr_bytes"], 7);
+    }
+
+    #[test]
+    fn test_redact_args() {
+        let lines = audit("redact", true, |auditor| {
+            auditor.record(
+                &Caller::default(),
+                Some(&exec()),
+                Outcome::Spawned { pid: Some(1) },
+            );
+        });
+        assert_eq!(lines[0]["event"], "spawned");
+        assert_eq!(lines[0]["exec"]["cmd"], "git");
+        assert_eq!(lines[0]["exec"]["args"][0], REDACTED);
+    }
+}
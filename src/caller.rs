@@ -0,0 +1,243 @@
+use std::{env, fmt, path::PathBuf};
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine as _,
+};
+use bytes::Bytes;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::net::UnixStream;
+
+use crate::ssh_agent::{get_string, SessionBind};
+
+/// What the agent knows about the other end of a client connection.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Caller {
+    /// Sequence number of the connection since the agent started.
+    pub connection: u64,
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    /// Every `session-bind@openssh.com` seen on the connection, in order.
+    pub bindings: Vec<HostBinding>,
+    /// Set once a request was authenticated by a paired client.
+    pub paired_client: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostBinding {
+    pub key_type: String,
+    /// In the same `SHA256:...` form as `ssh-keygen -l` prints.
+    pub fingerprint: String,
+    /// Names of the key in `known_hosts`, if any.
+    pub hosts: Vec<String>,
+    pub is_forwarding: bool,
+    /// Whether the upstream agent accepted the binding, which means it
+    /// checked the host signature.
+    pub verified: bool,
+}
+
+impl Caller {
+    pub fn from_stream(connection: u64, stream: &UnixStream) -> Self {
+        match stream.peer_cred() {
+            Ok(cred) => Self {
+                connection,
+                pid: cred.pid(),
+                uid: Some(cred.uid()),
+                bindings: vec![],
+                paired_client: None,
+            },
+            Err(err) => {
+                log::warn!("Failed to get peer credentials: {}", err);
+                Self {
+                    connection,
+                    ..Self::default()
+                }
+            }
+        }
+    }
+
+    /// The host that is using the forwarded agent, i.e. the destination of
+    /// the last forwarding hop.
+    pub fn remote_host(&self) -> Option<&HostBinding> {
+        self.bindings
+            .iter()
+            .rev()
+            .find(|binding| binding.is_forwarding)
+    }
+
+    /// Like [`Caller::remote_host`], but only if the binding was verified.
+    pub fn verified_remote_host(&self) -> Option<&HostBinding> {
+        self.remote_host().filter(|binding| binding.verified)
+    }
+
+    /// Who detached jobs belong to: the paired client if there is one,
+    /// otherwise the verified remote host, otherwise the local user.
+    /// Unlike throttling, this must not go by bindings anyone could send.
+    pub fn owner(&self) -> String {
+        if let Some(client_id) = &self.paired_client {
+            return format!("client {}", client_id);
+        }
+        if let Some(host) = self.verified_remote_host() {
+            return format!("host {}", host.fingerprint);
+        }
+        match self.uid {
+            Some(uid) => format!("uid {}", uid),
+            None => "unknown caller".into(),
+        }
+    }
+}
+
+impl HostBinding {
+    pub async fn new(bind: &SessionBind, verified: bool) -> Self {
+        let key_type = get_string(&mut bind.host_key.clone())
+            .map(|key_type| String::from_utf8_lossy(&key_type).into_owned())
+            .unwrap_or_default();
+        let fingerprint = format!(
+            "SHA256:{}",
+            STANDARD_NO_PAD.encode(Sha256::digest(&bind.host_key))
+        );
+        Self {
+            key_type,
+            fingerprint,
+            hosts: known_hosts_names(&bind.host_key, &known_hosts_files()).await,
+            is_forwarding: bind.is_forwarding,
+            verified,
+        }
+    }
+
+    /// Whether `pattern` is this binding's fingerprint or one of its names.
+    pub fn matches(&self, pattern: &str) -> bool {
+        self.fingerprint == pattern || self.hosts.iter().any(|host| host == pattern)
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.pid, self.uid) {
+            (Some(pid), Some(uid)) => write!(f, "pid {} (uid {})", pid, uid)?,
+            (None, Some(uid)) => write!(f, "uid {}", uid)?,
+            _ => f.write_str("unknown")?,
+        }
+        if let Some(host) = self.remote_host() {
+            write!(f, " via {}", host)?;
+        }
+        if let Some(client_id) = &self.paired_client {
+            write!(f, " paired as {}", client_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for HostBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(host) = self.hosts.first() {
+            write!(f, "{} ", host)?;
+        }
+        write!(f, "({} {})", self.key_type, self.fingerprint)?;
+        if !self.verified {
+            f.write_str(" [unverified]")?;
+        }
+        Ok(())
+    }
+}
+
+/// The user's and the system's `known_hosts`.
+fn known_hosts_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from("/etc/ssh/ssh_known_hosts")];
+    if let Some(home) = env::var_os("HOME") {
+        files.insert(0, PathBuf::from(home).join(".ssh/known_hosts"));
+    }
+    files
+}
+
+/// Looks `host_key` up in `files`. Hashed entries are skipped because their
+/// names cannot be recovered.
+async fn known_hosts_names(host_key: &Bytes, files: &[PathBuf]) -> Vec<String> {
+    let encoded = STANDARD.encode(host_key);
+    let mut names = vec![];
+    for file in files {
+        let Ok(text) = tokio::fs::read_to_string(file).await else {
+            continue;
+        };
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let Some(hosts) = fields.next() else {
+                continue;
+            };
+            if hosts.starts_with('#') || hosts.starts_with('@') || hosts.starts_with('|') {
+                continue;
+            }
+            if fields.nth(1) != Some(encoded.as_str()) {
+                continue;
+            }
+            names.extend(
+                hosts
+                    .split(',')
+                    .filter(|host| !host.starts_with('!'))
+                    .map(str::to_owned),
+            );
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+
+    use super::*;
+    use crate::ssh_agent::put_string;
+
+    const HOST_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIMSvmplJZJJgQVD25hbK7wY1gAhPS86xvIp76IQVMfa4";
+
+    fn bind(is_forwarding: bool) -> SessionBind {
+        let mut bytes = BytesMut::new();
+        put_string(&mut bytes, &STANDARD.decode(HOST_KEY).unwrap());
+        put_string(&mut bytes, b"session");
+        put_string(&mut bytes, b"signature");
+        bytes.put_u8(is_forwarding as u8);
+        SessionBind::try_from(bytes.freeze()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_host_binding() {
+        let binding = HostBinding::new(&bind(true), true).await;
+        assert_eq!(binding.key_type, "ssh-ed25519");
+        // as printed by `ssh-keygen -l`
+        assert_eq!(
+            binding.fingerprint,
+            "SHA256:8Ap346WPKDo7GqIZYb3S28uUrSHDRSZqNCTroFVPGpU"
+        );
+        assert!(binding.matches("SHA256:8Ap346WPKDo7GqIZYb3S28uUrSHDRSZqNCTroFVPGpU"));
+
+        let caller = Caller {
+            bindings: vec![binding, HostBinding::new(&bind(false), true).await],
+            ..Caller::default()
+        };
+        assert!(caller.remote_host().unwrap().is_forwarding);
+        assert_eq!(
+            caller.owner(),
+            format!("host {}", caller.bindings[0].fingerprint)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_known_hosts_names() {
+        let path = std::env::temp_dir().join(format!("ssh-rev-known-hosts-{}", std::process::id()));
+        let text = format!(
+            "# example.org ssh-ed25519 {key}\n\
+             |1|c2FsdA==|aGFzaA== ssh-ed25519 {key}\n\
+             @cert-authority *.example.com ssh-ed25519 {key}\n\
+             other.example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOther\n\
+             example.com,!bad.example.com,10.0.0.1 ssh-ed25519 {key} comment\n",
+            key = HOST_KEY
+        );
+        std::fs::write(&path, text).unwrap();
+        let host_key = Bytes::from(STANDARD.decode(HOST_KEY).unwrap());
+        let missing = path.with_extension("missing");
+        let names = known_hosts_names(&host_key, &[missing, path.clone()]).await;
+        assert_eq!(names, vec!["example.com", "10.0.0.1"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
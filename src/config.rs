@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    audit::AuditConfig, confirm::ConfirmConfig, connection::ConnectionConfig,
+    dry_run::DryRunConfig, env_policy::EnvPolicy, jobs::JobConfig, limits::Limits,
+    pairing::PairingConfig, paths::PathConfig, policy::Policy, throttle::ThrottleConfig,
+    verbs::Verbs,
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub connection: ConnectionConfig,
+    #[serde(default)]
+    pub env: EnvPolicy,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
+    #[serde(default)]
+    pub verbs: Verbs,
+    #[serde(default)]
+    pub paths: PathConfig,
+    #[serde(default)]
+    pub jobs: JobConfig,
+    pub confirm: Option<ConfirmConfig>,
+    pub pairing: Option<PairingConfig>,
+    pub audit: Option<AuditConfig>,
+    pub dry_run: Option<DryRunConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read config file {}", path.display()))?;
+        let config =
+            toml::from_str(&text).with_context(|| format!("parse config file {}", path.display()))?;
+        Ok(config)
+    }
+}
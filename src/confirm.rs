@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use tokio::process;
+
+use crate::{
+    caller::Caller,
+    rpc::{Exec, Rejection, RejectionKind},
+};
+
+/// Asks the local user before running anything, the same way `ssh-add -c`
+/// does: the prompt program is invoked with `SSH_ASKPASS_PROMPT=confirm`
+/// and the prompt text as its only argument, and exit status 0 approves.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfirmConfig {
+    /// Defaults to `$SSH_ASKPASS`.
+    pub program: Option<PathBuf>,
+    /// How long an approval is remembered for the same request: command
+    /// line, cwd, environment and caller.
+    #[serde(default)]
+    pub cache_secs: u64,
+}
+
+pub struct Confirmer {
+    program: OsString,
+    cache: Duration,
+    approvals: Mutex<HashMap<ApprovalKey, Instant>>,
+}
+
+/// What has to be the same for an approval to count again.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct ApprovalKey {
+    argv: Vec<OsString>,
+    cwd: Option<PathBuf>,
+    /// Sorted by name.
+    envs: Vec<(OsString, OsString)>,
+    /// [`Caller::owner`].
+    owner: String,
+    /// Fingerprint of the remote host, even if unverified, since the
+    /// prompt shows it.
+    host: Option<String>,
+}
+
+impl ApprovalKey {
+    fn new(exec: &Exec, caller: &Caller) -> Self {
+        let mut envs: Vec<_> = exec
+            .envs
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        envs.sort();
+        Self {
+            argv: [&exec.cmd].into_iter().chain(&exec.args).cloned().collect(),
+            cwd: exec.cwd.clone(),
+            envs,
+            owner: caller.owner(),
+            host: caller.remote_host().map(|host| host.fingerprint.clone()),
+        }
+    }
+}
+
+impl Confirmer {
+    pub fn new(config: ConfirmConfig) -> Result<Self> {
+        let program = match config.program {
+            Some(program) => program.into_os_string(),
+            None => env::var_os("SSH_ASKPASS")
+                .ok_or_else(|| anyhow!("confirm.program is not set and neither is SSH_ASKPASS"))?,
+        };
+        Ok(Self {
+            program,
+            cache: Duration::from_secs(config.cache_secs),
+            approvals: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn confirm(&self, exec: &Exec, caller: &Caller) -> Result<(), Rejection> {
+        let key = ApprovalKey::new(exec, caller);
+        if self.is_approved(&key) {
+            log::debug!("Using cached approval for {:?}", key.argv);
+            return Ok(());
+        }
+        let prompt = format!(
+            "Allow a remote host to run a command on this machine?\n\n\
+             command: {}\ncwd: {}\ncaller: {}",
+            format_command_line(&key.argv),
+            exec.cwd
+                .as_deref()
+                .map_or("(default)".into(), Path::to_string_lossy),
+            caller,
+        );
+        let status = process::Command::new(&self.program)
+            .arg(&prompt)
+            .env("SSH_ASKPASS_PROMPT", "confirm")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .await;
+        match status {
+            Ok(status) if status.success() => {
+                if !self.cache.is_zero() {
+                    let mut approvals = self.approvals.lock().unwrap();
+                    approvals.insert(key, Instant::now() + self.cache);
+                }
+                Ok(())
+            }
+            Ok(_) => Err(Rejection::new(
+                RejectionKind::DeniedByUser,
+                format!("`{}` was denied by user", exec.cmd.to_string_lossy()),
+            )),
+            Err(err) => {
+                log::error!("Failed to run confirmation program: {}", err);
+                Err(Rejection::new(
+                    RejectionKind::DeniedByUser,
+                    format!(
+                        "`{}` could not be confirmed: {}",
+                        exec.cmd.to_string_lossy(),
+                        err
+                    ),
+                ))
+            }
+        }
+    }
+
+    fn is_approved(&self, key: &ApprovalKey) -> bool {
+        let mut approvals = self.approvals.lock().unwrap();
+        let now = Instant::now();
+        approvals.retain(|_, expires_at| *expires_at > now);
+        approvals.contains_key(key)
+    }
+}
+
+fn format_command_line(argv: &[OsString]) -> String {
+    argv.iter()
+        .map(|arg| match arg.to_str() {
+            Some(arg)
+                if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"') =>
+            {
+                arg.to_owned()
+            }
+            // quoted, with bytes that are not UTF-8 escaped
+            _ => format!("{:?}", arg),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approving(cache: Duration) -> Confirmer {
+        Confirmer {
+            program: "true".into(),
+            cache,
+            approvals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn exec(cwd: &str) -> Exec {
+        Exec {
+            cwd: Some(cwd.into()),
+            ..Exec::new("make", &["install"])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_approval_is_cached_for_the_same_request() {
+        let mut confirmer = approving(Duration::from_secs(60));
+        let caller = Caller::default();
+        confirmer.confirm(&exec("/src"), &caller).await.unwrap();
+        // from now on, only cached approvals get through
+        confirmer.program = "false".into();
+        confirmer.confirm(&exec("/src"), &caller).await.unwrap();
+
+        let rejection = confirmer.confirm(&exec("/"), &caller).await.unwrap_err();
+        assert_eq!(rejection.kind, RejectionKind::DeniedByUser);
+        let mut with_env = exec("/src");
+        with_env.envs.insert("DESTDIR".into(), "/".into());
+        assert!(confirmer.confirm(&with_env, &caller).await.is_err());
+        let other = Caller {
+            paired_client: Some("laptop".into()),
+            ..Caller::default()
+        };
+        assert!(confirmer.confirm(&exec("/src"), &other).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_approval_expires() {
+        let mut confirmer = approving(Duration::from_millis(50));
+        let caller = Caller::default();
+        confirmer.confirm(&exec("/src"), &caller).await.unwrap();
+        confirmer.program = "false".into();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(confirmer.confirm(&exec("/src"), &caller).await.is_err());
+        assert!(confirmer.approvals.lock().unwrap().is_empty());
+
+        // nothing is remembered without a cache
+        let mut confirmer = approving(Duration::ZERO);
+        confirmer.confirm(&exec("/src"), &caller).await.unwrap();
+        confirmer.program = "false".into();
+        assert!(confirmer.confirm(&exec("/src"), &caller).await.is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::ssh_agent::{self, FRAME_OVERHEAD, MAX_FRAME_BYTES};
+
+/// Smallest `max_frame_bytes` that still leaves useful room for data.
+const MIN_FRAME_BYTES: usize = 4 * FRAME_OVERHEAD;
+
+/// Settings for the connections that clients make to the agent.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawConnectionConfig")]
+pub struct ConnectionConfig {
+    /// Longest ssh-rev message accepted from or sent to a client. Larger
+    /// stdin and output are split to fit. Forwarded agent messages may be
+    /// as long as [`MAX_FRAME_BYTES`] whatever it is.
+    pub max_frame_bytes: usize,
+    /// Bytes of stdin a client may send ahead of what the command has read,
+    /// whether or not it does flow control.
+    pub stdin_window_bytes: u32,
+    /// How long a v2 command outlives a lost connection, waiting for its
+    /// client to resume it. Zero kills it right away.
+    pub resume_grace_secs: u64,
+    /// How long a `Watch` waits for output before the agent replies with
+    /// no events, so that the client can tell a quiet command from a dead
+    /// connection. Zero sends no heartbeats.
+    pub keepalive_secs: u64,
+    /// How long a client that takes heartbeats may stay silent once it has
+    /// started a command before the agent drops the connection, which
+    /// kills or parks the command. Zero waits for as long as the connection
+    /// stays open.
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConnectionConfig {
+    #[serde(default = "default_max_frame_bytes")]
+    max_frame_bytes: usize,
+    #[serde(default = "default_stdin_window_bytes")]
+    stdin_window_bytes: u32,
+    #[serde(default)]
+    resume_grace_secs: u64,
+    #[serde(default = "default_keepalive_secs")]
+    keepalive_secs: u64,
+    #[serde(default)]
+    idle_timeout_secs: u64,
+}
+
+fn default_max_frame_bytes() -> usize {
+    MAX_FRAME_BYTES
+}
+
+fn default_stdin_window_bytes() -> u32 {
+    1024 * 1024
+}
+
+fn default_keepalive_secs() -> u64 {
+    15
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: default_max_frame_bytes(),
+            stdin_window_bytes: default_stdin_window_bytes(),
+            resume_grace_secs: 0,
+            keepalive_secs: default_keepalive_secs(),
+            idle_timeout_secs: 0,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// The largest chunk of stdout or stderr to put in one event.
+    pub fn max_chunk(&self) -> usize {
+        ssh_agent::max_chunk(self.max_frame_bytes)
+    }
+
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_secs)
+    }
+
+    pub fn keepalive(&self) -> Option<Duration> {
+        (self.keepalive_secs > 0).then(|| Duration::from_secs(self.keepalive_secs))
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+}
+
+impl TryFrom<RawConnectionConfig> for ConnectionConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawConnectionConfig) -> Result<Self> {
+        // clients and OpenSSH agents refuse anything longer, so the limit
+        // can only be lowered
+        if !(MIN_FRAME_BYTES..=MAX_FRAME_BYTES).contains(&raw.max_frame_bytes) {
+            bail!(
+                "max_frame_bytes must be between {} and {}",
+                MIN_FRAME_BYTES,
+                MAX_FRAME_BYTES
+            );
+        }
+        if raw.stdin_window_bytes == 0 {
+            bail!("stdin_window_bytes must not be zero");
+        }
+        // a client that is there would be dropped between two heartbeats
+        if raw.idle_timeout_secs > 0
+            && (raw.keepalive_secs == 0 || raw.idle_timeout_secs <= raw.keepalive_secs)
+        {
+            bail!("idle_timeout_secs must be longer than keepalive_secs, which must not be zero");
+        }
+        Ok(Self {
+            max_frame_bytes: raw.max_frame_bytes,
+            stdin_window_bytes: raw.stdin_window_bytes,
+            resume_grace_secs: raw.resume_grace_secs,
+            keepalive_secs: raw.keepalive_secs,
+            idle_timeout_secs: raw.idle_timeout_secs,
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::rpc::{Event, Exec, Rejection};
+
+/// Observe-only mode: requests are evaluated and logged, but nothing is
+/// run. The client sees `message` and the decision on stderr, and then
+/// `exit_code`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DryRunConfig {
+    #[serde(default)]
+    pub exit_code: i32,
+    #[serde(default = "default_message")]
+    pub message: String,
+}
+
+fn default_message() -> String {
+    "ssh-rev: the agent is in dry-run mode; nothing was run".into()
+}
+
+impl Default for DryRunConfig {
+    fn default() -> Self {
+        Self {
+            exit_code: 0,
+            message: default_message(),
+        }
+    }
+}
+
+impl DryRunConfig {
+    /// The events to reply with in place of running `exec`.
+    pub fn events(&self, exec: &Exec, decision: Result<(), &Rejection>) -> VecDeque<Event> {
+        let cmd = exec.cmd.to_string_lossy();
+        let decision = match decision {
+            Ok(()) => format!("ssh-rev: `{}` would have been run", cmd),
+            Err(rejection) => format!("ssh-rev: `{}` would have been refused: {}", cmd, rejection),
+        };
+        let stderr = format!("{}\n{}\n", self.message, decision);
+        VecDeque::from([
+            Event::Stderr(Bytes::from(stderr)),
+            Event::Exited(self.exit_code),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::RejectionKind;
+
+    fn stderr(events: &VecDeque<Event>) -> String {
+        match &events[0] {
+            Event::Stderr(data) => String::from_utf8(data.to_vec()).unwrap(),
+            _ => panic!("expected stderr"),
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let config: DryRunConfig = toml::from_str("exit_code = 3").unwrap();
+        let exec = Exec::new("open", &["https://example.com"]);
+
+        let events = config.events(&exec, Ok(()));
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            stderr(&events),
+            "ssh-rev: the agent is in dry-run mode; nothing was run\n\
+             ssh-rev: `open` would have been run\n"
+        );
+        assert!(matches!(events[1], Event::Exited(3)));
+
+        let rejection = Rejection::new(RejectionKind::Policy, "no rule allows `open`");
+        let events = config.events(&exec, Err(&rejection));
+        assert_eq!(
+            stderr(&events),
+            "ssh-rev: the agent is in dry-run mode; nothing was run\n\
+             ssh-rev: `open` would have been refused: no rule allows `open`\n"
+        );
+        assert!(matches!(events[1], Event::Exited(3)));
+    }
+
+    #[test]
+    fn test_default() {
+        let config: DryRunConfig = toml::from_str(r#"message = "dry run""#).unwrap();
+        assert_eq!(config.exit_code, 0);
+        assert_eq!(config.message, "dry run");
+        assert!(toml::from_str::<DryRunConfig>("exitcode = 1").is_err());
+    }
+}
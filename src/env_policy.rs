@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    env,
+    ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+};
+
+use serde::Deserialize;
+use tokio::process;
+
+use crate::rpc::{Exec, Rejection, RejectionKind};
+
+/// Names that change how programs, loaders or shells behave, and so would
+/// let a remote host run something other than what the policy allowed.
+/// They are denied whatever the configuration says.
+const DEFAULT_DENY: &[&str] = &[
+    "LD_*",
+    "DYLD_*",
+    "PATH",
+    "IFS",
+    "ENV",
+    "BASH_ENV",
+    "BASH_FUNC_*",
+    "SHELLOPTS",
+    "PERL5LIB",
+    "PERL5OPT",
+    "PYTHONPATH",
+    "PYTHONSTARTUP",
+    "RUBYOPT",
+    "NODE_OPTIONS",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnDeny {
+    /// Run the command without the offending variables.
+    Drop,
+    /// Refuse to run the command at all.
+    Reject,
+}
+
+/// Decides which requested environment variables reach the spawned command.
+/// Patterns may contain `*`, which matches any run of characters.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct EnvPolicy {
+    /// Names that are never accepted from a request, on top of the
+    /// built-in list.
+    pub deny: Vec<String>,
+    /// If not empty, only these names are accepted from a request.
+    pub allow: Vec<String>,
+    pub on_deny: OnDeny,
+    /// Start from an empty environment instead of the agent's own.
+    pub clear: bool,
+    /// Variables of the agent's environment to keep when `clear` is set.
+    pub keep: Vec<String>,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        Self {
+            deny: vec![],
+            allow: vec![],
+            on_deny: OnDeny::Drop,
+            clear: false,
+            keep: vec![],
+        }
+    }
+}
+
+impl EnvPolicy {
+    /// Removes the variables of `exec` that are not accepted, or refuses the
+    /// request if any is found and `on_deny` says so.
+    pub fn filter(&self, exec: &mut Exec) -> Result<(), Rejection> {
+        // such a name would set another variable than the one checked
+        if let Some(name) = exec.envs.keys().find(|name| !is_valid_name(name)) {
+            return Err(Rejection::new(
+                RejectionKind::Env,
+                format!("{:?} is not a valid environment variable name", name),
+            ));
+        }
+        let mut denied: Vec<OsString> = exec
+            .envs
+            .keys()
+            .filter(|name| !self.accepts(name))
+            .cloned()
+            .collect();
+        if denied.is_empty() {
+            return Ok(());
+        }
+        denied.sort();
+        match self.on_deny {
+            OnDeny::Drop => {
+                log::info!("Dropping environment variables {:?}", denied);
+                exec.envs.retain(|name, _| !denied.contains(name));
+                Ok(())
+            }
+            OnDeny::Reject => Err(Rejection::new(
+                RejectionKind::Env,
+                format!(
+                    "environment variables {} are not allowed",
+                    denied
+                        .iter()
+                        .map(|name| name.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        }
+    }
+
+    /// Sets up the environment of `command` before the requested variables
+    /// are added.
+    pub fn prepare(&self, command: &mut process::Command) {
+        if !self.clear {
+            return;
+        }
+        command.env_clear();
+        let kept: HashMap<String, String> = env::vars()
+            .filter(|(name, _)| {
+                self.keep
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, name))
+            })
+            .collect();
+        command.envs(kept);
+    }
+
+    /// Names that are not UTF-8 are never accepted.
+    fn accepts(&self, name: &OsStr) -> bool {
+        let Some(name) = name.to_str() else {
+            return false;
+        };
+        let denied = DEFAULT_DENY
+            .iter()
+            .copied()
+            .chain(self.deny.iter().map(String::as_str))
+            .any(|pattern| wildcard_match(pattern, name));
+        let allowed = self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|pattern| wildcard_match(pattern, name));
+        !denied && allowed
+    }
+}
+
+fn is_valid_name(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    !name.is_empty() && !name.contains(&b'=') && !name.contains(&0)
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(name) = name.strip_prefix(prefix) else {
+        return false;
+    };
+    if !rest.contains('*') {
+        return name.ends_with(rest);
+    }
+    (0..=name.len())
+        .filter(|&i| name.is_char_boundary(i))
+        .any(|i| wildcard_match(rest, &name[i..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("LD_*", "LD_PRELOAD"));
+        assert!(wildcard_match("PATH", "PATH"));
+        assert!(!wildcard_match("PATH", "MANPATH"));
+        assert!(wildcard_match("*PATH", "MANPATH"));
+        assert!(wildcard_match("A*B*C", "AxxBxxC"));
+        assert!(!wildcard_match("A*B*C", "AxxCxxB"));
+    }
+
+    #[test]
+    fn test_default_drops_dangerous_names() {
+        let mut exec = Exec {
+            envs: [("LD_PRELOAD", "evil.so"), ("LANG", "C"), ("PATH", "/tmp")]
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+            ..Exec::new("true", &[])
+        };
+        EnvPolicy::default().filter(&mut exec).unwrap();
+        assert_eq!(exec.envs.keys().collect::<Vec<_>>(), vec!["LANG"]);
+    }
+
+    fn exec_with_env(name: &str) -> Exec {
+        Exec {
+            envs: [(name.into(), "y".into())].into_iter().collect(),
+            ..Exec::new("true", &[])
+        }
+    }
+
+    #[test]
+    fn test_invalid_names_are_rejected() {
+        let policy: EnvPolicy = toml::from_str(r#"allow = ["NODE_OPTIONS*", "X*"]"#).unwrap();
+        for name in ["NODE_OPTIONS=--require /tmp/evil.js --x", "X\0", ""] {
+            let rejection = policy.filter(&mut exec_with_env(name)).unwrap_err();
+            assert_eq!(rejection.kind, RejectionKind::Env);
+        }
+    }
+
+    #[test]
+    fn test_configured_deny_adds_to_defaults() {
+        let policy: EnvPolicy = toml::from_str(r#"deny = ["AWS_*"]"#).unwrap();
+        for name in ["AWS_SECRET_ACCESS_KEY", "LD_PRELOAD", "NODE_OPTIONS"] {
+            let mut exec = exec_with_env(name);
+            policy.filter(&mut exec).unwrap();
+            assert!(exec.envs.is_empty(), "{} was accepted", name);
+        }
+        let mut exec = exec_with_env("LANG");
+        policy.filter(&mut exec).unwrap();
+        assert_eq!(exec.envs.len(), 1);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch, Notify};
+
+use crate::rpc::{Event, Exec, JobId, JobStatus, Rejection, RejectionKind};
+
+/// Settings for commands that keep running after the client that started
+/// them has gone.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    /// Jobs kept at once. Finished jobs count until they are waited for,
+    /// but make room for new ones, oldest first.
+    #[serde(default = "default_max_jobs")]
+    pub max_jobs: usize,
+    /// Bytes of output kept per job for `attach`; older output is dropped.
+    #[serde(default = "default_output_bytes")]
+    pub output_bytes: usize,
+}
+
+fn default_max_jobs() -> usize {
+    32
+}
+
+fn default_output_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            max_jobs: default_max_jobs(),
+            output_bytes: default_output_bytes(),
+        }
+    }
+}
+
+/// The agent-wide table of detached jobs.
+pub struct Jobs {
+    config: JobConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    last_id: JobId,
+    jobs: BTreeMap<JobId, Arc<Job>>,
+}
+
+pub struct Job {
+    pub id: JobId,
+    /// [`Caller::owner`](crate::caller::Caller::owner) of the client that
+    /// started it; nobody else may see it.
+    owner: String,
+    command: String,
+    started_at: SystemTime,
+    output: Mutex<Output>,
+    /// Touched whenever `output` changes.
+    changed: watch::Sender<()>,
+    kill: Notify,
+}
+
+struct Output {
+    /// Events from number `first` on; earlier ones were dropped to keep
+    /// `bytes` within `max_bytes`.
+    events: VecDeque<Event>,
+    first: u64,
+    bytes: usize,
+    max_bytes: usize,
+    /// The last event, once the job is over.
+    end: Option<Event>,
+}
+
+impl Jobs {
+    pub fn new(config: JobConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Enters a job for `exec`, which the caller then has to start.
+    pub fn add(&self, owner: String, exec: &Exec) -> Result<Arc<Job>, Rejection> {
+        let mut state = self.state.lock().unwrap();
+        if state.jobs.len() >= self.config.max_jobs {
+            let finished = state
+                .jobs
+                .values()
+                .find(|job| job.is_over())
+                .map(|job| job.id);
+            match finished {
+                Some(id) => {
+                    state.jobs.remove(&id);
+                }
+                None => {
+                    return Err(Rejection::new(
+                        RejectionKind::Busy,
+                        format!(
+                            "the agent already runs {} detached jobs",
+                            self.config.max_jobs
+                        ),
+                    ))
+                }
+            }
+        }
+        state.last_id += 1;
+        let job = Arc::new(Job {
+            id: state.last_id,
+            owner,
+            command: command_line(exec),
+            started_at: SystemTime::now(),
+            output: Mutex::new(Output {
+                events: VecDeque::new(),
+                first: 0,
+                bytes: 0,
+                max_bytes: self.config.output_bytes,
+                end: None,
+            }),
+            changed: watch::channel(()).0,
+            kill: Notify::new(),
+        });
+        state.jobs.insert(job.id, job.clone());
+        Ok(job)
+    }
+
+    /// The job `id` if it belongs to `owner`.
+    pub fn get(&self, id: JobId, owner: &str) -> Result<Arc<Job>, Rejection> {
+        let state = self.state.lock().unwrap();
+        match state.jobs.get(&id) {
+            Some(job) if job.owner == owner => Ok(job.clone()),
+            _ => Err(Rejection::new(
+                RejectionKind::UnknownJob,
+                format!("there is no job {}", id),
+            )),
+        }
+    }
+
+    pub fn list(&self, owner: &str) -> Vec<JobStatus> {
+        let state = self.state.lock().unwrap();
+        state
+            .jobs
+            .values()
+            .filter(|job| job.owner == owner)
+            .map(|job| job.status())
+            .collect()
+    }
+
+    pub fn remove(&self, id: JobId) {
+        self.state.lock().unwrap().jobs.remove(&id);
+    }
+}
+
+impl Job {
+    /// Keeps `event` for those who attach. Output events over the limit
+    /// push out the oldest ones.
+    pub fn push(&self, event: Event) {
+        let mut output = self.output.lock().unwrap();
+        match event {
+            event if event.is_last() => output.end = Some(event),
+            event => {
+                output.bytes += event.data_len();
+                output.events.push_back(event);
+                while output.bytes > output.max_bytes {
+                    let Some(dropped) = output.events.pop_front() else {
+                        break;
+                    };
+                    output.bytes -= dropped.data_len();
+                    output.first += 1;
+                }
+            }
+        }
+        self.changed.send_replace(());
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.output.lock().unwrap().end.is_some()
+    }
+
+    pub fn status(&self) -> JobStatus {
+        let output = self.output.lock().unwrap();
+        let end = output.end.as_ref();
+        let killed_for = match end {
+            Some(Event::LimitExceeded(limit)) => Some(*limit),
+            _ => None,
+        };
+        let signal = match end {
+            Some(Event::Signaled { signal, .. }) => Some(*signal),
+            _ => None,
+        };
+        JobStatus {
+            id: self.id,
+            command: self.command.clone(),
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            exit_code: end.and_then(Event::exit_code),
+            killed_for,
+            signal,
+        }
+    }
+
+    /// Asks whoever runs the job to kill it.
+    pub fn kill(&self) {
+        self.kill.notify_one();
+    }
+
+    /// Resolves once the job was asked to be killed.
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    /// Resolves once the job is over.
+    pub async fn wait(&self) -> JobStatus {
+        let mut changed = self.changed.subscribe();
+        while !self.is_over() {
+            if changed.changed().await.is_err() {
+                break;
+            }
+        }
+        self.status()
+    }
+
+    /// Sends the output kept so far, then the output to come, and finally
+    /// the exit as `tag(event)`, or stops early once `events` is closed.
+    pub async fn follow<T>(
+        self: Arc<Self>,
+        events: mpsc::Sender<T>,
+        tag: impl Fn(Event) -> T,
+    ) -> Result<()> {
+        let mut changed = self.changed.subscribe();
+        // number of the next event to send
+        let mut next: u64 = 0;
+        loop {
+            changed.borrow_and_update();
+            let (new, end) = {
+                let output = self.output.lock().unwrap();
+                let skip = next.saturating_sub(output.first) as usize;
+                next = output.first + output.events.len() as u64;
+                let new: Vec<_> = output.events.iter().skip(skip).cloned().collect();
+                (new, output.end.clone())
+            };
+            for event in new.into_iter().chain(end.clone()) {
+                if events.send(tag(event)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if end.is_some() || changed.changed().await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// `exec` as a line to show in job lists.
+fn command_line(exec: &Exec) -> String {
+    let mut line = exec.cmd.to_string_lossy().into_owned();
+    for arg in &exec.args {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_follow() {
+        let jobs = Jobs::new(JobConfig {
+            max_jobs: 1,
+            output_bytes: 8,
+        });
+        let exec = Exec::new("yes", &[]);
+        let job = jobs.add("uid 1000".into(), &exec).unwrap();
+        assert!(jobs.get(job.id, "uid 1001").is_err());
+        assert!(jobs.add("uid 1000".into(), &exec).is_err());
+        for _ in 0..3 {
+            job.push(Event::Stdout(Bytes::from_static(b"y\ny\n")));
+        }
+        job.push(Event::Exited(0));
+
+        let (events_tx, mut events_rx) = mpsc::channel(8);
+        job.clone().follow(events_tx, |event| event).await.unwrap();
+        let mut stdout = vec![];
+        while let Some(event) = events_rx.recv().await {
+            match event {
+                Event::Stdout(bytes) => stdout.extend_from_slice(&bytes),
+                Event::Exited(code) => assert_eq!(code, 0),
+                _ => panic!("unexpected event"),
+            }
+        }
+        // the oldest output did not fit
+        assert_eq!(stdout, b"y\ny\ny\ny\n");
+        assert_eq!(job.wait().await.exit_code, Some(0));
+
+        // a finished job makes room for a new one
+        let next = jobs.add("uid 1000".into(), &exec).unwrap();
+        assert_eq!(next.id, job.id + 1);
+        assert_eq!(jobs.list("uid 1000").len(), 1);
+    }
+}
@@ -2,7 +2,26 @@ mod ssh_agent;
 mod rev_agent;
 mod rev_exec;
 mod rpc;
+mod config;
+mod policy;
+mod caller;
+mod confirm;
+mod pairing;
+mod audit;
+mod env_policy;
+mod limits;
+mod throttle;
+mod verbs;
+mod paths;
+mod dry_run;
+mod connection;
+mod jobs;
+mod pty;
 
+pub use config::Config;
+pub use pairing::{
+    default_store_path, issue_code, ClientPairing, AGENT_STORE_FILE, CLIENT_STORE_FILE,
+};
 pub use rev_agent::RevAgent;
-pub use rev_exec::RevExec;
-pub use rpc::Exec;
+pub use rev_exec::{Mux, MuxSession, MuxStdin, RevExec};
+pub use rpc::{Compression, Event, Exec, JobId, JobStatus, Limit, Rejection, Signal, VerbCall};
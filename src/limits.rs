@@ -0,0 +1,70 @@
+use std::{io, time::Duration};
+
+use serde::Deserialize;
+use tokio::{process, time::Instant};
+
+/// Resource limits for spawned commands. The rlimits are inherited by
+/// everything the command starts in turn.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Wall-clock time after which the command is killed.
+    pub timeout_secs: Option<u64>,
+    /// `RLIMIT_CPU`; the command gets `SIGXCPU` once it is used up.
+    pub cpu_secs: Option<u64>,
+    /// `RLIMIT_AS`, in bytes.
+    pub address_space: Option<u64>,
+    /// `RLIMIT_NOFILE`.
+    pub open_files: Option<u64>,
+    /// Niceness to run commands with; without privileges it can only be
+    /// raised above the agent's own.
+    pub nice: Option<i32>,
+    /// Total bytes of stdout and stderr after which the command is killed.
+    pub max_output_bytes: Option<u64>,
+}
+
+impl Limits {
+    pub fn apply(&self, command: &mut process::Command) {
+        let mut rlimits = vec![];
+        if let Some(cpu_secs) = self.cpu_secs {
+            // one more second as the hard limit so that SIGXCPU is sent
+            // before the kernel resorts to SIGKILL
+            rlimits.push((libc::RLIMIT_CPU, cpu_secs, cpu_secs + 1));
+        }
+        if let Some(address_space) = self.address_space {
+            rlimits.push((libc::RLIMIT_AS, address_space, address_space));
+        }
+        if let Some(open_files) = self.open_files {
+            rlimits.push((libc::RLIMIT_NOFILE, open_files, open_files));
+        }
+        let nice = self.nice;
+        if rlimits.is_empty() && nice.is_none() {
+            return;
+        }
+        // SAFETY: the closure only makes async-signal-safe system calls.
+        unsafe {
+            command.pre_exec(move || {
+                for &(resource, soft, hard) in &rlimits {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: soft as libc::rlim_t,
+                        rlim_max: hard as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.timeout_secs
+            .map(|secs| Instant::now() + Duration::from_secs(secs))
+    }
+}
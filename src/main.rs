@@ -1,13 +1,22 @@
 use std::{
-    os::unix::prelude::FileTypeExt,
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    future::Future,
+    io::{BufRead, IsTerminal},
+    os::unix::prelude::{FileTypeExt, OsStrExt},
     path::{Path, PathBuf},
     process::exit,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser as _;
+use tokio::io::{Stderr, Stdin, Stdout};
 
-use ssh_rev::{Exec, RevAgent, RevExec};
+use ssh_rev::{
+    default_store_path, issue_code, ClientPairing, Compression, Config, Exec, JobId, JobStatus,
+    Rejection, RevAgent, RevExec, VerbCall, AGENT_STORE_FILE, CLIENT_STORE_FILE,
+};
 
 #[derive(clap::Parser, Debug)]
 struct Args {
@@ -19,6 +28,16 @@ struct Args {
 enum Command {
     Agent(CmdAgent),
     Exec(CmdExec),
+    Verb(CmdVerb),
+    PairCode(CmdPairCode),
+    Pair(CmdPair),
+    Jobs(CmdJobs),
+    /// Write out the output of a detached job until it exits
+    Attach(CmdJob),
+    /// Wait for a detached job to exit, and exit with its exit code
+    Wait(CmdJob),
+    /// Kill a detached job
+    Kill(CmdJob),
 }
 
 #[derive(clap::Args, Debug)]
@@ -27,48 +46,381 @@ struct CmdAgent {
     ssh_auth_sock: Option<PathBuf>,
     #[clap(long, short = 'R')]
     ssh_rev_sock: PathBuf,
+    #[clap(long, short = 'c')]
+    config: Option<PathBuf>,
+    /// Evaluate and log requests without running anything
+    #[clap(long)]
+    dry_run: bool,
 }
 
 #[derive(clap::Args, Debug)]
-struct CmdExec {
+struct ClientArgs {
     #[clap(env, long, short = 'A')]
     ssh_auth_sock: PathBuf,
     #[clap(long, short)]
-    env: Vec<String>,
+    env: Vec<OsString>,
     #[clap(long, short = 'C')]
-    cwd: Option<String>,
-    cmd: String,
-    args: Vec<String>,
+    cwd: Option<PathBuf>,
+    #[clap(long, env = "SSH_REV_PAIRING")]
+    pairing: Option<PathBuf>,
+    /// Keep retrying for up to this many seconds while the agent is busy
+    #[clap(long, default_value_t = 0)]
+    wait_busy: u64,
+    /// Run the command as a job that keeps running after ssh-rev exits,
+    /// and print its id
+    #[clap(long)]
+    detach: bool,
+    /// Deflate stdin and output in small chunks too, not only in bulk
+    #[clap(long, conflicts_with = "no_compress")]
+    compress: bool,
+    /// Send stdin and output as they are
+    #[clap(long)]
+    no_compress: bool,
+}
+
+impl ClientArgs {
+    fn compression(&self) -> Option<Compression> {
+        match (self.compress, self.no_compress) {
+            (_, true) => None,
+            (true, _) => Some(Compression::Always),
+            _ => Some(Compression::Auto),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct CmdExec {
+    #[command(flatten)]
+    client: ClientArgs,
+    /// Mark the argument at INDEX (starting at 0) as a path that the agent
+    /// may translate to a local one
+    #[clap(long = "path-arg", value_name = "INDEX")]
+    path_args: Vec<usize>,
+    /// Take over a command whose connection was lost, with the token that
+    /// was printed for it
+    #[clap(long, value_name = "TOKEN", conflicts_with = "cmd")]
+    resume: Option<String>,
+    /// Run the command on a terminal of the agent; the default when stdin
+    /// and stdout are terminals
+    #[clap(long, short = 't', conflicts_with = "no_tty")]
+    tty: bool,
+    /// Run the command with pipes even when stdin and stdout are terminals
+    #[clap(long, short = 'T')]
+    no_tty: bool,
+    #[clap(required_unless_present = "resume")]
+    cmd: Option<OsString>,
+    args: Vec<OsString>,
+}
+
+/// Invoke a verb registered on the agent
+#[derive(clap::Args, Debug)]
+struct CmdVerb {
+    #[command(flatten)]
+    client: ClientArgs,
+    verb: String,
+    /// Parameters as NAME=VALUE
+    params: Vec<String>,
+}
+
+/// List the detached jobs started from this host
+#[derive(clap::Args, Debug)]
+struct CmdJobs {
+    #[command(flatten)]
+    client: JobClientArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct CmdJob {
+    #[command(flatten)]
+    client: JobClientArgs,
+    id: JobId,
+}
+
+#[derive(clap::Args, Debug)]
+struct JobClientArgs {
+    #[clap(env, long, short = 'A')]
+    ssh_auth_sock: PathBuf,
+    #[clap(long, env = "SSH_REV_PAIRING")]
+    pairing: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CmdPairCode {
+    #[clap(long)]
+    store: Option<PathBuf>,
+    #[clap(long, default_value_t = 300)]
+    ttl_secs: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct CmdPair {
+    #[clap(env, long, short = 'A')]
+    ssh_auth_sock: PathBuf,
+    #[clap(long, env = "SSH_REV_PAIRING")]
+    pairing: Option<PathBuf>,
+    code: Option<String>,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    match args.command {
+    let Err(err) = run(args.command).await else {
+        return Ok(());
+    };
+    match err.downcast_ref::<Rejection>() {
+        Some(rejection) => {
+            eprintln!("ssh-rev: {}", rejection);
+            exit(rejection.exit_code());
+        }
+        None => Err(err),
+    }
+}
+
+async fn run(command: Command) -> Result<()> {
+    match command {
         Command::Agent(agent) => {
             env_logger::init();
+            let mut config = match agent.config.as_deref() {
+                Some(path) => Config::load(path)?,
+                None => Config::default(),
+            };
+            if agent.dry_run {
+                config.dry_run.get_or_insert_with(Default::default);
+            }
             cleanup_sock(&agent.ssh_rev_sock)?;
-            let rev_agent = RevAgent::open(&agent.ssh_rev_sock, agent.ssh_auth_sock)?;
+            let rev_agent = RevAgent::open(&agent.ssh_rev_sock, agent.ssh_auth_sock, config)?;
             rev_agent.run().await?;
         }
         Command::Exec(exec) => {
-            let rev_exec = RevExec::open(&exec.ssh_auth_sock).await?;
+            let client = exec.client;
+            let Some(cmd) = exec.cmd else {
+                let token = exec.resume.unwrap_or_default();
+                run_client(&client, |rev_exec, stdin, stdout, stderr| {
+                    rev_exec.resume(&token, stdin, stdout, stderr)
+                })
+                .await?;
+                return Ok(());
+            };
+            // the agent cannot tell what relative paths are relative to
+            let base = match client.cwd.as_deref() {
+                Some(cwd) => std::env::current_dir()?.join(cwd),
+                None => std::env::current_dir()?,
+            };
+            let mut args = exec.args;
+            for &index in &exec.path_args {
+                if let Some(arg) = args.get_mut(index) {
+                    *arg = base.join(&arg).into_os_string();
+                }
+            }
+            // as with ssh, redirected output is left alone
+            let tty = exec.tty
+                || !exec.no_tty
+                    && std::io::stdin().is_terminal()
+                    && std::io::stdout().is_terminal();
             let exec = Exec {
-                cmd: exec.cmd,
-                args: exec.args,
-                envs: Default::default(),
-                cwd: exec.cwd,
+                cmd,
+                args,
+                envs: parse_envs(&client.env),
+                cwd: client.cwd.as_ref().map(|_| base),
+                path_args: exec.path_args,
+            };
+            if client.detach {
+                run_client(&client, |rev_exec, _, _, _| {
+                    print_job_id(rev_exec.detach(exec.clone()))
+                })
+                .await?;
+            } else {
+                run_client(&client, |rev_exec, stdin, stdout, stderr| {
+                    rev_exec
+                        .with_tty(tty)
+                        .exec(exec.clone(), stdin, stdout, stderr)
+                })
+                .await?;
+            }
+        }
+        Command::Verb(verb) => {
+            let client = verb.client;
+            let params = verb
+                .params
+                .iter()
+                .map(|param| {
+                    param
+                        .split_once('=')
+                        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                        .ok_or_else(|| anyhow!("parameter must be NAME=VALUE: {}", param))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()?;
+            let utf8 = |string: OsString| {
+                string
+                    .into_string()
+                    .map_err(|string| anyhow!("verbs only take UTF-8, not {:?}", string))
+            };
+            let call = VerbCall {
+                verb: verb.verb,
+                params,
+                envs: parse_envs(&client.env)
+                    .into_iter()
+                    .map(|(name, value)| Ok((utf8(name)?, utf8(value)?)))
+                    .collect::<Result<_>>()?,
+                cwd: client
+                    .cwd
+                    .clone()
+                    .map(|cwd| utf8(cwd.into_os_string()))
+                    .transpose()?,
+            };
+            if client.detach {
+                run_client(&client, |rev_exec, _, _, _| {
+                    print_job_id(rev_exec.detach_verb(call.clone()))
+                })
+                .await?;
+            } else {
+                run_client(&client, |rev_exec, stdin, stdout, stderr| {
+                    rev_exec.verb(call.clone(), stdin, stdout, stderr)
+                })
+                .await?;
+            }
+        }
+        Command::PairCode(pair_code) => {
+            let store = match pair_code.store {
+                Some(path) => path,
+                None => default_store_path(AGENT_STORE_FILE)?,
             };
-            let stdin = tokio::io::stdin();
-            let stdout = tokio::io::stdout();
-            let stderr = tokio::io::stderr();
-            let exit_code = rev_exec.exec(exec, stdin, stdout, stderr).await?;
+            let code = issue_code(&store, Duration::from_secs(pair_code.ttl_secs))?;
+            println!("{}", code);
+        }
+        Command::Pair(pair) => {
+            let pairing_path = match pair.pairing {
+                Some(path) => path,
+                None => default_store_path(CLIENT_STORE_FILE)?,
+            };
+            let code = match pair.code {
+                Some(code) => code,
+                None => {
+                    eprint!("Pairing code: ");
+                    let mut line = String::new();
+                    std::io::stdin().lock().read_line(&mut line)?;
+                    line
+                }
+            };
+            let rev_exec = RevExec::open(&pair.ssh_auth_sock).await?;
+            let pairing = rev_exec.pair(&code).await?;
+            pairing.save(&pairing_path)?;
+            eprintln!("Paired as {}", pairing.client_id);
+        }
+        Command::Jobs(jobs) => {
+            let jobs = open_client(&jobs.client).await?.jobs().await?;
+            for job in jobs {
+                let state = match (job.signal, job.exit_code) {
+                    (Some(signal), _) => format!("signal {}", signal),
+                    (None, Some(exit_code)) => format!("exit {}", exit_code),
+                    (None, None) => "running".into(),
+                };
+                let started_at = UNIX_EPOCH + Duration::from_secs(job.started_at);
+                println!(
+                    "{:>4}  {:<8}  {}  {}",
+                    job.id,
+                    state,
+                    humantime::format_rfc3339_seconds(started_at),
+                    job.command
+                );
+            }
+        }
+        Command::Attach(job) => {
+            let rev_exec = open_client(&job.client).await?;
+            let exit_code = rev_exec
+                .attach(job.id, tokio::io::stdout(), tokio::io::stderr())
+                .await?;
             exit(exit_code);
         }
+        Command::Wait(job) => {
+            let status = open_client(&job.client).await?.wait(job.id).await?;
+            if let Some(limit) = status.killed_for {
+                eprintln!("ssh-rev: {}", limit);
+            }
+            exit(status.exit_code.unwrap_or_default());
+        }
+        Command::Kill(job) => {
+            open_client(&job.client).await?.kill(job.id).await?;
+        }
     }
     Ok(())
 }
 
+/// Runs `start` and exits with the remote exit code, retrying while the
+/// agent is busy for as long as `--wait-busy` allows.
+async fn run_client<F, Fut>(client: &ClientArgs, mut start: F) -> Result<()>
+where
+    F: FnMut(RevExec, Stdin, Stdout, Stderr) -> Fut,
+    Fut: Future<Output = Result<i32>>,
+{
+    let pairing = load_pairing(client.pairing.as_deref())?;
+    let give_up_at = Instant::now() + Duration::from_secs(client.wait_busy);
+    loop {
+        let rev_exec = RevExec::open(&client.ssh_auth_sock)
+            .await?
+            .with_pairing(pairing.clone())
+            .with_compression(client.compression());
+        let stdin = tokio::io::stdin();
+        let stdout = tokio::io::stdout();
+        let stderr = tokio::io::stderr();
+        let err = match start(rev_exec, stdin, stdout, stderr).await {
+            Ok(exit_code) => exit(exit_code),
+            Err(err) => err,
+        };
+        let Some(rejection) = err.downcast_ref::<Rejection>() else {
+            return Err(err);
+        };
+        if !rejection.is_retryable() {
+            return Err(err);
+        }
+        let now = Instant::now();
+        if now >= give_up_at {
+            return Err(err);
+        }
+        let delay = rejection.retry_after().unwrap_or(Duration::from_secs(1));
+        tokio::time::sleep(delay.min(give_up_at - now)).await;
+    }
+}
+
+/// Prints the id of the job that `detach` started.
+async fn print_job_id(detach: impl Future<Output = Result<JobStatus>>) -> Result<i32> {
+    println!("{}", detach.await?.id);
+    Ok(0)
+}
+
+async fn open_client(client: &JobClientArgs) -> Result<RevExec> {
+    let pairing = load_pairing(client.pairing.as_deref())?;
+    Ok(RevExec::open(&client.ssh_auth_sock)
+        .await?
+        .with_pairing(pairing))
+}
+
+fn load_pairing(path: Option<&Path>) -> Result<Option<ClientPairing>> {
+    let path = match path {
+        Some(path) => path.to_owned(),
+        None => default_store_path(CLIENT_STORE_FILE)?,
+    };
+    ClientPairing::load(&path)
+}
+
+/// `KEY=VALUE` sets a variable, `KEY` copies it from the local environment
+/// if it is set.
+fn parse_envs(specs: &[OsString]) -> HashMap<OsString, OsString> {
+    specs
+        .iter()
+        .filter_map(|spec| {
+            let bytes = spec.as_bytes();
+            match bytes.iter().position(|&byte| byte == b'=') {
+                Some(i) => Some((
+                    OsStr::from_bytes(&bytes[..i]).to_owned(),
+                    OsStr::from_bytes(&bytes[i + 1..]).to_owned(),
+                )),
+                None => std::env::var_os(spec).map(|value| (spec.clone(), value)),
+            }
+        })
+        .collect()
+}
+
 fn cleanup_sock(path: &Path) -> Result<()> {
     match std::fs::metadata(path) {
         Ok(metadata) if metadata.file_type().is_socket() => Ok(std::fs::remove_file(path)?),
@@ -0,0 +1,470 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::prelude::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::task;
+
+use crate::{
+    caller::Caller,
+    rpc::{Auth, Pair, PairReply, Rejection, RejectionKind},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const PROOF_LABEL: &[u8] = b"ssh-rev pair proof";
+const SECRET_LABEL: &[u8] = b"ssh-rev pair secret";
+const CONFIRM_LABEL: &[u8] = b"ssh-rev pair confirm";
+/// Unambiguous when read aloud or typed; 32 symbols so that a random byte
+/// maps onto it without bias.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 8;
+/// How far the timestamp of an authenticated request may be off.
+const MAX_CLOCK_SKEW: u64 = 300;
+
+pub const AGENT_STORE_FILE: &str = "agent-pairings.json";
+pub const CLIENT_STORE_FILE: &str = "pairing.json";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PairingConfig {
+    /// Refuse `Exec` requests that are not authenticated by a paired client.
+    #[serde(default)]
+    pub required: bool,
+    /// Defaults to `agent-pairings.json` in the state directory.
+    pub store: Option<PathBuf>,
+}
+
+/// `$XDG_STATE_HOME/ssh-rev/<file>`, falling back to `~/.local/state`.
+pub fn default_store_path(file: &str) -> Result<PathBuf> {
+    let state_dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?;
+            PathBuf::from(home).join(".local/state")
+        }
+    };
+    Ok(state_dir.join("ssh-rev").join(file))
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AgentStore {
+    #[serde(default)]
+    codes: Vec<PendingCode>,
+    #[serde(default)]
+    clients: BTreeMap<String, PairedClient>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingCode {
+    code: String,
+    expires_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PairedClient {
+    secret: String,
+    paired_at: u64,
+    #[serde(default)]
+    host: Option<String>,
+}
+
+/// Creates a single-use pairing code that expires after `ttl`.
+pub fn issue_code(store_path: &Path, ttl: Duration) -> Result<String> {
+    let mut random = [0u8; CODE_LEN];
+    getrandom::getrandom(&mut random)?;
+    let code: String = random
+        .iter()
+        .map(|&b| CODE_ALPHABET[b as usize % CODE_ALPHABET.len()] as char)
+        .collect();
+    let mut store: AgentStore = load_json(store_path)?.unwrap_or_default();
+    let now = now();
+    store.codes.retain(|pending| pending.expires_at > now);
+    store.codes.push(PendingCode {
+        code: code.clone(),
+        expires_at: now + ttl.as_secs(),
+    });
+    save_json(store_path, &store)?;
+    Ok(format!(
+        "{}-{}",
+        &code[..CODE_LEN / 2],
+        &code[CODE_LEN / 2..]
+    ))
+}
+
+/// The agent side of pairing.
+pub struct Pairing {
+    store_path: PathBuf,
+    required: bool,
+    store: tokio::sync::Mutex<CachedStore>,
+    /// Nonces of recently accepted requests, to refuse replays.
+    seen: Mutex<HashMap<(String, Bytes), u64>>,
+}
+
+/// The store as last read or written by the agent. `ssh-rev pair-code` and
+/// revoking a host change the file behind its back, so it is read again
+/// whenever it was modified since.
+#[derive(Default)]
+struct CachedStore {
+    store: AgentStore,
+    modified: Option<SystemTime>,
+}
+
+impl Pairing {
+    pub fn new(config: PairingConfig) -> Result<Self> {
+        let store_path = match config.store {
+            Some(path) => path,
+            None => default_store_path(AGENT_STORE_FILE)?,
+        };
+        Ok(Self {
+            store_path,
+            required: config.required,
+            store: Default::default(),
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    pub async fn pair(&self, pair: &Pair, caller: &Caller) -> Result<PairReply, Rejection> {
+        let mut cached = self.load_store().await?;
+        let store = &mut cached.store;
+        let now = now();
+        store.codes.retain(|pending| pending.expires_at > now);
+        let client_nonce = decode(&pair.nonce)?;
+        let proof = decode(&pair.proof)?;
+        let Some(index) = store.codes.iter().position(|pending| {
+            let mut mac = hmac(pending.code.as_bytes());
+            mac.update(PROOF_LABEL);
+            mac.update(pair.client_id.as_bytes());
+            mac.update(&client_nonce);
+            mac.verify_slice(&proof).is_ok()
+        }) else {
+            return Err(unpaired("the pairing code is wrong or has expired"));
+        };
+        let pending = store.codes.remove(index);
+
+        let mut agent_nonce = [0u8; 32];
+        getrandom::getrandom(&mut agent_nonce).map_err(|err| unpaired(&err.to_string()))?;
+        let secret = derive_secret(&pending.code, &client_nonce, &agent_nonce);
+        store.clients.insert(
+            pair.client_id.clone(),
+            PairedClient {
+                secret: STANDARD.encode(secret),
+                paired_at: now,
+                host: caller
+                    .verified_remote_host()
+                    .map(|host| host.fingerprint.clone()),
+            },
+        );
+        match self.save_store(store).await {
+            Ok(modified) => cached.modified = Some(modified),
+            Err(err) => {
+                log::error!("Failed to save pairing store: {:?}", err);
+                // forget what the file did not get
+                *cached = CachedStore::default();
+                return Err(unpaired("the agent could not save the pairing"));
+            }
+        }
+        log::info!("Paired client {} from {}", pair.client_id, caller);
+        Ok(PairReply {
+            nonce: STANDARD.encode(agent_nonce),
+            proof: STANDARD.encode(confirmation(&secret)),
+        })
+    }
+
+    /// Checks that `auth` was made by a paired client, and returns its id.
+    pub async fn verify(&self, auth: &Auth) -> Result<String, Rejection> {
+        let cached = self.load_store().await?;
+        let Some(client) = cached.store.clients.get(&auth.client_id) else {
+            return Err(unpaired(
+                "this host is not paired with the agent; pair it with `ssh-rev pair`",
+            ));
+        };
+        let secret = decode(&client.secret)?;
+        drop(cached);
+        let now = now();
+        if auth.timestamp.abs_diff(now) > MAX_CLOCK_SKEW {
+            return Err(unpaired("the request is too old; check the clocks"));
+        }
+        let mut mac = hmac(&secret);
+        mac.update(&Auth::signed_payload(
+            &auth.client_id,
+            auth.timestamp,
+            &auth.nonce,
+            &auth.inner,
+        ));
+        if mac.verify_slice(&auth.mac).is_err() {
+            return Err(unpaired("the request signature is invalid"));
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, timestamp| timestamp.abs_diff(now) <= MAX_CLOCK_SKEW);
+        let key = (auth.client_id.clone(), auth.nonce.clone());
+        if seen.insert(key, auth.timestamp).is_some() {
+            return Err(unpaired("the request was replayed"));
+        }
+        Ok(auth.client_id.clone())
+    }
+
+    /// The store, read again if the file changed since it was last read.
+    async fn load_store(&self) -> Result<tokio::sync::MutexGuard<'_, CachedStore>, Rejection> {
+        let mut cached = self.store.lock().await;
+        let loaded = async {
+            let modified = match tokio::fs::metadata(&self.store_path).await {
+                Ok(metadata) => Some(metadata.modified()?),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err).context("stat pairing store"),
+            };
+            if modified != cached.modified {
+                let path = self.store_path.clone();
+                let store = task::spawn_blocking(move || load_json(&path)).await??;
+                *cached = CachedStore {
+                    store: store.unwrap_or_default(),
+                    modified,
+                };
+            }
+            Ok(())
+        };
+        if let Err(err) = loaded.await {
+            log::error!("Failed to load pairing store: {:?}", err);
+            return Err(unpaired("the agent could not load its pairings"));
+        }
+        Ok(cached)
+    }
+
+    /// Writes `store` back, and returns when the file was modified.
+    async fn save_store(&self, store: &AgentStore) -> Result<SystemTime> {
+        let json = serde_json::to_vec_pretty(store)?;
+        let path = self.store_path.clone();
+        task::spawn_blocking(move || write_private(&path, &json)).await??;
+        let metadata = tokio::fs::metadata(&self.store_path).await?;
+        Ok(metadata.modified()?)
+    }
+}
+
+/// The remote side of pairing, as stored by `ssh-rev pair`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientPairing {
+    pub client_id: String,
+    secret: String,
+}
+
+/// A pairing that has been requested but not yet confirmed by the agent.
+pub struct PendingPairing {
+    client_id: String,
+    code: String,
+    nonce: [u8; 32],
+}
+
+impl PendingPairing {
+    pub fn new(code: &str) -> Result<(Self, Pair)> {
+        let code: String = code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if code.len() != CODE_LEN {
+            return Err(anyhow!("pairing code must have {} characters", CODE_LEN));
+        }
+        let mut id = [0u8; 16];
+        getrandom::getrandom(&mut id)?;
+        let client_id = id.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let mut nonce = [0u8; 32];
+        getrandom::getrandom(&mut nonce)?;
+        let mut mac = hmac(code.as_bytes());
+        mac.update(PROOF_LABEL);
+        mac.update(client_id.as_bytes());
+        mac.update(&nonce);
+        let pair = Pair {
+            client_id: client_id.clone(),
+            nonce: STANDARD.encode(nonce),
+            proof: STANDARD.encode(mac.finalize().into_bytes()),
+        };
+        Ok((
+            Self {
+                client_id,
+                code,
+                nonce,
+            },
+            pair,
+        ))
+    }
+
+    pub fn finish(self, reply: PairReply) -> Result<ClientPairing> {
+        let agent_nonce = STANDARD.decode(reply.nonce)?;
+        let secret = derive_secret(&self.code, &self.nonce, &agent_nonce);
+        if STANDARD.decode(reply.proof)? != confirmation(&secret) {
+            return Err(anyhow!(
+                "the agent could not prove it knows the pairing code"
+            ));
+        }
+        Ok(ClientPairing {
+            client_id: self.client_id,
+            secret: STANDARD.encode(secret),
+        })
+    }
+}
+
+impl ClientPairing {
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        load_json(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save_json(path, self)
+    }
+
+    pub fn sign(&self, inner: Bytes) -> Result<Auth> {
+        let mut nonce = [0u8; 16];
+        getrandom::getrandom(&mut nonce)?;
+        let timestamp = now();
+        let mut mac = hmac(&STANDARD.decode(&self.secret)?);
+        mac.update(&Auth::signed_payload(
+            &self.client_id,
+            timestamp,
+            &nonce,
+            &inner,
+        ));
+        Ok(Auth {
+            client_id: self.client_id.clone(),
+            timestamp,
+            nonce: Bytes::copy_from_slice(&nonce),
+            inner,
+            mac: Bytes::copy_from_slice(&mac.finalize().into_bytes()),
+        })
+    }
+}
+
+fn hmac(key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+fn derive_secret(code: &str, client_nonce: &[u8], agent_nonce: &[u8]) -> [u8; 32] {
+    let mut mac = hmac(code.as_bytes());
+    mac.update(SECRET_LABEL);
+    mac.update(client_nonce);
+    mac.update(agent_nonce);
+    mac.finalize().into_bytes().into()
+}
+
+fn confirmation(secret: &[u8]) -> Vec<u8> {
+    let mut mac = hmac(secret);
+    mac.update(CONFIRM_LABEL);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn unpaired(reason: &str) -> Rejection {
+    Rejection::new(RejectionKind::Unpaired, reason)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Rejection> {
+    STANDARD
+        .decode(value)
+        .map_err(|_| unpaired("the pairing data is malformed"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn load_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(
+            serde_json::from_slice(&bytes).with_context(|| format!("parse {}", path.display()))?,
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("read {}", path.display())),
+    }
+}
+
+/// Writes `value` readable by the owner only, replacing the file atomically.
+fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    write_private(path, &serde_json::to_vec_pretty(value)?)
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("create {}", tmp_path.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pair_and_sign() {
+        let dir = env::temp_dir().join(format!("ssh-rev-pairing-{}", std::process::id()));
+        let store = dir.join(AGENT_STORE_FILE);
+        let code = issue_code(&store, Duration::from_secs(60)).unwrap();
+        let pairing = Pairing::new(PairingConfig {
+            required: true,
+            store: Some(store.clone()),
+        })
+        .unwrap();
+
+        let (_, wrong) = PendingPairing::new("AAAA-AAAA").unwrap();
+        assert!(pairing.pair(&wrong, &Caller::default()).await.is_err());
+
+        let (pending, pair) = PendingPairing::new(&code.to_lowercase()).unwrap();
+        let reply = pairing.pair(&pair, &Caller::default()).await.unwrap();
+        let client = pending.finish(reply).unwrap();
+        // codes are single-use
+        assert!(pairing.pair(&pair, &Caller::default()).await.is_err());
+
+        let auth = client.sign(Bytes::from_static(b"\x02")).unwrap();
+        assert_eq!(pairing.verify(&auth).await.unwrap(), client.client_id);
+        assert!(
+            pairing.verify(&auth).await.is_err(),
+            "replay must be refused"
+        );
+
+        let mut forged = client.sign(Bytes::from_static(b"\x02")).unwrap();
+        forged.inner = Bytes::from_static(b"\x00{}");
+        assert!(pairing.verify(&forged).await.is_err());
+
+        // codes issued and hosts revoked while the agent runs count too
+        let code = issue_code(&store, Duration::from_secs(60)).unwrap();
+        let (pending, pair) = PendingPairing::new(&code).unwrap();
+        let reply = pairing.pair(&pair, &Caller::default()).await.unwrap();
+        let other = pending.finish(reply).unwrap();
+        let mut saved: AgentStore = load_json(&store).unwrap().unwrap();
+        saved.clients.remove(&client.client_id);
+        save_json(&store, &saved).unwrap();
+        let auth = client.sign(Bytes::from_static(b"\x02")).unwrap();
+        assert!(pairing.verify(&auth).await.is_err());
+        let auth = other.sign(Bytes::from_static(b"\x02")).unwrap();
+        assert_eq!(pairing.verify(&auth).await.unwrap(), other.client_id);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
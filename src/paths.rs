@@ -0,0 +1,217 @@
+use std::{
+    ffi::OsString,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::{caller::Caller, rpc::Exec};
+
+/// Rewrites remote paths in requests to where the same files are found on
+/// this machine.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathConfig {
+    /// Working directory for requests whose `cwd` is missing or has no
+    /// mapping. Without it such requests run in the agent's own directory
+    /// or in `cwd` as is.
+    pub default_cwd: Option<PathBuf>,
+    /// Tried in order; the first mapping that applies wins.
+    #[serde(default)]
+    pub mappings: Vec<Mapping>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawMapping")]
+pub struct Mapping {
+    pub host: Vec<String>,
+    pub remote: PathBuf,
+    pub target: Target,
+}
+
+#[derive(Debug)]
+pub enum Target {
+    /// A local directory, e.g. a copy of the tree or an sshfs mount point.
+    Local(PathBuf),
+    /// A `vscode-remote://` URI with this authority, such as
+    /// `ssh-remote+devbox`. Only applies to arguments, not to `cwd`.
+    VscodeRemote(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMapping {
+    /// Host key fingerprints or `known_hosts` names, as in policy rules.
+    #[serde(default)]
+    host: Vec<String>,
+    remote: PathBuf,
+    local: Option<PathBuf>,
+    vscode_remote: Option<String>,
+}
+
+impl TryFrom<RawMapping> for Mapping {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawMapping) -> Result<Self> {
+        if !raw.remote.is_absolute() {
+            bail!("remote path must be absolute: {}", raw.remote.display());
+        }
+        let target = match (raw.local, raw.vscode_remote) {
+            (Some(local), None) => Target::Local(local),
+            (None, Some(authority)) => Target::VscodeRemote(authority),
+            _ => bail!("a mapping needs exactly one of `local` and `vscode_remote`"),
+        };
+        Ok(Self {
+            host: raw.host,
+            remote: raw.remote,
+            target,
+        })
+    }
+}
+
+impl PathConfig {
+    /// Rewrites `exec.cwd` and the arguments listed in `exec.path_args`.
+    pub fn translate(&self, exec: &mut Exec, caller: &Caller) {
+        let cwd = exec
+            .cwd
+            .as_deref()
+            .and_then(|cwd| self.map(cwd, caller, true));
+        match (cwd, &self.default_cwd) {
+            (Some(cwd), _) => exec.cwd = Some(cwd.into()),
+            (None, Some(default_cwd)) => exec.cwd = Some(default_cwd.clone()),
+            (None, None) => {}
+        }
+        for &index in &exec.path_args {
+            let Some(arg) = exec.args.get_mut(index) else {
+                continue;
+            };
+            if let Some(mapped) = self.map(Path::new(arg), caller, false) {
+                *arg = mapped;
+            }
+        }
+    }
+
+    fn map(&self, remote: &Path, caller: &Caller, is_cwd: bool) -> Option<OsString> {
+        self.mappings.iter().find_map(|mapping| {
+            if !mapping.matches_host(caller) {
+                return None;
+            }
+            let rest = remote.strip_prefix(&mapping.remote).ok()?;
+            // it could lead out of the mapped directory
+            if rest
+                .components()
+                .any(|component| component == Component::ParentDir)
+            {
+                return None;
+            }
+            match &mapping.target {
+                Target::Local(local) => Some(local.join(rest).into_os_string()),
+                Target::VscodeRemote(_) if is_cwd => None,
+                Target::VscodeRemote(authority) => Some(
+                    format!(
+                        "vscode-remote://{}{}",
+                        authority,
+                        percent_encode(remote.as_os_str().as_bytes())
+                    )
+                    .into(),
+                ),
+            }
+        })
+    }
+}
+
+impl Mapping {
+    fn matches_host(&self, caller: &Caller) -> bool {
+        if self.host.is_empty() {
+            return true;
+        }
+        let Some(host) = caller.verified_remote_host() else {
+            return false;
+        };
+        self.host.iter().any(|pattern| host.matches(pattern))
+    }
+}
+
+/// Encodes everything but unreserved characters and `/`.
+fn percent_encode(path: &[u8]) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &byte in path {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let config: PathConfig = toml::from_str(
+            r#"
+            default_cwd = "/Users/me"
+
+            [[mappings]]
+            remote = "/home/me/src"
+            vscode_remote = "ssh-remote+devbox"
+
+            [[mappings]]
+            remote = "/home/me"
+            local = "/Volumes/devbox"
+            "#,
+        )
+        .unwrap();
+        let mut exec = Exec {
+            cwd: Some("/home/me/src/app".into()),
+            path_args: vec![1, 2],
+            ..Exec::new(
+                "code",
+                &["--goto", "/home/me/src/my app/main.rs", "/etc/hosts"],
+            )
+        };
+        config.translate(&mut exec, &Caller::default());
+        assert_eq!(
+            exec.cwd.as_deref(),
+            Some(Path::new("/Volumes/devbox/src/app"))
+        );
+        assert_eq!(
+            exec.args,
+            vec![
+                "--goto",
+                "vscode-remote://ssh-remote+devbox/home/me/src/my%20app/main.rs",
+                "/etc/hosts",
+            ]
+        );
+
+        exec.cwd = Some("/tmp".into());
+        config.translate(&mut exec, &Caller::default());
+        assert_eq!(exec.cwd.as_deref(), Some(Path::new("/Users/me")));
+    }
+
+    #[test]
+    fn test_parent_dir_is_not_mapped() {
+        let config: PathConfig = toml::from_str(
+            r#"
+            [[mappings]]
+            remote = "/home/me"
+            local = "/Volumes/devbox"
+            "#,
+        )
+        .unwrap();
+        let mut exec = Exec {
+            cwd: Some("/home/me/src/../..".into()),
+            path_args: vec![0],
+            ..Exec::new("cat", &["/home/me/../../etc/passwd"])
+        };
+        config.translate(&mut exec, &Caller::default());
+        assert_eq!(exec.cwd.as_deref(), Some(Path::new("/home/me/src/../..")));
+        assert_eq!(exec.args, vec!["/home/me/../../etc/passwd"]);
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    os::unix::prelude::{OsStrExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use regex::bytes::Regex;
+use serde::{Deserialize, Deserializer};
+use tokio::fs;
+
+use crate::{
+    caller::Caller,
+    rpc::{Exec, Rejection, RejectionKind},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Rules are evaluated in order and the first matching rule decides.
+/// Without a `[policy]` table every request is allowed, but once the table
+/// exists the fallback action is `deny` unless `default` says otherwise.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default = "default_action")]
+    pub default: Action,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+fn default_action() -> Action {
+    Action::Deny
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            default: Action::Allow,
+            rules: vec![],
+        }
+    }
+}
+
+/// Every condition that is present must hold for the rule to match.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: Option<String>,
+    pub action: Action,
+    /// Program names or absolute paths. A name matches the file name of
+    /// the requested command, as sent or as resolved, so that `rm` covers
+    /// `/bin/rm` and `./rm` too. A path matches the command as resolved
+    /// against the agent's own `PATH`.
+    #[serde(default)]
+    pub program: Vec<String>,
+    /// For `allow`, each argument must fully match at least one of these
+    /// patterns. For `deny`, one argument that does is enough, so that
+    /// adding more arguments cannot get around the rule.
+    #[serde(default)]
+    pub args: Vec<Pattern>,
+    /// The requested working directory must be below one of these paths,
+    /// once both are resolved. These are resolved as the configuration is
+    /// read.
+    #[serde(default, deserialize_with = "resolved_dirs")]
+    pub cwd: Vec<PathBuf>,
+    /// For `allow`, every requested environment variable must be listed
+    /// here. For `deny`, one listed variable is enough, as with `args`.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Host key fingerprints or `known_hosts` names. Only hosts whose
+    /// session binding was verified by the upstream agent can match.
+    #[serde(default)]
+    pub host: Vec<String>,
+}
+
+fn resolved_dirs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    let dirs = Vec::<PathBuf>::deserialize(deserializer)?;
+    Ok(dirs
+        .into_iter()
+        .map(|dir| dir.canonicalize().unwrap_or(dir))
+        .collect())
+}
+
+/// The program and working directory of a request as the agent will see
+/// them, resolved once before the request is checked.
+#[derive(Debug, Default)]
+pub struct Resolved {
+    /// See [`resolve_program`].
+    pub program: Option<PathBuf>,
+    /// The working directory with `..` and symlinks resolved, if it exists.
+    pub cwd: Option<PathBuf>,
+}
+
+impl Resolved {
+    pub async fn new(exec: &Exec) -> Self {
+        let cwd = match &exec.cwd {
+            Some(cwd) => fs::canonicalize(cwd).await.ok(),
+            None => None,
+        };
+        Self {
+            program: resolve_program(&exec.cmd, exec.cwd.as_deref()).await,
+            cwd,
+        }
+    }
+}
+
+/// A regular expression that has to match all of a string. It matches
+/// bytes, so strings that are not UTF-8 can match it too.
+#[derive(Debug)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn is_match(&self, text: &[u8]) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(serde::de::Error::custom)?;
+        Ok(Self(regex))
+    }
+}
+
+impl Policy {
+    pub fn check(
+        &self,
+        exec: &Exec,
+        resolved: &Resolved,
+        caller: &Caller,
+    ) -> Result<(), Rejection> {
+        self.decide(exec, resolved, caller, self.default)
+    }
+
+    /// Like [`Policy::check`], for the command line a verb expanded to.
+    /// Rules apply to it alike, but a verb that matches none is allowed,
+    /// since being registered is what allows it.
+    pub fn check_verb(
+        &self,
+        exec: &Exec,
+        resolved: &Resolved,
+        caller: &Caller,
+    ) -> Result<(), Rejection> {
+        self.decide(exec, resolved, caller, Action::Allow)
+    }
+
+    fn decide(
+        &self,
+        exec: &Exec,
+        resolved: &Resolved,
+        caller: &Caller,
+        fallback: Action,
+    ) -> Result<(), Rejection> {
+        let matched = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(exec, resolved, caller));
+        let (action, rule) = match matched {
+            Some((i, rule)) => (rule.action, Some(rule.label(i))),
+            None => (fallback, None),
+        };
+        match action {
+            Action::Allow => Ok(()),
+            Action::Deny => {
+                let cmd = exec.cmd.to_string_lossy();
+                let message = match &rule {
+                    Some(rule) => format!("`{}` was denied by policy rule {}", cmd, rule),
+                    None => format!("`{}` was denied by the default policy", cmd),
+                };
+                Err(Rejection {
+                    rule,
+                    ..Rejection::new(RejectionKind::Policy, message)
+                })
+            }
+        }
+    }
+}
+
+impl Rule {
+    fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("#{}", index + 1),
+        }
+    }
+
+    fn matches(&self, exec: &Exec, resolved: &Resolved, caller: &Caller) -> bool {
+        self.matches_program(&exec.cmd, resolved.program.as_deref())
+            && self.matches_host(caller)
+            && self.matches_args(&exec.args)
+            && self.matches_cwd(resolved.cwd.as_deref())
+            && self.matches_env(exec.envs.keys())
+    }
+
+    fn matches_program(&self, cmd: &OsStr, program: Option<&Path>) -> bool {
+        self.program.is_empty() || matches_program(&self.program, cmd, program)
+    }
+
+    fn matches_host(&self, caller: &Caller) -> bool {
+        if self.host.is_empty() {
+            return true;
+        }
+        let Some(host) = caller.verified_remote_host() else {
+            return false;
+        };
+        self.host.iter().any(|pattern| host.matches(pattern))
+    }
+
+    fn matches_args(&self, args: &[OsString]) -> bool {
+        if self.args.is_empty() {
+            return true;
+        }
+        let is_listed = |arg: &OsString| self.args.iter().any(|p| p.is_match(arg.as_bytes()));
+        match self.action {
+            Action::Allow => args.iter().all(is_listed),
+            Action::Deny => args.iter().any(is_listed),
+        }
+    }
+
+    fn matches_env<'a>(&self, mut keys: impl Iterator<Item = &'a OsString>) -> bool {
+        if self.env.is_empty() {
+            return true;
+        }
+        let is_listed = |key: &OsString| self.env.iter().any(|name| key == name.as_str());
+        match self.action {
+            Action::Allow => keys.all(is_listed),
+            Action::Deny => keys.any(is_listed),
+        }
+    }
+
+    /// Compares resolved paths, so that neither `..` nor a symlink can lead
+    /// out of a listed directory. A cwd that does not exist matches nothing,
+    /// and the command could not be started in it anyway.
+    fn matches_cwd(&self, cwd: Option<&Path>) -> bool {
+        if self.cwd.is_empty() {
+            return true;
+        }
+        let Some(cwd) = cwd else {
+            return false;
+        };
+        self.cwd.iter().any(|prefix| cwd.starts_with(prefix))
+    }
+}
+
+/// Whether `cmd` is one of `entries`. A name matches the file name of `cmd`
+/// or of the resolved `program`, an absolute path matches `program`.
+pub fn matches_program(entries: &[String], cmd: &OsStr, program: Option<&Path>) -> bool {
+    let names = [Some(Path::new(cmd)), program].map(|path| path.and_then(Path::file_name));
+    entries.iter().any(|entry| {
+        if Path::new(entry).is_absolute() {
+            program == Some(Path::new(entry))
+        } else {
+            names.contains(&Some(OsStr::new(entry)))
+        }
+    })
+}
+
+/// Resolves `cmd` the way the agent will run it: names are looked up in the
+/// agent's own `PATH` so that a request cannot redirect them elsewhere.
+async fn resolve_program(cmd: &OsStr, cwd: Option<&Path>) -> Option<PathBuf> {
+    if cmd.as_bytes().contains(&b'/') {
+        let path = Path::new(cmd);
+        let path = match cwd {
+            Some(cwd) if path.is_relative() => cwd.join(path),
+            _ => path.to_path_buf(),
+        };
+        return fs::canonicalize(path).await.ok();
+    }
+    let paths = env::var_os("PATH")?;
+    for dir in env::split_paths(&paths) {
+        let path = dir.join(cmd);
+        if is_executable(&path).await {
+            return Some(path);
+        }
+    }
+    None
+}
+
+async fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .await
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rules]]
+            name = "no-rm-rf"
+            action = "deny"
+            program = ["rm"]
+            args = ["-rf?", "/.*"]
+
+            [[rules]]
+            action = "allow"
+            program = ["rm", "ls"]
+            "#,
+        )
+        .unwrap();
+        let caller = Caller::default();
+        let unresolved = Resolved::default();
+        assert!(policy
+            .check(&Exec::new("ls", &["-la"]), &unresolved, &caller)
+            .is_ok());
+        assert!(policy
+            .check(&Exec::new("rm", &["foo"]), &unresolved, &caller)
+            .is_ok());
+        let rejection = policy
+            .check(&Exec::new("rm", &["-rf", "/"]), &unresolved, &caller)
+            .unwrap_err();
+        assert_eq!(rejection.rule.as_deref(), Some("no-rm-rf"));
+        let rejection = policy
+            .check(&Exec::new("vim", &[]), &unresolved, &caller)
+            .unwrap_err();
+        assert_eq!(rejection.rule, None);
+    }
+
+    #[test]
+    fn test_deny_rule_matches_any_argument() {
+        let policy: Policy = toml::from_str(
+            r#"
+            default = "allow"
+
+            [[rules]]
+            action = "deny"
+            program = ["rm"]
+            args = ["-rf?", "/"]
+            "#,
+        )
+        .unwrap();
+        let caller = Caller::default();
+        let unresolved = Resolved::default();
+        let rm = Exec::new("rm", &["-rf", "/", "--no-preserve-root"]);
+        assert!(policy.check(&rm, &unresolved, &caller).is_err());
+        assert!(policy
+            .check(&Exec::new("rm", &["-r", "build"]), &unresolved, &caller)
+            .is_err());
+        assert!(policy
+            .check(&Exec::new("rm", &["build"]), &unresolved, &caller)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_deny_rule_matches_any_env() {
+        let policy: Policy = toml::from_str(
+            r#"
+            default = "allow"
+
+            [[rules]]
+            action = "deny"
+            env = ["GIT_SSH_COMMAND"]
+            "#,
+        )
+        .unwrap();
+        let caller = Caller::default();
+        let unresolved = Resolved::default();
+        let with_env = |names: &[&str]| Exec {
+            envs: names
+                .iter()
+                .map(|&name| (name.into(), "x".into()))
+                .collect(),
+            ..Exec::new("git", &["fetch"])
+        };
+        assert!(policy.check(&with_env(&[]), &unresolved, &caller).is_ok());
+        assert!(policy
+            .check(&with_env(&["LANG"]), &unresolved, &caller)
+            .is_ok());
+        let both = with_env(&["GIT_SSH_COMMAND", "LANG"]);
+        assert!(policy.check(&both, &unresolved, &caller).is_err());
+    }
+
+    #[test]
+    fn test_program_name_matches_any_path() {
+        let policy: Policy = toml::from_str(
+            r#"
+            default = "allow"
+
+            [[rules]]
+            action = "deny"
+            program = ["rm"]
+            "#,
+        )
+        .unwrap();
+        let caller = Caller::default();
+        let unresolved = Resolved::default();
+        for cmd in ["rm", "/bin/rm", "/usr/bin/rm", "./rm"] {
+            let rm = Exec::new(cmd, &["-rf", "/"]);
+            assert!(policy.check(&rm, &unresolved, &caller).is_err(), "{}", cmd);
+        }
+        let resolved = |program: &str| Resolved {
+            program: Some(program.into()),
+            cwd: None,
+        };
+        let renamed = Exec::new("./remove", &["-rf", "/"]);
+        assert!(policy
+            .check(&renamed, &resolved("/usr/bin/rm"), &caller)
+            .is_err());
+        let rmdir = Exec::new("/bin/rmdir", &["build"]);
+        assert!(policy
+            .check(&rmdir, &resolved("/usr/bin/rmdir"), &caller)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verbs_fall_back_to_allow() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rules]]
+            action = "deny"
+            program = ["git"]
+            args = ["--upload-pack.*"]
+            "#,
+        )
+        .unwrap();
+        let caller = Caller::default();
+        let unresolved = Resolved::default();
+        let clone = Exec::new("git", &["clone", "https://example.com/r"]);
+        assert!(policy.check(&clone, &unresolved, &caller).is_err());
+        assert!(policy.check_verb(&clone, &unresolved, &caller).is_ok());
+        let upload_pack = Exec::new("git", &["clone", "--upload-pack=touch x"]);
+        assert!(policy
+            .check_verb(&upload_pack, &unresolved, &caller)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cwd_is_resolved() {
+        let root = std::env::temp_dir().join(format!("ssh-rev-policy-{}", std::process::id()));
+        let project = root.join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        let policy: Policy = toml::from_str(&format!(
+            r#"
+            [[rules]]
+            action = "allow"
+            cwd = [{:?}]
+            "#,
+            project
+        ))
+        .unwrap();
+        let caller = Caller::default();
+        let check = |cwd: PathBuf| {
+            let exec = Exec {
+                cwd: Some(cwd),
+                ..Exec::new("ls", &[])
+            };
+            let policy = &policy;
+            let caller = &caller;
+            async move { policy.check(&exec, &Resolved::new(&exec).await, caller) }
+        };
+        assert!(check(project.clone()).await.is_ok());
+        assert!(check(project.join("../../../etc")).await.is_err());
+        assert!(check(project.join("missing")).await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
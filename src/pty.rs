@@ -0,0 +1,270 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    process::Stdio,
+    ptr,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    process,
+};
+
+use crate::rpc::WindowSize;
+
+/// The end-of-file character of a terminal whose settings can't be read.
+const CTRL_D: u8 = 0x04;
+
+/// A pseudo-terminal for the agent to run a command on.
+pub struct Pty {
+    master: OwnedFd,
+    slave: OwnedFd,
+}
+
+impl Pty {
+    pub fn open(size: WindowSize) -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let winsize = winsize(size);
+        // SAFETY: openpty only writes the two descriptors.
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                &winsize,
+            )
+        };
+        if opened != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just opened and belong to nobody else.
+        let (master, slave) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        // the command is to have the slave as stdio only
+        set_cloexec(master.as_raw_fd())?;
+        set_cloexec(slave.as_raw_fd())?;
+        Ok(Self { master, slave })
+    }
+
+    /// Makes `command` run on the terminal, as the leader of a session of
+    /// its own that has the terminal as its controlling terminal.
+    pub fn attach(&self, command: &mut process::Command) -> io::Result<()> {
+        command.stdin(Stdio::from(self.slave.try_clone()?));
+        command.stdout(Stdio::from(self.slave.try_clone()?));
+        command.stderr(Stdio::from(self.slave.try_clone()?));
+        // SAFETY: setsid and ioctl are async-signal-safe.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY as _, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Gives up the slave, which the command has by now, so that reading
+    /// the master ends once the command and whatever it started are gone.
+    pub fn into_master(self) -> io::Result<PtyMaster> {
+        let fd = self.master.as_raw_fd();
+        // SAFETY: fcntl on a descriptor that is still open.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(PtyMaster(Arc::new(AsyncFd::new(self.master)?)))
+    }
+}
+
+/// The agent's end of a [`Pty`], which is both the stdin and the output
+/// of the command.
+#[derive(Clone)]
+pub struct PtyMaster(Arc<AsyncFd<OwnedFd>>);
+
+impl PtyMaster {
+    pub fn resize(&self, size: WindowSize) -> io::Result<()> {
+        let winsize = winsize(size);
+        // SAFETY: TIOCSWINSZ only reads the winsize.
+        if unsafe { libc::ioctl(self.0.as_raw_fd(), libc::TIOCSWINSZ as _, &winsize) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let read = guard.try_io(|fd| {
+                // SAFETY: reads into the initialized part of `buf`.
+                let read = unsafe {
+                    libc::read(fd.as_raw_fd(), unfilled.as_mut_ptr().cast(), unfilled.len())
+                };
+                if read < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(read as usize)
+            });
+            match read {
+                Ok(Ok(read)) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                // how Linux tells that the slave was closed
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            let written = guard.try_io(|fd| {
+                // SAFETY: writes from `buf`, which outlives the call.
+                let written =
+                    unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+                if written < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(written as usize)
+            });
+            match written {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends the input of the command the way a typed ^D would, with the
+    /// end-of-file character of the terminal.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        // SAFETY: tcgetattr only writes `termios`.
+        let eof = match unsafe { libc::tcgetattr(self.0.as_raw_fd(), &mut termios) } {
+            0 => termios.c_cc[libc::VEOF],
+            _ => CTRL_D,
+        };
+        self.poll_write(cx, &[eof]).map_ok(|_| ())
+    }
+}
+
+/// Keeps the terminal of the client in raw mode, so that keys reach the
+/// command as they are typed, until it is dropped.
+pub struct RawMode {
+    fd: RawFd,
+    saved: libc::termios,
+    raw: libc::termios,
+}
+
+impl RawMode {
+    /// Puts `fd` in raw mode if it is a terminal.
+    pub fn enter(fd: RawFd) -> io::Result<Option<Self>> {
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        // SAFETY: tcgetattr only writes `saved`.
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOTTY) {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        let mut raw = saved;
+        // SAFETY: only touches `raw`.
+        unsafe { libc::cfmakeraw(&mut raw) };
+        let raw_mode = Self { fd, saved, raw };
+        raw_mode.resume()?;
+        Ok(Some(raw_mode))
+    }
+
+    /// Gives the terminal back the settings it had, as before stopping.
+    pub fn suspend(&self) -> io::Result<()> {
+        set_termios(self.fd, &self.saved)
+    }
+
+    /// Puts the terminal back in raw mode, as after continuing.
+    pub fn resume(&self) -> io::Result<()> {
+        set_termios(self.fd, &self.raw)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(err) = self.suspend() {
+            log::warn!("Failed to restore the terminal: {}", err);
+        }
+    }
+}
+
+fn set_termios(fd: RawFd, termios: &libc::termios) -> io::Result<()> {
+    // SAFETY: sets what tcgetattr returned for the same descriptor, or a
+    // copy cfmakeraw changed.
+    if unsafe { libc::tcsetattr(fd, libc::TCSADRAIN, termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The size of the first of stdin, stdout and stderr that is a terminal.
+pub fn window_size() -> Option<WindowSize> {
+    [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .find_map(|fd| {
+            let mut winsize = unsafe { std::mem::zeroed::<libc::winsize>() };
+            // SAFETY: TIOCGWINSZ only writes the winsize.
+            if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ as _, &mut winsize) } != 0 {
+                return None;
+            }
+            Some(WindowSize {
+                cols: winsize.ws_col,
+                rows: winsize.ws_row,
+            })
+        })
+}
+
+fn winsize(size: WindowSize) -> libc::winsize {
+    libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl on a descriptor that is open.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
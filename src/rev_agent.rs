@@ -1,61 +1,194 @@
 use std::{
+    collections::{HashMap, VecDeque},
+    io, mem,
+    os::unix::prelude::ExitStatusExt,
     path::{Path, PathBuf},
     process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
 use futures::{
-    future::{self, Either},
-    FutureExt, SinkExt, TryStreamExt,
+    future::{self, BoxFuture, Either},
+    stream::FuturesUnordered,
+    FutureExt, SinkExt, StreamExt, TryStreamExt,
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{
         unix::{OwnedReadHalf, OwnedWriteHalf},
         UnixListener, UnixStream,
     },
-    process::{self, Child, ChildStderr, ChildStdin, ChildStdout},
-    sync::{mpsc, oneshot},
+    process::{self, Child},
+    runtime::Handle,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+    time::{self, Instant},
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use crate::{
-    rpc::{Event, Exec, Request, EXTENSION_TYPE},
-    ssh_agent::{self, Extension, Message, SSH_AGENTC_EXTENSION, SSH_AGENT_SUCCESS},
+    audit::{Auditor, ExecAudit, Outcome},
+    caller::{Caller, HostBinding},
+    config::Config,
+    confirm::Confirmer,
+    connection::ConnectionConfig,
+    dry_run::DryRunConfig,
+    env_policy::EnvPolicy,
+    jobs::{Job, Jobs},
+    limits::Limits,
+    pairing::Pairing,
+    paths::PathConfig,
+    policy::{Policy, Resolved},
+    pty::{Pty, PtyMaster},
+    rpc::{
+        split_session, Auth, Capabilities, Compression, Event, EventBatch, Exec, ExecReply, Limit,
+        Pair, Rejection, RejectionKind, Request, Resume, Resumed, SessionEventBatch, SessionId,
+        Signal, Terminal, Version, WindowSize,
+    },
+    ssh_agent::{
+        self, Extension, Message, QueryReply, SessionBind, QUERY_EXTENSION, SESSION_BIND_EXTENSION,
+        SSH_AGENTC_EXTENSION, SSH_AGENT_SUCCESS,
+    },
+    throttle::{Permit, Throttle},
+    verbs::Verbs,
 };
 pub struct RevAgent {
     listener: UnixListener,
     upstream_sock_path: Option<PathBuf>,
+    context: Arc<Context>,
+}
+
+/// Agent-wide state shared by every client connection.
+struct Context {
+    connection: ConnectionConfig,
+    policy: Policy,
+    env: EnvPolicy,
+    limits: Limits,
+    throttle: Arc<Throttle>,
+    verbs: Verbs,
+    paths: PathConfig,
+    jobs: Jobs,
+    confirmer: Option<Confirmer>,
+    pairing: Option<Pairing>,
+    auditor: Option<Arc<Auditor>>,
+    dry_run: Option<DryRunConfig>,
+    /// Commands whose connection was lost, by resume token.
+    parked: Mutex<HashMap<String, Parked>>,
+}
+
+impl Context {
+    /// Upper bound of the events in one batch: the output window of the
+    /// client if it sent one, but never more than fits in a frame.
+    fn max_batch_bytes(&self, window: Option<u32>) -> usize {
+        let max_chunk = self.connection.max_chunk();
+        match window {
+            Some(window) => (window as usize).clamp(1, max_chunk),
+            None => max_chunk.min(MAX_BATCH_BYTES),
+        }
+    }
+
+    /// Decodes a request of a client, which doesn't get to inflate past
+    /// the frames of this agent.
+    fn request(&self, request: Bytes) -> Result<Request> {
+        Request::decode(request, self.connection.max_frame_bytes)
+    }
+
+    /// Takes the command parked as `resume.token` if it belongs to `owner`
+    /// and still has the events from `resume.seq` on.
+    fn unpark(&self, resume: &Resume, owner: &str) -> Result<Parked, Rejection> {
+        let mut parked = self.parked.lock().unwrap();
+        let rejection = |message| Rejection::new(RejectionKind::UnknownToken, message);
+        let Some(entry) = parked
+            .get(&resume.token)
+            .filter(|entry| entry.owner == owner)
+        else {
+            return Err(rejection("there is no command to resume with this token"));
+        };
+        if let Some(seq) = resume.seq {
+            if !(entry.stream.unacked_seq()..=entry.stream.seq).contains(&seq) {
+                return Err(rejection("the output to resume from is gone"));
+            }
+        }
+        Ok(parked.remove(&resume.token).unwrap())
+    }
 }
 
 impl RevAgent {
-    pub fn new(listener: UnixListener, upstream_sock_path: Option<PathBuf>) -> Self {
-        Self {
+    pub fn new(
+        listener: UnixListener,
+        upstream_sock_path: Option<PathBuf>,
+        config: Config,
+    ) -> Result<Self> {
+        let context = Context {
+            connection: config.connection,
+            policy: config.policy,
+            env: config.env,
+            limits: config.limits,
+            throttle: Arc::new(Throttle::new(config.throttle)),
+            verbs: config.verbs,
+            paths: config.paths,
+            jobs: Jobs::new(config.jobs),
+            confirmer: config.confirm.map(Confirmer::new).transpose()?,
+            pairing: config.pairing.map(Pairing::new).transpose()?,
+            auditor: config.audit.map(Auditor::open).transpose()?.map(Arc::new),
+            dry_run: config.dry_run,
+            parked: Mutex::new(HashMap::new()),
+        };
+        Ok(Self {
             listener,
             upstream_sock_path,
-        }
+            context: Arc::new(context),
+        })
     }
 
-    pub fn open(listen_sock_path: &Path, upstream_sock_path: Option<PathBuf>) -> Result<Self> {
+    pub fn open(
+        listen_sock_path: &Path,
+        upstream_sock_path: Option<PathBuf>,
+        config: Config,
+    ) -> Result<Self> {
         log::trace!("Opening RevAgent");
         let listener = UnixListener::bind(listen_sock_path)?;
-        Ok(Self::new(listener, upstream_sock_path))
+        Self::new(listener, upstream_sock_path, config)
     }
 
     pub async fn run(self) -> Result<()> {
         log::trace!("Running");
-        loop {
+        for connection in 0.. {
             let (client, _addr) = self.listener.accept().await?;
-            tokio::spawn(handle_client(self.upstream_sock_path.clone(), client));
+            tokio::spawn(handle_client(
+                self.upstream_sock_path.clone(),
+                self.context.clone(),
+                connection,
+                client,
+            ));
         }
+        unreachable!()
     }
 }
 
-async fn handle_client(upstream_sock_path: Option<PathBuf>, client: UnixStream) -> Result<()> {
+async fn handle_client(
+    upstream_sock_path: Option<PathBuf>,
+    context: Arc<Context>,
+    connection: u64,
+    client: UnixStream,
+) -> Result<()> {
+    let (caller_tx, caller_rx) = watch::channel(Caller::from_stream(connection, &client));
     let (client_r, client_w) = client.into_split();
-    let mut incoming = FramedRead::new(client_r, ssh_agent::Codec);
-    let mut outgoing = FramedWrite::new(client_w, ssh_agent::Codec);
+    // the configured limit is for ssh-rev messages alone, since forwarded
+    // agent messages such as keys to add are not ours to split
+    let max_frame = context.connection.max_frame_bytes;
+    let mut incoming = FramedRead::new(client_r, ssh_agent::Codec::default());
+    let mut outgoing = FramedWrite::new(client_w, ssh_agent::Codec::default());
     let upstream = if let Some(path) = upstream_sock_path.as_deref() {
         Some(UpstreamAgent::open(path).await?)
     } else {
@@ -90,12 +223,23 @@ async fn handle_client(upstream_sock_path: Option<PathBuf>, client: UnixStream)
     .boxed();
     let rev_ext = RevExt {
         requests: rev_ext_rx,
+        context,
+        caller: caller_rx,
+        version: Version::V1,
+        flow_control: false,
+        resume: false,
+        signaled: false,
+        keepalive: false,
+        compression: None,
+        terminal: None,
     };
     let rev_ext_fut = rev_ext.run().boxed();
     let router = Router {
         requests: request_rx,
         upstream,
         rev_ext: rev_ext_tx,
+        caller: caller_tx,
+        max_frame,
     };
     let request_handler_fut = router.run().boxed();
 
@@ -112,7 +256,10 @@ async fn handle_client(upstream_sock_path: Option<PathBuf>, client: UnixStream)
 struct Router {
     requests: mpsc::Receiver<(Message, oneshot::Sender<Message>)>,
     upstream: Option<UpstreamAgent>,
-    rev_ext: mpsc::Sender<(Bytes, oneshot::Sender<Message>)>,
+    rev_ext: mpsc::Sender<ExtRequest>,
+    caller: watch::Sender<Caller>,
+    /// Longest ssh-rev message taken from the client.
+    max_frame: usize,
 }
 
 impl Router {
@@ -133,22 +280,100 @@ impl Router {
                 let reply = move |reply_tx: oneshot::Sender<_>, msg| {
                     reply_tx.send(msg).map_err(|_| anyhow!("failed to reply"))
                 };
-                let Ok::<Extension, _>(ext) = request.contents.try_into() else {
+                let Ok::<Extension, _>(ext) = request.contents.clone().try_into() else {
                     reply(reply_tx, Message::failure())?;
                     return Ok(());
                 };
-                if &*ext.extension_type != EXTENSION_TYPE {
+                if &*ext.extension_type == SESSION_BIND_EXTENSION {
+                    return self
+                        .handle_session_bind(request, ext.contents, reply_tx)
+                        .await;
+                }
+                if &*ext.extension_type == QUERY_EXTENSION {
+                    return self.handle_query(request, reply_tx).await;
+                }
+                let Some(version) = Version::from_extension_type(&ext.extension_type) else {
                     reply(reply_tx, Message::failure())?;
                     return Ok(());
+                };
+                let len = request.contents.len() + 1;
+                if len > self.max_frame {
+                    log::warn!(
+                        "Refused a message of {} bytes; the limit is {}",
+                        len,
+                        self.max_frame
+                    );
+                    reply(reply_tx, Message::extension_failure())?;
+                    return Ok(());
                 }
-                Ok(self.rev_ext.send((ext.contents, reply_tx)).await?)
+                Ok(self.rev_ext.send((version, ext.contents, reply_tx)).await?)
             }
-            // TODO: support "4.7.1.  Query extension"
-            // https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#section-4.7.1
             _ => self.forward_to_upstream(request, reply_tx).await,
         }
     }
 
+    /// Records the host the connection is bound to. The binding is passed on
+    /// to the upstream agent, which both keeps destination-constrained keys
+    /// working and has it check the host signature for us.
+    async fn handle_session_bind(
+        &mut self,
+        request: Message,
+        contents: Bytes,
+        reply_tx: oneshot::Sender<Message>,
+    ) -> Result<()> {
+        let reply = move |msg| reply_tx.send(msg).map_err(|_| anyhow!("failed to reply"));
+        let bind = match SessionBind::try_from(contents) {
+            Ok(bind) => bind,
+            Err(err) => {
+                log::warn!("Malformed session-bind: {}", err);
+                return reply(Message::failure());
+            }
+        };
+        let reply_msg = if let Some(upstream) = self.upstream.as_mut() {
+            upstream.request(&request).await?
+        } else {
+            Message::success()
+        };
+        let verified = self.upstream.is_some() && reply_msg.message_type == SSH_AGENT_SUCCESS;
+        let binding = HostBinding::new(&bind, verified).await;
+        log::debug!("Session bound to {}", binding);
+        self.caller
+            .send_modify(|caller| caller.bindings.push(binding));
+        reply(reply_msg)
+    }
+
+    /// Answers `query` with the extensions of the upstream agent as well as
+    /// our own, since every other extension request is passed on to it.
+    async fn handle_query(
+        &mut self,
+        request: Message,
+        reply_tx: oneshot::Sender<Message>,
+    ) -> Result<()> {
+        let mut extension_types = vec![];
+        if let Some(upstream) = self.upstream.as_mut() {
+            let reply = upstream.request(&request).await?;
+            if reply.message_type == SSH_AGENT_SUCCESS {
+                match QueryReply::try_from(reply.contents) {
+                    Ok(upstream_types) => extension_types = upstream_types.0,
+                    Err(err) => log::warn!("Malformed query reply from upstream: {}", err),
+                }
+            }
+        }
+        let own_types = [QUERY_EXTENSION, SESSION_BIND_EXTENSION]
+            .into_iter()
+            .chain(Version::ALL.map(Version::extension_type));
+        for extension_type in own_types {
+            if !extension_types.iter().any(|known| known == extension_type) {
+                extension_types.push(Bytes::from_static(extension_type));
+            }
+        }
+        let reply = Message {
+            message_type: SSH_AGENT_SUCCESS,
+            contents: QueryReply(extension_types).into(),
+        };
+        reply_tx.send(reply).map_err(|_| anyhow!("failed to reply"))
+    }
+
     async fn forward_to_upstream(
         &mut self,
         request: Message,
@@ -156,13 +381,7 @@ impl Router {
     ) -> Result<()> {
         let reply = move |msg| reply_tx.send(msg).map_err(|_| anyhow!("failed to reply"));
         if let Some(upstream) = self.upstream.as_mut() {
-            upstream.write.send(&request).await?;
-            let reply_msg = upstream
-                .read
-                .try_next()
-                .await?
-                .ok_or_else(|| anyhow!("upstream agent has gone"))?;
-            reply(reply_msg)?;
+            reply(upstream.request(&request).await?)?;
         } else {
             reply(Message::failure())?;
         }
@@ -170,53 +389,969 @@ impl Router {
     }
 }
 
+/// A request to the extension, with the version it was sent as.
+type ExtRequest = (Version, Bytes, oneshot::Sender<Message>);
+
+/// Upper bound of the events in one v2 `Watch` reply, in encoded bytes,
+/// unless the frame limit is lower.
+const MAX_BATCH_BYTES: usize = 64 * 1024;
+/// What an event adds to a batch besides its data: the session, the length
+/// of the data and the event code.
+const EVENT_OVERHEAD: usize = 9;
+/// How many events are read ahead of the client's watches in v2.
+const EVENT_BACKLOG: usize = 64;
+/// How many chunks of stdin are queued ahead of the command reading them.
+const STDIN_BACKLOG: usize = 256;
+
 struct RevExt {
-    requests: mpsc::Receiver<(Bytes, oneshot::Sender<Message>)>,
+    requests: mpsc::Receiver<ExtRequest>,
+    context: Arc<Context>,
+    caller: watch::Receiver<Caller>,
+    /// The version of the request that started the command.
+    version: Version,
+    /// Whether the client asked for flow control in its `Hello`.
+    flow_control: bool,
+    /// Whether the client said in its `Hello` that it can resume commands.
+    resume: bool,
+    /// Whether the client said in its `Hello` that it takes
+    /// [`Event::Signaled`].
+    signaled: bool,
+    /// Whether the client said in its `Hello` that it takes heartbeats.
+    keepalive: bool,
+    /// How the client asked in its `Hello` for output to be deflated.
+    compression: Option<Compression>,
+    /// Set by [`Request::Pty`] for the next command to start.
+    terminal: Option<Terminal>,
 }
 
-#[derive(Debug)]
+/// The stdin of a command: a pipe, or the terminal it runs on.
+type Input = Box<dyn AsyncWrite + Send + Unpin>;
+/// The stdout or stderr of a command.
+type Output = Box<dyn AsyncRead + Send + Unpin>;
+
 struct Running {
+    /// [`Caller::owner`] of the client that started it.
+    owner: String,
     child: Child,
-    stdin: Option<ChildStdin>,
-    stdout: Option<ChildStdout>,
-    stderr: Option<ChildStderr>,
+    stdin: Option<Input>,
+    stdout: Option<Output>,
+    /// `None` from the start on a terminal, where stderr is stdout.
+    stderr: Option<Output>,
+    pty: Option<PtyMaster>,
+    audit: Option<ExecAudit>,
+    /// Dropped once the command has exited.
+    deadline: Option<Deadline>,
+    output_bytes: u64,
+    /// Set once the agent killed the command for exceeding a limit.
+    killed_for: Option<Limit>,
+    /// Released once the command has exited.
+    permit: Option<Permit>,
+}
+
+impl Running {
+    /// Like [`RevExt::watch`], but enforces the limits that are checked while
+    /// the command runs.
+    async fn watch(&mut self, context: &Context) -> Result<Event> {
+        let limits = &context.limits;
+        if let Some(limit) = self.killed_for {
+            self.child.wait().await?;
+            return Ok(Event::LimitExceeded(limit));
+        }
+        let event = RevExt::watch(
+            &mut self.stdout,
+            &mut self.stderr,
+            &mut self.child,
+            context.connection.max_chunk(),
+        )
+        .await?;
+        if event.is_last() {
+            // its process group may be someone else's from now on
+            if let Some(deadline) = self.deadline.take() {
+                if deadline.is_expired() {
+                    self.killed_for = Some(Limit::Timeout);
+                    return Ok(Event::LimitExceeded(Limit::Timeout));
+                }
+            }
+        }
+        let event = match event {
+            Event::Stdout(bytes) => Event::Stdout(self.limit_output(bytes, limits)),
+            Event::Stderr(bytes) => Event::Stderr(self.limit_output(bytes, limits)),
+            Event::Signaled {
+                signal: libc::SIGXCPU,
+                ..
+            } if limits.cpu_secs.is_some() => {
+                self.killed_for = Some(Limit::Cpu);
+                Event::LimitExceeded(Limit::Cpu)
+            }
+            event => event,
+        };
+        Ok(event)
+    }
+
+    /// Cuts `bytes` off at the output limit, and kills the command if it
+    /// was reached.
+    fn limit_output(&mut self, bytes: Bytes, limits: &Limits) -> Bytes {
+        self.output_bytes += bytes.len() as u64;
+        let Some(max) = limits.max_output_bytes else {
+            return bytes;
+        };
+        if self.output_bytes <= max {
+            return bytes;
+        }
+        let excess = (self.output_bytes - max) as usize;
+        if self.killed_for.is_none() {
+            self.killed_for = Some(Limit::Output);
+            // the id is gone once the command was reaped
+            if let Some(pid) = self.child.id() {
+                kill_group(pid, libc::SIGKILL);
+            }
+        }
+        bytes.slice(..bytes.len().saturating_sub(excess))
+    }
+
+    fn audit_event(&mut self, event: &Event) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        match event {
+            Event::Stdout(bytes) => audit.stdout(bytes.len()),
+            Event::Stderr(bytes) => audit.stderr(bytes.len()),
+            Event::Exited(_) | Event::LimitExceeded(_) | Event::Signaled { .. } => {
+                if let Ok(Some(status)) = self.child.try_wait() {
+                    audit.exited(status, self.killed_for);
+                }
+            }
+            Event::Cancelled | Event::WindowAdjust(_) => {}
+        }
+    }
+}
+
+/// Kills the process group of a command once its time is up, whether or
+/// not anyone is watching it. Stopped when dropped.
+struct Deadline {
+    timer: JoinHandle<()>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    fn start(at: Instant, pid: u32) -> Self {
+        let expired = Arc::new(AtomicBool::new(false));
+        let timer = tokio::spawn({
+            let expired = expired.clone();
+            async move {
+                time::sleep_until(at).await;
+                log::info!("Killing process group {} for exceeding its time limit", pid);
+                expired.store(true, Ordering::Relaxed);
+                kill_group(pid, libc::SIGKILL);
+            }
+        });
+        Self { timer, expired }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        self.timer.abort();
+    }
+}
+
+/// A command as [`RevExt::exec`] started it.
+struct Spawned {
+    child: Child,
+    stdin: Input,
+    stdout: Output,
+    stderr: Option<Output>,
+    pty: Option<PtyMaster>,
+}
+
+/// Feeds the stdin of a v3 session and pumps its events until it exits.
+/// A failure ends only its own session.
+type SessionTask = BoxFuture<'static, SessionId>;
+
+/// Starts a v3 session, and hands back where to reply once it has.
+type SessionStart = BoxFuture<
+    'static,
+    (
+        SessionId,
+        oneshot::Sender<Message>,
+        Result<(Message, Option<Started>)>,
+    ),
+>;
+
+/// Hands the stdin of a v2 or v3 command to its writer task, and keeps a
+/// client that does flow control to its window.
+struct StdinFeed {
+    stdin: Option<mpsc::Sender<Bytes>>,
+    /// The command to pass signals on to, if there is one.
+    pid: Option<u32>,
+    pty: Option<PtyMaster>,
+    audit: Option<ExecAudit>,
+    /// Bytes taken ahead of the command, whatever the client said in its
+    /// `Hello`.
+    window: usize,
+    /// Whether the client is told as the command reads, to pace itself.
+    flow_control: bool,
+    /// Bytes passed on that the command has not read yet.
+    unread: usize,
+    /// Bytes from before the command was resumed that the command has not
+    /// read yet. The new client never had them counted against its window.
+    stale: usize,
+}
+
+impl StdinFeed {
+    /// Feeds `stdin` to `running`, which is `None` for commands that were
+    /// not started on this connection.
+    fn new(
+        stdin: Option<mpsc::Sender<Bytes>>,
+        running: Option<&Running>,
+        window: usize,
+        flow_control: bool,
+    ) -> Self {
+        Self {
+            stdin,
+            pid: running.and_then(|r| r.child.id()),
+            pty: running.and_then(|r| r.pty.clone()),
+            audit: running.and_then(|r| r.audit.clone()),
+            window,
+            flow_control,
+            unread: 0,
+            stale: 0,
+        }
+    }
+
+    fn stdin(&mut self, bytes: Bytes) -> Message {
+        let len = bytes.len();
+        if self.unread + len > self.window {
+            log::warn!("Refused stdin beyond the window of {} bytes", self.window);
+            return Message::extension_failure();
+        }
+        let is_eof = bytes.is_empty();
+        let sent = self.stdin.as_ref().map(|tx| tx.try_send(bytes));
+        if is_eof {
+            self.stdin = None;
+        }
+        match sent {
+            Some(Ok(())) => {
+                self.unread += len;
+                if let Some(audit) = &self.audit {
+                    audit.stdin(len);
+                }
+                Message::success()
+            }
+            Some(Err(TrySendError::Full(_))) => {
+                log::warn!("Refused stdin beyond {} unread chunks", STDIN_BACKLOG);
+                Message::extension_failure()
+            }
+            _ => Message::extension_failure(),
+        }
+    }
+
+    /// Takes note of an [`Event::WindowAdjust`] on its way to the client,
+    /// and returns whether to pass the event on.
+    fn adjust(&mut self, event: &Event) -> bool {
+        let Event::WindowAdjust(bytes) = event else {
+            return true;
+        };
+        let bytes = *bytes as usize;
+        if self.stale > 0 {
+            self.stale = self.stale.saturating_sub(bytes);
+            return false;
+        }
+        self.unread = self.unread.saturating_sub(bytes);
+        self.flow_control
+    }
+
+    fn signal(&self, signal: Signal) -> Message {
+        if let Some(pid) = self.pid {
+            deliver(pid, signal);
+        }
+        Message::success()
+    }
+
+    fn resize(&self, size: WindowSize) -> Message {
+        if let Some(pty) = &self.pty {
+            resize(pty, size);
+        }
+        Message::success()
+    }
+
+    /// Starts over with a client that resumed the command.
+    fn restart(&mut self, window: usize, flow_control: bool) {
+        self.window = window;
+        self.flow_control = flow_control;
+        self.stale += mem::take(&mut self.unread);
+    }
+}
+
+/// A command served with v2, or a job attached to. Output is read ahead
+/// into a backlog while replies are on their way, and stdin is written by
+/// a separate task so that neither direction waits for the other. Both
+/// tasks are stopped when it is dropped, which kills the command.
+struct Stream {
+    feed: StdinFeed,
+    events: mpsc::Receiver<Event>,
+    pump: JoinHandle<Result<()>>,
+    writer: JoinHandle<Result<()>>,
+    pump_done: bool,
+    writer_done: bool,
+    backlog: VecDeque<Event>,
+    backlog_bytes: usize,
+    max_batch: usize,
+    /// How the client that is served the stream wants output deflated.
+    compression: Option<Compression>,
+    /// The number of the next event to send.
+    seq: u64,
+    /// Events sent since the last `Watch`, which the client may have missed
+    /// if the connection was lost.
+    unacked: Vec<Event>,
+}
+
+impl Stream {
+    /// Replies to a watch with as many events of the backlog as fit in a
+    /// batch, possibly none.
+    fn send_batch(&mut self, watch: oneshot::Sender<Message>) -> Result<()> {
+        let batch = EventBatch {
+            seq: self.seq,
+            events: take_batch(
+                &mut self.backlog,
+                &mut self.backlog_bytes,
+                self.max_batch,
+                Event::data_len,
+            ),
+        };
+        self.seq += batch.events.len() as u64;
+        self.unacked.extend(batch.events.iter().cloned());
+        watch
+            .send(Message {
+                message_type: SSH_AGENT_SUCCESS,
+                contents: batch.into_compressed_bytes(self.compression),
+            })
+            .map_err(|_| anyhow!("failed to reply"))
+    }
+
+    /// The number of the first event the client may have missed.
+    fn unacked_seq(&self) -> u64 {
+        self.seq - self.unacked.len() as u64
+    }
+
+    /// Whether every event has been sent, the exit included.
+    fn is_over(&mut self) -> bool {
+        while let Ok(event) = self.events.try_recv() {
+            if self.feed.adjust(&event) {
+                self.backlog.push_back(event);
+            }
+        }
+        self.pump.is_finished() && self.backlog.is_empty()
+    }
+
+    /// Gets ready for a client that resumed the stream from event `seq`, or
+    /// from the last batch sent without it.
+    fn rewind(&mut self, seq: Option<u64>, window: usize, flow_control: bool) -> Resumed {
+        let first = self.unacked_seq();
+        let seq = seq.unwrap_or(first);
+        let missed = self.unacked.split_off((seq - first) as usize);
+        self.unacked.clear();
+        for event in missed.into_iter().rev() {
+            self.backlog.push_front(event);
+        }
+        self.seq = seq;
+        // the new client starts with a full window
+        self.backlog
+            .retain(|event| !matches!(event, Event::WindowAdjust(_)));
+        self.backlog_bytes = self.backlog.iter().map(Event::data_len).sum();
+        self.feed.restart(window, flow_control);
+        Resumed {
+            seq,
+            stdin: self.feed.stdin.is_some() && !self.writer.is_finished(),
+            pty: self.feed.pty.is_some(),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.pump.abort();
+        self.writer.abort();
+    }
+}
+
+/// A v2 command that its client can resume on another connection with
+/// `token`. Dropped while the command is still going, as when the
+/// connection is lost, it is parked for the grace period.
+struct Resumable {
+    token: String,
+    owner: String,
+    /// Only taken when dropped.
+    stream: Option<Stream>,
+    context: Arc<Context>,
+}
+
+impl Resumable {
+    fn stream(&mut self) -> &mut Stream {
+        self.stream.as_mut().unwrap()
+    }
+}
+
+impl Drop for Resumable {
+    fn drop(&mut self) {
+        let Some(mut stream) = self.stream.take() else {
+            return;
+        };
+        if stream.is_over() {
+            return;
+        }
+        let Ok(runtime) = Handle::try_current() else {
+            return;
+        };
+        let grace = self.context.connection.resume_grace();
+        log::info!("Keeping a command for {:?} to be resumed", grace);
+        let context = self.context.clone();
+        let token = self.token.clone();
+        let timer = runtime.spawn(async move {
+            time::sleep(grace).await;
+            // dropping it kills the command
+            let parked = context.parked.lock().unwrap().remove(&token);
+            if parked.is_some() {
+                log::info!("Killed a command that was not resumed in time");
+            }
+        });
+        let parked = Parked {
+            owner: mem::take(&mut self.owner),
+            stream,
+            timer,
+        };
+        let token = mem::take(&mut self.token);
+        self.context.parked.lock().unwrap().insert(token, parked);
+    }
+}
+
+/// A [`Resumable`] whose connection was lost, until it is resumed or its
+/// grace period is over.
+struct Parked {
+    owner: String,
+    stream: Stream,
+    timer: JoinHandle<()>,
+}
+
+/// What an accepted exec request turned into.
+enum Started {
+    Running(Box<Running>),
+    /// Events to reply with in place of a command that was not run.
+    DryRun(VecDeque<Event>),
+    /// A detached job to stream the output of.
+    Attached(Arc<Job>),
+    /// A v2 command taken over from a lost connection.
+    Resumed(Box<Resumable>),
 }
 
 impl RevExt {
+    /// `event` as the client takes it.
+    fn for_client(&self, event: Event) -> Event {
+        if self.signaled {
+            event
+        } else {
+            event.without_signal()
+        }
+    }
+
+    /// Bytes of stdin taken ahead of the command, whether or not the client
+    /// does flow control.
+    fn stdin_window(&self) -> usize {
+        self.context.connection.stdin_window_bytes as usize
+    }
+
+    /// How long a `Watch` waits before it is replied to with no events, if
+    /// the client takes heartbeats.
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        self.context
+            .connection
+            .keepalive()
+            .filter(|_| self.keepalive)
+    }
+
+    /// How long the client may stay silent before it is taken for gone, if
+    /// it takes heartbeats and so has reason to keep asking for output.
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.context
+            .connection
+            .idle_timeout()
+            .filter(|_| self.keepalive)
+    }
+
+    /// Logs that the connection is dropped for having gone silent.
+    fn log_idle(&self, idle_timeout: Duration) {
+        log::info!(
+            "Dropped the connection of {}, which was silent for {:?}",
+            *self.caller.borrow(),
+            idle_timeout
+        );
+    }
+
     async fn run(mut self) -> Result<()> {
-        let Some(running) = self.handle_exec().await? else {
-            return Ok(())
-        };
-        self.handle_stdin_watch(running).await?;
+        while let Some((version, request, reply_tx)) = self.requests.recv().await {
+            let reply = move |msg| reply_tx.send(msg).map_err(|_| anyhow!("failed to reply"));
+            let (session, request) = match version {
+                Version::V3 => match split_session(request) {
+                    Ok(split) => split,
+                    Err(_) => {
+                        reply(Message::extension_failure())?;
+                        continue;
+                    }
+                },
+                _ => (0, request),
+            };
+            self.version = version;
+            let (mut message, started) = self.start(request).await?;
+            let token = match &started {
+                Some(Started::Running(r)) if self.is_resumable() => {
+                    Some((resume_token()?, r.owner.clone()))
+                }
+                _ => None,
+            };
+            if let Some((token, _)) = &token {
+                message = json_reply(&ExecReply {
+                    resume_token: Some(token.clone()),
+                })?;
+            }
+            reply(message)?;
+            let Some(started) = started else {
+                continue;
+            };
+            return match (version, started) {
+                (Version::V3, started) => self.handle_mux(session, started).await,
+                (_, Started::DryRun(events)) => self.handle_dry_run(events).await,
+                (_, Started::Resumed(mut resumable)) => {
+                    self.handle_stream(resumable.stream()).await
+                }
+                (Version::V1, Started::Running(running)) => self.handle_stdin_watch(*running).await,
+                (Version::V1, Started::Attached(_)) => unreachable!("jobs are attached with v2"),
+                (Version::V2, started) => {
+                    let stream = self.open_stream(started);
+                    match token {
+                        Some((token, owner)) => {
+                            let mut resumable = Resumable {
+                                token,
+                                owner,
+                                stream: Some(stream),
+                                context: self.context.clone(),
+                            };
+                            self.handle_stream(resumable.stream()).await
+                        }
+                        None => self.handle_stream(&mut { stream }).await,
+                    }
+                }
+            };
+        }
         Ok(())
     }
 
-    async fn handle_exec(&mut self) -> Result<Option<Running>> {
-        while let Some((request, reply_tx)) = self.requests.recv().await {
-            let reply = move |msg| reply_tx.send(msg).map_err(|_| anyhow!("failed to reply"));
-            if let Ok(Request::Exec(exec)) = Request::try_from(request) {
-                let (child, stdin, stdout, stderr) = Self::exec(&exec).await?;
-                let running = Running {
-                    child,
-                    stdin: Some(stdin),
-                    stdout: Some(stdout),
-                    stderr: Some(stderr),
-                };
-                reply(Message {
+    /// Handles a request made while no command is running, and returns the
+    /// reply to it along with the command if it started one.
+    async fn start(&mut self, request: Bytes) -> Result<(Message, Option<Started>)> {
+        let mut caller = self.caller.borrow().clone();
+        let request = match self.context.request(request) {
+            Ok(Request::Pair(pair)) => return Ok((self.pair(&pair, &caller).await?, None)),
+            Ok(Request::Hello(hello)) => {
+                self.flow_control = hello.flow_control;
+                self.resume = hello.resume;
+                self.signaled = hello.signaled;
+                self.keepalive = hello.keepalive;
+                self.compression = hello.compression;
+                let reply = Message {
                     message_type: SSH_AGENT_SUCCESS,
-                    contents: Bytes::new(),
-                })?;
-                return Ok(Some(running));
-            } else {
-                reply(Message::extension_failure())?;
+                    contents: serde_json::to_vec(&Capabilities::local(&self.context.connection))?
+                        .into(),
+                };
+                return Ok((reply, None));
+            }
+            Ok(Request::Pty(terminal)) => {
+                self.terminal = Some(terminal);
+                return Ok((Message::success(), None));
+            }
+            Ok(Request::Authenticated(auth)) => match self.authenticate(&auth).await {
+                Ok(client_id) => {
+                    caller.paired_client = client_id;
+                    self.context.request(auth.inner)
+                }
+                Err(rejection) => {
+                    log::info!("Rejected request from {}: {}", caller, rejection);
+                    self.audit(&caller, None, Outcome::Rejected(&rejection));
+                    return Ok((rejection.into_message()?, None));
+                }
+            },
+            request => request,
+        };
+        let request = match request {
+            Ok(
+                request
+                @ (Request::Jobs | Request::Attach(_) | Request::Wait(_) | Request::Kill(_)),
+            ) => {
+                return self.manage_jobs(request, &caller).await;
+            }
+            Ok(Request::Resume(resume)) => return self.resume(resume, &caller),
+            request => request,
+        };
+        let (request, detach) = match request {
+            Ok(Request::Detach(inner)) => (self.context.request(inner), true),
+            request => (request, false),
+        };
+        let terminal = self.terminal.take();
+        let (mut exec, verb) = match request {
+            Ok(Request::Exec(exec)) => (exec, None),
+            Ok(Request::Verb(call)) => match self.context.verbs.expand(&call) {
+                Ok(exec) => (exec, Some(call.verb)),
+                Err(rejection) => {
+                    log::info!("Rejected {:?} from {}: {}", call, caller, rejection);
+                    self.audit(&caller, None, Outcome::Rejected(&rejection));
+                    return Ok((rejection.into_message()?, None));
+                }
+            },
+            _ => return Ok((Message::extension_failure(), None)),
+        };
+        self.context.paths.translate(&mut exec, &caller);
+        // checked by the env policy like any requested variable, and one
+        // given with `-e` wins
+        if let Some(term) = terminal.as_ref().and_then(|terminal| terminal.term.clone()) {
+            exec.envs.entry("TERM".into()).or_insert(term.into());
+        }
+        let resolved = Resolved::new(&exec).await;
+        if let Some(dry_run) = &self.context.dry_run {
+            let decision = self.check(&mut exec, &resolved, verb.as_deref(), &caller);
+            match &decision {
+                Ok(()) => log::info!("Dry run: would run {:?} for {}", exec, caller),
+                Err(rejection) => log::info!(
+                    "Dry run: would reject {:?} from {}: {}",
+                    exec,
+                    caller,
+                    rejection
+                ),
+            }
+            let rejection = decision.as_ref().err();
+            self.audit(&caller, Some(&exec), Outcome::DryRun { rejection });
+            let events = dry_run.events(&exec, decision.as_ref().map(|_| ()));
+            if !detach {
+                return Ok((Message::success(), Some(Started::DryRun(events))));
             }
+            let job = match self.add_job(&exec, &caller) {
+                Ok(job) => job,
+                Err(rejection) => return Ok((rejection.into_message()?, None)),
+            };
+            for event in events {
+                job.push(event);
+            }
+            return Ok((json_reply(&job.status())?, None));
+        }
+        let authorized = self
+            .authorize(&mut exec, &resolved, verb.as_deref(), &caller)
+            .await;
+        let permit = match authorized {
+            Ok(permit) => permit,
+            Err(rejection) => {
+                log::info!("Rejected {:?} from {}: {}", exec, caller, rejection);
+                self.audit(&caller, Some(&exec), Outcome::Rejected(&rejection));
+                return Ok((rejection.into_message()?, None));
+            }
+        };
+        let job = match detach.then(|| self.add_job(&exec, &caller)).transpose() {
+            Ok(job) => job,
+            Err(rejection) => return Ok((rejection.into_message()?, None)),
+        };
+        log::info!("Running {:?} for {}", exec, caller);
+        let spawned = self
+            .exec(
+                &exec,
+                resolved.program.as_deref(),
+                terminal.as_ref(),
+                &caller,
+            )
+            .await;
+        let spawned = match spawned {
+            Ok(spawned) => spawned,
+            Err(rejection) => {
+                if let Some(job) = &job {
+                    self.context.jobs.remove(job.id);
+                }
+                log::info!("Failed to run {:?} for {}: {}", exec, caller, rejection);
+                let outcome = Outcome::SpawnFailed {
+                    error: rejection.message.clone(),
+                    errno: rejection.errno,
+                };
+                self.audit(&caller, Some(&exec), outcome);
+                return Ok((rejection.into_message()?, None));
+            }
+        };
+        let pid = spawned.child.id();
+        self.audit(&caller, Some(&exec), Outcome::Spawned { pid });
+        let owner = caller.owner();
+        let audit = self
+            .context
+            .auditor
+            .clone()
+            .map(|auditor| ExecAudit::new(auditor, caller, exec));
+        let mut running = Running {
+            owner,
+            child: spawned.child,
+            stdin: Some(spawned.stdin),
+            stdout: Some(spawned.stdout),
+            stderr: spawned.stderr,
+            pty: spawned.pty,
+            audit,
+            deadline: self
+                .context
+                .limits
+                .deadline()
+                .zip(pid)
+                .map(|(at, pid)| Deadline::start(at, pid)),
+            output_bytes: 0,
+            killed_for: None,
+            permit: Some(permit),
+        };
+        let Some(job) = job else {
+            return Ok((
+                Message::success(),
+                Some(Started::Running(Box::new(running))),
+            ));
+        };
+        // nobody is there to give it input
+        running.stdin = None;
+        let status = job.status();
+        tokio::spawn(run_job(running, self.context.clone(), job));
+        Ok((json_reply(&status)?, None))
+    }
+
+    /// Enters `exec` in the job table, unless it is full.
+    fn add_job(&self, exec: &Exec, caller: &Caller) -> Result<Arc<Job>, Rejection> {
+        self.context
+            .jobs
+            .add(caller.owner(), exec)
+            .map_err(|rejection| {
+                log::info!("Rejected {:?} from {}: {}", exec, caller, rejection);
+                self.audit(caller, Some(exec), Outcome::Rejected(&rejection));
+                rejection
+            })
+    }
+
+    /// Handles the requests that deal with the detached jobs of `caller`.
+    async fn manage_jobs(
+        &self,
+        request: Request,
+        caller: &Caller,
+    ) -> Result<(Message, Option<Started>)> {
+        if let Err(rejection) = self.check_paired(caller) {
+            return Ok((rejection.into_message()?, None));
+        }
+        let jobs = &self.context.jobs;
+        let owner = caller.owner();
+        let id = match request {
+            Request::Jobs => return Ok((json_reply(&jobs.list(&owner))?, None)),
+            Request::Attach(id) | Request::Wait(id) | Request::Kill(id) => id,
+            _ => return Ok((Message::extension_failure(), None)),
+        };
+        let job = match jobs.get(id, &owner) {
+            Ok(job) => job,
+            Err(rejection) => return Ok((rejection.into_message()?, None)),
+        };
+        match request {
+            Request::Attach(_) if self.version >= Version::V2 => {
+                Ok((json_reply(&job.status())?, Some(Started::Attached(job))))
+            }
+            Request::Wait(_) => {
+                let status = job.wait().await;
+                jobs.remove(id);
+                Ok((json_reply(&status)?, None))
+            }
+            Request::Kill(_) => {
+                log::info!("Killing job {} for {}", id, caller);
+                job.kill();
+                Ok((json_reply(&job.status())?, None))
+            }
+            _ => Ok((Message::extension_failure(), None)),
         }
-        Ok(None)
+    }
+
+    /// Whether commands started now outlive a lost connection for a while.
+    fn is_resumable(&self) -> bool {
+        self.version == Version::V2
+            && self.resume
+            && !self.context.connection.resume_grace().is_zero()
+    }
+
+    /// Takes over the parked command that `resume` names, if it belongs to
+    /// `caller`.
+    fn resume(&self, resume: Resume, caller: &Caller) -> Result<(Message, Option<Started>)> {
+        if self.version != Version::V2 {
+            return Ok((Message::extension_failure(), None));
+        }
+        if let Err(rejection) = self.check_paired(caller) {
+            return Ok((rejection.into_message()?, None));
+        }
+        let Parked {
+            owner,
+            mut stream,
+            timer,
+        } = match self.context.unpark(&resume, &caller.owner()) {
+            Ok(parked) => parked,
+            Err(rejection) => {
+                log::info!("Refused to resume a command for {}: {}", caller, rejection);
+                return Ok((rejection.into_message()?, None));
+            }
+        };
+        timer.abort();
+        log::info!("Resuming a command for {}", caller);
+        let resumed = stream.rewind(resume.seq, self.stdin_window(), self.flow_control);
+        let resumable = Resumable {
+            token: resume.token,
+            owner,
+            stream: Some(stream),
+            context: self.context.clone(),
+        };
+        Ok((
+            json_reply(&resumed)?,
+            Some(Started::Resumed(Box::new(resumable))),
+        ))
+    }
+
+    fn audit(&self, caller: &Caller, exec: Option<&Exec>, outcome: Outcome) {
+        if let Some(auditor) = &self.context.auditor {
+            auditor.record(caller, exec, outcome);
+        }
+    }
+
+    async fn pair(&self, pair: &Pair, caller: &Caller) -> Result<Message> {
+        let Some(pairing) = &self.context.pairing else {
+            return Rejection::new(
+                RejectionKind::Unpaired,
+                "pairing is not enabled on this agent",
+            )
+            .into_message();
+        };
+        match pairing.pair(pair, caller).await {
+            Ok(pair_reply) => Ok(Message {
+                message_type: SSH_AGENT_SUCCESS,
+                contents: serde_json::to_vec(&pair_reply)?.into(),
+            }),
+            Err(rejection) => {
+                log::info!("Refused to pair with {}: {}", caller, rejection);
+                rejection.into_message()
+            }
+        }
+    }
+
+    /// Returns the id of the paired client that made `auth`. When pairing is
+    /// optional, requests that fail verification are treated as anonymous.
+    async fn authenticate(&self, auth: &Auth) -> Result<Option<String>, Rejection> {
+        let Some(pairing) = &self.context.pairing else {
+            return Ok(None);
+        };
+        match pairing.verify(auth).await {
+            Ok(client_id) => Ok(Some(client_id)),
+            Err(rejection) if !pairing.required() => {
+                log::warn!("Ignoring authentication: {}", rejection);
+                Ok(None)
+            }
+            Err(rejection) => Err(rejection),
+        }
+    }
+
+    async fn authorize(
+        &self,
+        exec: &mut Exec,
+        resolved: &Resolved,
+        verb: Option<&str>,
+        caller: &Caller,
+    ) -> Result<Permit, Rejection> {
+        self.check(exec, resolved, verb, caller)?;
+        // taken before asking the user so that prompts are throttled too
+        let program = resolved.program.as_deref();
+        let permit = self.context.throttle.acquire(exec, program, caller).await?;
+        if let Some(confirmer) = &self.context.confirmer {
+            confirmer.confirm(exec, caller).await?;
+        }
+        Ok(permit)
+    }
+
+    /// The checks of [`RevExt::authorize`] that need neither waiting nor
+    /// the user.
+    fn check(
+        &self,
+        exec: &mut Exec,
+        resolved: &Resolved,
+        verb: Option<&str>,
+        caller: &Caller,
+    ) -> Result<(), Rejection> {
+        self.check_paired(caller)?;
+        self.context.env.filter(exec)?;
+        match verb {
+            Some(_) => self.context.policy.check_verb(exec, resolved, caller)?,
+            None => self.context.policy.check(exec, resolved, caller)?,
+        }
+        Ok(())
+    }
+
+    fn check_paired(&self, caller: &Caller) -> Result<(), Rejection> {
+        if let Some(pairing) = &self.context.pairing {
+            if pairing.required() && caller.paired_client.is_none() {
+                return Err(Rejection::new(
+                    RejectionKind::Unpaired,
+                    "this host is not paired with the agent; pair it with `ssh-rev pair`",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_dry_run(&mut self, mut events: VecDeque<Event>) -> Result<()> {
+        let mut seq = 0;
+        while let Some((_, request, reply_tx)) = self.requests.recv().await {
+            let message = match self.context.request(request) {
+                Ok(Request::Stdin(_) | Request::Signal(_) | Request::Resize(_)) => {
+                    Message::success()
+                }
+                Ok(Request::Watch(_)) if self.version == Version::V2 => {
+                    let batch = EventBatch {
+                        seq,
+                        events: events.drain(..).collect(),
+                    };
+                    seq += batch.events.len() as u64;
+                    Message {
+                        message_type: SSH_AGENT_SUCCESS,
+                        contents: batch.into_bytes(),
+                    }
+                }
+                Ok(Request::Watch(_)) => match events.pop_front() {
+                    Some(event) => Message {
+                        message_type: SSH_AGENT_SUCCESS,
+                        contents: event.into_bytes(),
+                    },
+                    None => Message::extension_failure(),
+                },
+                _ => Message::extension_failure(),
+            };
+            reply_tx
+                .send(message)
+                .map_err(|_| anyhow!("failed to reply"))?;
+        }
+        Ok(())
     }
 
     async fn handle_stdin_watch(&mut self, mut r: Running) -> Result<()> {
-        let mut peek_buf: Option<(Bytes, oneshot::Sender<Message>)> = None;
-        while let Some((request, reply_tx)) = {
+        // a command that does not read its stdin right away must not keep
+        // the watches that would drain its output from being served, up to
+        // a backlog
+        let (stdin_tx, stdin_rx) = mpsc::channel(STDIN_BACKLOG);
+        let mut stdin_tx = Some(stdin_tx);
+        let writer = write_stdin(r.stdin.take(), stdin_rx, None, |event| event);
+        tokio::spawn(async move {
+            if let Err(err) = writer.await {
+                log::debug!("Stopped writing to stdin: {}", err);
+            }
+        });
+        let mut peek_buf: Option<ExtRequest> = None;
+        while let Some((_, request, reply_tx)) = {
             if let Some(peek_buf) = peek_buf.take() {
                 Some(peek_buf)
             } else {
@@ -224,18 +1359,26 @@ impl RevExt {
             }
         } {
             let reply = move |msg| reply_tx.send(msg).map_err(|_| anyhow!("failed to reply"));
-            let Ok(request) = Request::try_from(request) else {
+            let Ok(request) = self.context.request(request) else {
                 reply(Message::extension_failure())?;
                 continue;
             };
             match request {
                 Request::Stdin(bytes) => {
-                    if let Some(stdin) = r.stdin.as_mut() {
-                        if bytes.is_empty() {
-                            stdin.shutdown().await?;
-                            drop(r.stdin.take()); // drop stdin to close
-                        } else {
-                            stdin.write_all(&bytes).await?;
+                    let len = bytes.len();
+                    let is_eof = bytes.is_empty();
+                    // v1 clients wait for the reply, which holds them back
+                    // while the command does not read
+                    let sent = match &stdin_tx {
+                        Some(tx) => Some(tx.send(bytes).await),
+                        None => None,
+                    };
+                    if is_eof {
+                        stdin_tx = None;
+                    }
+                    if let Some(Ok(())) = sent {
+                        if let Some(audit) = &r.audit {
+                            audit.stdin(len);
                         }
                         reply(Message {
                             message_type: SSH_AGENT_SUCCESS,
@@ -245,21 +1388,41 @@ impl RevExt {
                         reply(Message::extension_failure())?;
                     }
                 }
-                Request::Watch => {
-                    let watch_fut = Self::watch(&mut r.stdout, &mut r.stderr, &mut r.child).boxed();
+                Request::Signal(signal) => {
+                    if let Some(pid) = r.child.id() {
+                        deliver(pid, signal);
+                    }
+                    reply(Message::success())?;
+                }
+                Request::Resize(size) => {
+                    if let Some(pty) = &r.pty {
+                        resize(pty, size);
+                    }
+                    reply(Message::success())?;
+                }
+                Request::Watch(_) => {
+                    let watch_fut = r.watch(&self.context).boxed();
                     let peek_fut = self.requests.recv().boxed();
-                    match future::select(watch_fut, peek_fut).await {
-                        Either::Left((Ok(event), _)) => {
+                    let selected = match future::select(watch_fut, peek_fut).await {
+                        Either::Left((result, _)) => Either::Left(result),
+                        Either::Right((next_tuple, _)) => Either::Right(next_tuple),
+                    };
+                    match selected {
+                        Either::Left(Ok(event)) => {
+                            r.audit_event(&event);
+                            if event.is_last() {
+                                r.permit.take();
+                            }
                             reply(Message {
                                 message_type: SSH_AGENT_SUCCESS,
-                                contents: event.into_bytes(),
+                                contents: self.for_client(event).into_bytes(),
                             })?;
                         }
-                        Either::Left((Err(_err), _)) => {
-                            // TODO: logging
-                            reply(Message::extension_failure())?;
+                        Either::Left(Err(err)) => {
+                            log::warn!("Failed to watch command: {}", err);
+                            reply(failed(&err).into_message()?)?;
                         }
-                        Either::Right((next_tuple, _)) => {
+                        Either::Right(next_tuple) => {
                             peek_buf = next_tuple;
                             reply(Message {
                                 message_type: SSH_AGENT_SUCCESS,
@@ -276,33 +1439,544 @@ impl RevExt {
         Ok(())
     }
 
-    async fn exec(exec: &Exec) -> Result<(process::Child, ChildStdin, ChildStdout, ChildStderr)> {
-        let mut command = process::Command::new(&exec.cmd);
+    /// Starts the tasks of a [`Stream`] for what [`RevExt::start`] returned.
+    fn open_stream(&self, started: Started) -> Stream {
+        let (stdin_tx, stdin_rx) = mpsc::channel(STDIN_BACKLOG);
+        let (event_tx, events) = mpsc::channel(EVENT_BACKLOG);
+        // passed on only to clients that do flow control, but taken note of
+        // in case one resumes the command
+        let adjust = Some(event_tx.clone());
+        let (stdin, feed, pump) = match started {
+            Started::Running(mut r) => {
+                let stdin = r.stdin.take();
+                let feed = StdinFeed::new(
+                    Some(stdin_tx),
+                    Some(&r),
+                    self.stdin_window(),
+                    self.flow_control,
+                );
+                let pump = pump(*r, self.context.clone(), event_tx, |event| event);
+                (stdin, feed, tokio::spawn(pump))
+            }
+            Started::Attached(job) => {
+                let feed =
+                    StdinFeed::new(Some(stdin_tx), None, self.stdin_window(), self.flow_control);
+                let pump = job.follow(event_tx, |event| event);
+                (None, feed, tokio::spawn(pump))
+            }
+            Started::DryRun(_) => unreachable!("dry runs are served by handle_dry_run"),
+            Started::Resumed(_) => unreachable!("resumed commands have their stream"),
+        };
+        let writer = write_stdin(stdin, stdin_rx, adjust, |event| event);
+        Stream {
+            feed,
+            events,
+            pump,
+            writer: tokio::spawn(writer),
+            pump_done: false,
+            writer_done: false,
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            max_batch: self.context.max_batch_bytes(None),
+            compression: self.compression,
+            seq: 0,
+            unacked: vec![],
+        }
+    }
+
+    /// Serves `stream` until the connection is closed.
+    async fn handle_stream(&mut self, stream: &mut Stream) -> Result<()> {
+        let mut watch: Option<oneshot::Sender<Message>> = None;
+        let heartbeat = self.heartbeat_interval();
+        let idle_timeout = self.idle_timeout();
+        let mut watched_at = Instant::now();
+        let mut heard_at = Instant::now();
+        // a resumed stream may have been served to a different client
+        stream.compression = self.compression;
+        loop {
+            tokio::select! {
+                result = &mut stream.pump, if !stream.pump_done => {
+                    stream.pump_done = true;
+                    if let Err(err) = result.unwrap_or_else(|err| Err(err.into())) {
+                        if let Some(watch) = watch.take() {
+                            let _ = watch.send(failed(&err).into_message()?);
+                        }
+                        return Err(err);
+                    }
+                }
+                result = &mut stream.writer, if !stream.writer_done => {
+                    stream.writer_done = true;
+                    if let Err(err) = result.unwrap_or_else(|err| Err(err.into())) {
+                        log::debug!("Stopped writing to stdin: {}", err);
+                    }
+                }
+                Some(event) = stream.events.recv(), if stream.backlog_bytes < stream.max_batch => {
+                    if stream.feed.adjust(&event) {
+                        stream.backlog_bytes += event.data_len();
+                        stream.backlog.push_back(self.for_client(event));
+                    }
+                }
+                _ = time::sleep_until(watched_at + heartbeat.unwrap_or_default()),
+                    if heartbeat.is_some() && watch.is_some() => {
+                    // an empty batch tells the client that the connection
+                    // is still there
+                    if let Some(watch) = watch.take() {
+                        stream.send_batch(watch)?;
+                    }
+                }
+                _ = time::sleep_until(heard_at + idle_timeout.unwrap_or_default()),
+                    if idle_timeout.is_some() => {
+                    // dropping the stream kills the command, or parks it
+                    self.log_idle(idle_timeout.unwrap_or_default());
+                    return Ok(());
+                }
+                request = self.requests.recv() => {
+                    let Some((_, request, reply_tx)) = request else {
+                        return Ok(());
+                    };
+                    heard_at = Instant::now();
+                    let reply = match self.context.request(request) {
+                        Ok(Request::Watch(window)) => {
+                            // the client has what was sent before it asked
+                            stream.unacked.clear();
+                            stream.max_batch = self.context.max_batch_bytes(window);
+                            watched_at = heard_at;
+                            if let Some(previous) = watch.replace(reply_tx) {
+                                stream.send_batch(previous)?;
+                            }
+                            None
+                        }
+                        Ok(Request::Stdin(bytes)) => Some((stream.feed.stdin(bytes), reply_tx)),
+                        // the process group may be someone else's once reaped
+                        Ok(Request::Signal(_)) if stream.pump.is_finished() => {
+                            Some((Message::success(), reply_tx))
+                        }
+                        Ok(Request::Signal(signal)) => Some((stream.feed.signal(signal), reply_tx)),
+                        Ok(Request::Resize(size)) => Some((stream.feed.resize(size), reply_tx)),
+                        _ => Some((Message::extension_failure(), reply_tx)),
+                    };
+                    if let Some((reply, reply_tx)) = reply {
+                        // its reply has to wait for the pending watch, so let
+                        // that one go with whatever is there
+                        if let Some(watch) = watch.take() {
+                            stream.send_batch(watch)?;
+                        }
+                        reply_tx.send(reply).map_err(|_| anyhow!("failed to reply"))?;
+                    }
+                }
+            }
+            if !stream.backlog.is_empty() {
+                if let Some(watch) = watch.take() {
+                    stream.send_batch(watch)?;
+                }
+            }
+        }
+    }
+
+    /// Serves a connection that started a command with v3, on which more
+    /// commands may be started while earlier ones run. Each one is pumped
+    /// as in [`RevExt::handle_stream`], into a backlog shared by all.
+    async fn handle_mux(&mut self, session: SessionId, started: Started) -> Result<()> {
+        let mut max_batch = self.context.max_batch_bytes(None);
+        let (event_tx, mut event_rx) = mpsc::channel(EVENT_BACKLOG);
+        let mut sessions = HashMap::new();
+        let mut tasks = FuturesUnordered::new();
+        let mut starting = FuturesUnordered::<SessionStart>::new();
+        let (entry, task) = self.open_session(session, started, event_tx.clone());
+        sessions.insert(session, entry);
+        tasks.push(task);
+
+        let mut backlog = VecDeque::new();
+        let mut backlog_bytes = 0;
+        let mut seq = 0;
+        let mut watch: Option<oneshot::Sender<Message>> = None;
+        let heartbeat = self.heartbeat_interval();
+        let idle_timeout = self.idle_timeout();
+        let mut watched_at = Instant::now();
+        let mut heard_at = Instant::now();
+        let compression = self.compression;
+        loop {
+            tokio::select! {
+                Some(session) = tasks.next() => {
+                    sessions.remove(&session);
+                }
+                Some((session, reply_tx, result)) = starting.next() => {
+                    let message = match result {
+                        Ok((message, Some(started))) => {
+                            let (entry, task) =
+                                self.open_session(session, started, event_tx.clone());
+                            sessions.insert(session, entry);
+                            tasks.push(task);
+                            message
+                        }
+                        Ok((message, None)) => {
+                            sessions.remove(&session);
+                            message
+                        }
+                        Err(err) => {
+                            log::warn!("Failed to start session {}: {}", session, err);
+                            sessions.remove(&session);
+                            Message::extension_failure()
+                        }
+                    };
+                    reply_tx.send(message).map_err(|_| anyhow!("failed to reply"))?;
+                    // starting a session may have waited for a confirmation
+                    heard_at = Instant::now();
+                }
+                Some((session, event)) = event_rx.recv(), if backlog_bytes < max_batch => {
+                    let wanted = match sessions.get_mut(&session) {
+                        Some(entry) => entry.adjust(&event),
+                        None => self.flow_control || !matches!(event, Event::WindowAdjust(_)),
+                    };
+                    if wanted {
+                        backlog_bytes += event.data_len();
+                        backlog.push_back((session, self.for_client(event)));
+                    }
+                }
+                _ = time::sleep_until(watched_at + heartbeat.unwrap_or_default()),
+                    if heartbeat.is_some() && watch.is_some() => {
+                    if let Some(watch) = watch.take() {
+                        send_session_batch(
+                            watch,
+                            &mut backlog,
+                            &mut backlog_bytes,
+                            &mut seq,
+                            max_batch,
+                            compression,
+                        )?;
+                    }
+                }
+                _ = time::sleep_until(heard_at + idle_timeout.unwrap_or_default()),
+                    if idle_timeout.is_some() => {
+                    self.log_idle(idle_timeout.unwrap_or_default());
+                    return Ok(());
+                }
+                request = self.requests.recv() => {
+                    let Some((_, request, reply_tx)) = request else {
+                        return Ok(());
+                    };
+                    heard_at = Instant::now();
+                    match split_session(request) {
+                        Ok((_, request)) if is_watch(&request, &self.context) => {
+                            if let Ok(Request::Watch(window)) = self.context.request(request) {
+                                max_batch = self.context.max_batch_bytes(window);
+                            }
+                            watched_at = Instant::now();
+                            if let Some(previous) = watch.replace(reply_tx) {
+                                send_session_batch(
+                                    previous,
+                                    &mut backlog,
+                                    &mut backlog_bytes,
+                                    &mut seq,
+                                    max_batch,
+                                    compression,
+                                )?;
+                            }
+                        }
+                        split => {
+                            // its reply has to wait for the pending watch, so
+                            // let that one go with whatever is there
+                            if let Some(watch) = watch.take() {
+                                send_session_batch(
+                                    watch,
+                                    &mut backlog,
+                                    &mut backlog_bytes,
+                                    &mut seq,
+                                    max_batch,
+                                    compression,
+                                )?;
+                            }
+                            let Ok((session, request)) = split else {
+                                reply_tx
+                                    .send(Message::extension_failure())
+                                    .map_err(|_| anyhow!("failed to reply"))?;
+                                continue;
+                            };
+                            let handled = self
+                                .handle_session_request(session, request.clone(), &mut sessions)
+                                .await;
+                            match handled {
+                                Some(message) => {
+                                    reply_tx.send(message).map_err(|_| anyhow!("failed to reply"))?;
+                                }
+                                None => {
+                                    // stands in for it until it has started,
+                                    // so that it is not started twice
+                                    sessions.insert(session, StdinFeed::new(None, None, 0, false));
+                                    starting.push(self.start_session(session, request, reply_tx));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            if !backlog.is_empty() {
+                if let Some(watch) = watch.take() {
+                    send_session_batch(
+                        watch,
+                        &mut backlog,
+                        &mut backlog_bytes,
+                        &mut seq,
+                        max_batch,
+                        compression,
+                    )?;
+                }
+            }
+        }
+    }
+
+    /// Handles a v3 request other than `Watch`, or returns `None` for one
+    /// that starts a session, which is left to [`RevExt::start_session`].
+    async fn handle_session_request(
+        &mut self,
+        session: SessionId,
+        request: Bytes,
+        sessions: &mut HashMap<SessionId, StdinFeed>,
+    ) -> Option<Message> {
+        let message = match self.context.request(request.clone()) {
+            Ok(Request::Stdin(bytes)) => match sessions.get_mut(&session) {
+                Some(entry) => entry.stdin(bytes),
+                None => Message::extension_failure(),
+            },
+            Ok(Request::Signal(signal)) => match sessions.get(&session) {
+                Some(entry) => entry.signal(signal),
+                None => Message::success(),
+            },
+            Ok(Request::Resize(size)) => match sessions.get(&session) {
+                Some(entry) => entry.resize(size),
+                None => Message::success(),
+            },
+            _ if sessions.contains_key(&session) => Message::extension_failure(),
+            // these only take note of what the client said
+            Ok(Request::Hello(_) | Request::Pty(_)) => match self.start(request).await {
+                Ok((message, _)) => message,
+                Err(err) => {
+                    log::warn!("Failed to handle a request: {}", err);
+                    Message::extension_failure()
+                }
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    /// Starts a session while the others go on being served, since it may
+    /// wait for a confirmation, its turn, or a job to finish.
+    fn start_session(
+        &mut self,
+        session: SessionId,
+        request: Bytes,
+        reply_tx: oneshot::Sender<Message>,
+    ) -> SessionStart {
+        let mut starter = self.fork();
+        async move {
+            let result = starter.start(request).await;
+            (session, reply_tx, result)
+        }
+        .boxed()
+    }
+
+    /// A copy that starts the next command as this one would, and that is
+    /// handed no requests of its own.
+    fn fork(&mut self) -> RevExt {
+        RevExt {
+            requests: mpsc::channel(1).1,
+            context: self.context.clone(),
+            caller: self.caller.clone(),
+            version: self.version,
+            flow_control: self.flow_control,
+            resume: self.resume,
+            signaled: self.signaled,
+            keepalive: self.keepalive,
+            compression: self.compression,
+            terminal: self.terminal.take(),
+        }
+    }
+
+    /// Returns what the request loop of [`RevExt::handle_mux`] keeps of a
+    /// session, and the task that feeds its stdin and pumps its events.
+    fn open_session(
+        &self,
+        session: SessionId,
+        started: Started,
+        event_tx: mpsc::Sender<(SessionId, Event)>,
+    ) -> (StdinFeed, SessionTask) {
+        let tag = move |event| (session, event);
+        let failure_tx = event_tx.clone();
+        let (entry, task) = match started {
+            Started::Running(mut r) => {
+                let (stdin_tx, stdin_rx) = mpsc::channel(STDIN_BACKLOG);
+                let entry = StdinFeed::new(
+                    Some(stdin_tx),
+                    Some(&r),
+                    self.stdin_window(),
+                    self.flow_control,
+                );
+                let adjust = Some(event_tx.clone());
+                let writer = write_stdin(r.stdin.take(), stdin_rx, adjust, tag).then(|result| {
+                    if let Err(err) = result {
+                        log::debug!("Stopped writing to stdin: {}", err);
+                    }
+                    // the session is over when the command exits
+                    future::pending::<()>()
+                });
+                let pump = pump(*r, self.context.clone(), event_tx, tag);
+                let task = async move {
+                    tokio::pin!(pump, writer);
+                    match future::select(pump, writer).await {
+                        Either::Left((result, _)) => result,
+                        Either::Right(((), _)) => unreachable!(),
+                    }
+                };
+                (entry, task.boxed())
+            }
+            Started::DryRun(events) => {
+                let task = async move {
+                    for event in events {
+                        if event_tx.send(tag(event)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                };
+                let entry = StdinFeed::new(None, None, 0, false);
+                (entry, task.boxed())
+            }
+            Started::Attached(job) => {
+                let task = job.follow(event_tx, tag);
+                let entry = StdinFeed::new(None, None, 0, false);
+                (entry, task.boxed())
+            }
+            Started::Resumed(_) => unreachable!("only v2 commands are resumed"),
+        };
+        // the client learns of a failure as the end of the session
+        let task = async move {
+            if let Err(err) = task.await {
+                log::warn!("Lost session {}: {}", session, err);
+                let rejection = failed(&err);
+                let stderr = format!("ssh-rev: {}\n", rejection);
+                let events = [
+                    Event::Stderr(stderr.into()),
+                    Event::Exited(rejection.exit_code()),
+                ];
+                for event in events {
+                    let _ = failure_tx.send(tag(event)).await;
+                }
+            }
+            session
+        };
+        (entry, task.boxed())
+    }
+
+    /// Starts `exec`, on a pseudo-terminal if the client asked for one.
+    async fn exec(
+        &self,
+        exec: &Exec,
+        program: Option<&Path>,
+        terminal: Option<&Terminal>,
+        caller: &Caller,
+    ) -> Result<Spawned, Rejection> {
+        let mut command = match program {
+            Some(program) => process::Command::new(program),
+            None => process::Command::new(&exec.cmd),
+        };
         command.args(&exec.args);
+        self.context.env.prepare(&mut command);
+        self.context.limits.apply(&mut command);
         command.envs(exec.envs.iter());
-        command.stdout(Stdio::piped());
-        command.stderr(Stdio::piped());
-        command.stdin(Stdio::piped());
+        if let Some(host) = caller.verified_remote_host() {
+            command.env("SSH_REV_REMOTE_HOST_KEY", &host.fingerprint);
+            if let Some(name) = host.hosts.first() {
+                command.env("SSH_REV_REMOTE_HOST", name);
+            }
+        }
         command.kill_on_drop(true);
+        let pty = match terminal {
+            // the session it leads is its process group as well
+            Some(terminal) => {
+                let pty = Pty::open(terminal.size)
+                    .and_then(|pty| pty.attach(&mut command).map(|()| pty))
+                    .map_err(|err| {
+                        os_error(RejectionKind::Spawn, "cannot open a pty".into(), &err)
+                    })?;
+                Some(pty)
+            }
+            None => {
+                command.stdout(Stdio::piped());
+                command.stderr(Stdio::piped());
+                command.stdin(Stdio::piped());
+                // in a process group of its own, so that signals passed on
+                // reach whatever it starts but not the agent
+                // SAFETY: setpgid is async-signal-safe.
+                unsafe {
+                    command.pre_exec(|| {
+                        if libc::setpgid(0, 0) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+                None
+            }
+        };
         if let Some(cwd) = exec.cwd.as_deref() {
+            // spawn would fail alike, but without saying that cwd is to blame
+            let is_dir = fs::metadata(cwd).await.and_then(|metadata| {
+                if metadata.is_dir() {
+                    Ok(())
+                } else {
+                    Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+                }
+            });
+            if let Err(err) = is_dir {
+                let message = format!("cannot change to `{}`", cwd.display());
+                return Err(os_error(RejectionKind::Cwd, message, &err));
+            }
             command.current_dir(cwd);
         }
-        let mut child = command.spawn()?;
-        let stdin = child.stdin.take().unwrap();
-        let stdout = child.stdout.take().unwrap();
-        let stderr = child.stderr.take().unwrap();
-        Ok((child, stdin, stdout, stderr))
+        let mut child = command.spawn().map_err(|err| {
+            let message = format!("cannot run `{}`", exec.cmd.to_string_lossy());
+            os_error(RejectionKind::Spawn, message, &err)
+        })?;
+        drop(command);
+        let Some(pty) = pty else {
+            return Ok(Spawned {
+                stdin: Box::new(child.stdin.take().unwrap()),
+                stdout: Box::new(child.stdout.take().unwrap()),
+                stderr: Some(Box::new(child.stderr.take().unwrap())),
+                pty: None,
+                child,
+            });
+        };
+        let master = pty.into_master().map_err(|err| {
+            let _ = child.start_kill();
+            os_error(RejectionKind::Spawn, "cannot open a pty".into(), &err)
+        })?;
+        Ok(Spawned {
+            child,
+            stdin: Box::new(master.clone()),
+            stdout: Box::new(master.clone()),
+            stderr: None,
+            pty: Some(master),
+        })
     }
 
     async fn watch(
-        stdout_opt: &mut Option<ChildStdout>,
-        stderr_opt: &mut Option<ChildStderr>,
+        stdout_opt: &mut Option<Output>,
+        stderr_opt: &mut Option<Output>,
         child: &mut Child,
+        max_chunk: usize,
     ) -> Result<Event> {
         let exited_fut = async {
             let exit_status = child.wait().await?;
-            let code = exit_status.code().unwrap_or_default();
-            anyhow::Ok(Event::Exited(code))
+            let event = match exit_status.signal() {
+                Some(signal) => Event::Signaled {
+                    signal,
+                    core_dumped: exit_status.core_dumped(),
+                },
+                None => Event::Exited(exit_status.code().unwrap_or_default()),
+            };
+            anyhow::Ok(event)
         }
         .boxed();
 
@@ -311,10 +1985,13 @@ impl RevExt {
         }
 
         let stdout_fut = async {
-            let mut buf = BytesMut::with_capacity(4096); // FIXME: magic number
+            let mut buf = BytesMut::with_capacity(max_chunk);
             if let Some(stdout) = stdout_opt {
                 log::trace!("Reading stdout");
-                stdout.read_buf(&mut buf).await?;
+                (&mut *stdout)
+                    .take(max_chunk as u64)
+                    .read_buf(&mut buf)
+                    .await?;
                 log::trace!("Read from stdout: {:?}", &buf);
                 if buf.is_empty() {
                     log::trace!("stdout was reached to EOS");
@@ -328,10 +2005,13 @@ impl RevExt {
         }
         .boxed();
         let stderr_fut = async {
-            let mut buf = BytesMut::with_capacity(4096); // FIXME: magic number
+            let mut buf = BytesMut::with_capacity(max_chunk);
             if let Some(stderr) = stderr_opt {
                 log::trace!("Reading stderr");
-                stderr.read_buf(&mut buf).await?;
+                (&mut *stderr)
+                    .take(max_chunk as u64)
+                    .read_buf(&mut buf)
+                    .await?;
                 log::trace!("Read from stderr: {:?}", &buf);
                 if buf.is_empty() {
                     log::trace!("stderr was reached to EOS");
@@ -351,6 +2031,213 @@ impl RevExt {
     }
 }
 
+/// Like [`Stream::send_batch`], but for v3.
+fn send_session_batch(
+    watch: oneshot::Sender<Message>,
+    backlog: &mut VecDeque<(SessionId, Event)>,
+    backlog_bytes: &mut usize,
+    seq: &mut u64,
+    max_bytes: usize,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let batch = SessionEventBatch {
+        seq: *seq,
+        events: take_batch(backlog, backlog_bytes, max_bytes, |(_, event)| {
+            event.data_len()
+        }),
+    };
+    *seq += batch.events.len() as u64;
+    watch
+        .send(Message {
+            message_type: SSH_AGENT_SUCCESS,
+            contents: batch.into_compressed_bytes(compression),
+        })
+        .map_err(|_| anyhow!("failed to reply"))
+}
+
+fn take_batch<T>(
+    backlog: &mut VecDeque<T>,
+    backlog_bytes: &mut usize,
+    max_bytes: usize,
+    size_of: impl Fn(&T) -> usize,
+) -> Vec<T> {
+    let mut batch = vec![];
+    let mut batch_bytes = 0;
+    while let Some(event) = backlog.front() {
+        let size = size_of(event);
+        if !batch.is_empty() && batch_bytes + size + EVENT_OVERHEAD > max_bytes {
+            break;
+        }
+        batch_bytes += size + EVENT_OVERHEAD;
+        *backlog_bytes -= size;
+        batch.extend(backlog.pop_front());
+    }
+    batch
+}
+
+/// Reads events of `r` until it exits, and sends them on as `tag(event)`.
+async fn pump<T>(
+    mut r: Running,
+    context: Arc<Context>,
+    events: mpsc::Sender<T>,
+    tag: impl Fn(Event) -> T,
+) -> Result<()> {
+    loop {
+        let event = r.watch(&context).await?;
+        r.audit_event(&event);
+        let is_last = event.is_last();
+        if is_last {
+            r.permit.take();
+        }
+        // the end of stdout and stderr is implied by the exit
+        if event.data_len() == 0 && !is_last {
+            continue;
+        }
+        if events.send(tag(event)).await.is_err() || is_last {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs a detached command until it exits, keeping its events in `job`
+/// for those who attach.
+async fn run_job(mut r: Running, context: Arc<Context>, job: Arc<Job>) {
+    loop {
+        let event = tokio::select! {
+            event = r.watch(&context) => event,
+            () = job.killed() => {
+                // the id is gone once the command was reaped
+                if let Some(pid) = r.child.id() {
+                    kill_group(pid, libc::SIGTERM);
+                }
+                continue;
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                log::warn!("Lost job {}: {}", job.id, err);
+                if let Some(pid) = r.child.id() {
+                    kill_group(pid, libc::SIGKILL);
+                }
+                let _ = r.child.kill().await;
+                // as ssh does when it fails
+                Event::Exited(255)
+            }
+        };
+        r.audit_event(&event);
+        let is_last = event.is_last();
+        if is_last {
+            r.permit.take();
+        }
+        // the end of stdout and stderr is implied by the exit
+        if event.data_len() == 0 && !is_last {
+            continue;
+        }
+        job.push(event);
+        if is_last {
+            log::info!("Job {} is over", job.id);
+            return;
+        }
+    }
+}
+
+fn resize(pty: &PtyMaster, size: WindowSize) {
+    if let Err(err) = pty.resize(size) {
+        log::debug!("Failed to resize the terminal: {}", err);
+    }
+}
+
+/// Sends `signal` to the process group that `pid` leads.
+fn deliver(pid: u32, signal: Signal) {
+    log::debug!("Passing on {:?} to process group {}", signal, pid);
+    if unsafe { libc::kill(-(pid as libc::pid_t), signal.number()) } != 0 {
+        log::debug!(
+            "Failed to pass on {:?}: {}",
+            signal,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Like [`deliver`], for the signals the agent sends on its own.
+fn kill_group(pid: u32, signal: i32) {
+    if unsafe { libc::kill(-(pid as libc::pid_t), signal) } != 0 {
+        log::warn!(
+            "Failed to kill process group {}: {}",
+            pid,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Tells the client that `message` failed for `err`.
+fn os_error(kind: RejectionKind, message: String, err: &io::Error) -> Rejection {
+    Rejection {
+        errno: err.raw_os_error(),
+        ..Rejection::new(kind, format!("{}: {}", message, err))
+    }
+}
+
+/// Tells the client that serving the command failed for `err`.
+fn failed(err: &anyhow::Error) -> Rejection {
+    Rejection {
+        errno: err
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error),
+        ..Rejection::new(
+            RejectionKind::Failed,
+            format!("the agent lost the command: {}", err),
+        )
+    }
+}
+
+/// A random token that only the client that got it can resume with.
+fn resume_token() -> Result<String> {
+    let mut token = [0u8; 16];
+    getrandom::getrandom(&mut token)?;
+    Ok(token.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// A successful reply that carries `value` as JSON.
+fn json_reply(value: &impl serde::Serialize) -> Result<Message> {
+    Ok(Message {
+        message_type: SSH_AGENT_SUCCESS,
+        contents: serde_json::to_vec(value)?.into(),
+    })
+}
+
+fn is_watch(request: &Bytes, context: &Context) -> bool {
+    matches!(context.request(request.clone()), Ok(Request::Watch(_)))
+}
+
+/// Writes `chunks` to `stdin` until an empty one closes it. With `adjust`,
+/// each chunk written is reported as an [`Event::WindowAdjust`], tagged as
+/// `tag(event)`.
+async fn write_stdin<T>(
+    stdin: Option<Input>,
+    mut chunks: mpsc::Receiver<Bytes>,
+    adjust: Option<mpsc::Sender<T>>,
+    tag: impl Fn(Event) -> T,
+) -> Result<()> {
+    let Some(mut stdin) = stdin else {
+        return Ok(());
+    };
+    while let Some(chunk) = chunks.recv().await {
+        if chunk.is_empty() {
+            stdin.shutdown().await?;
+            break;
+        }
+        stdin.write_all(&chunk).await?;
+        if let Some(adjust) = &adjust {
+            let _ = adjust
+                .send(tag(Event::WindowAdjust(chunk.len() as u32)))
+                .await;
+        }
+    }
+    Ok(())
+}
+
 struct UpstreamAgent {
     read: FramedRead<OwnedReadHalf, ssh_agent::Codec>,
     write: FramedWrite<OwnedWriteHalf, ssh_agent::Codec>,
@@ -360,8 +2247,16 @@ impl UpstreamAgent {
     async fn open(path: &Path) -> Result<Self> {
         let upstream = UnixStream::connect(path).await?;
         let (r, w) = upstream.into_split();
-        let read = FramedRead::new(r, ssh_agent::Codec);
-        let write = FramedWrite::new(w, ssh_agent::Codec);
+        let read = FramedRead::new(r, ssh_agent::Codec::default());
+        let write = FramedWrite::new(w, ssh_agent::Codec::default());
         Ok(UpstreamAgent { read, write })
     }
+
+    async fn request(&mut self, request: &Message) -> Result<Message> {
+        self.write.send(request).await?;
+        self.read
+            .try_next()
+            .await?
+            .ok_or_else(|| anyhow!("upstream agent has gone"))
+    }
 }
@@ -1,141 +1,1753 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::IsTerminal,
+    path::Path,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bytes::{Bytes, BytesMut};
 use futures::{
     future::{self, Either},
     FutureExt, SinkExt, TryStreamExt,
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, Stderr, Stdin, Stdout},
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, Stderr, Stdin, Stdout},
     net::{
         unix::{OwnedReadHalf, OwnedWriteHalf},
         UnixStream,
     },
-    sync::Mutex,
+    runtime::Handle,
+    signal::unix::{self, SignalKind},
+    sync::{mpsc, oneshot, Mutex, Semaphore},
+    time,
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use crate::{
-    rpc::{build_request_message, Event, Exec, Request},
-    ssh_agent::{self, SSH_AGENT_EXTENSION_FAILURE, SSH_AGENT_FAILURE, SSH_AGENT_SUCCESS},
+    pairing::{ClientPairing, PendingPairing},
+    pty::{self, RawMode},
+    rpc::{
+        build_message, build_request_message, build_session_message, Capabilities, ClientHello,
+        Compression, Event, EventBatch, Exec, ExecReply, JobId, JobStatus, OpCode, Rejection,
+        Request, Resume, Resumed, SessionEventBatch, SessionId, Signal, Terminal, VerbCall,
+        Version, WindowSize,
+    },
+    ssh_agent::{
+        self, chunks, max_chunk, MAX_FRAME_BYTES, SSH_AGENT_EXTENSION_FAILURE, SSH_AGENT_FAILURE,
+        SSH_AGENT_SUCCESS,
+    },
 };
 
 pub struct RevExec {
     outgoing: Outgoing,
     incoming: Incoming,
+    pairing: Option<ClientPairing>,
+    /// Set if the agent keeps the command running for a while when the
+    /// connection is lost.
+    resume_token: Option<String>,
+    /// The number of the first v2 event to come.
+    next_seq: u64,
+    /// Whether to ask for the command to run on a terminal.
+    tty: bool,
+    /// Set once the command runs on a terminal.
+    pty: bool,
 }
 
 impl RevExec {
     pub async fn open(ssh_auth_sock: &Path) -> Result<Self> {
         let (r, w) = UnixStream::connect(ssh_auth_sock).await?.into_split();
-        let incoming = Incoming(FramedRead::new(r, ssh_agent::Codec));
-        let outgoing = Outgoing(FramedWrite::new(w, ssh_agent::Codec));
-        Ok(Self { outgoing, incoming })
+        let incoming = Incoming(FramedRead::new(r, ssh_agent::Codec::default()));
+        let outgoing = Outgoing {
+            framed: FramedWrite::new(w, ssh_agent::Codec::default()),
+            version: Version::V1,
+            binary_exec: false,
+            max_chunk: max_chunk(MAX_FRAME_BYTES),
+            stdin_window: None,
+            signals: false,
+            keepalive: None,
+            compression: Some(Compression::Auto),
+        };
+        Ok(Self {
+            outgoing,
+            incoming,
+            pairing: None,
+            resume_token: None,
+            next_seq: 0,
+            tty: false,
+            pty: false,
+        })
+    }
+
+    /// Authenticates subsequent requests with `pairing`.
+    pub fn with_pairing(mut self, pairing: Option<ClientPairing>) -> Self {
+        self.pairing = pairing;
+        self
+    }
+
+    /// Runs commands on a pseudo-terminal of the agent, as `ssh -t` does,
+    /// if the agent can. The local terminal is then in raw mode until the
+    /// command exits.
+    pub fn with_tty(mut self, tty: bool) -> Self {
+        self.tty = tty;
+        self
+    }
+
+    /// Deflates stdin and output as `compression` says, if the agent can.
+    /// By default only bulk transfers are.
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.outgoing.compression = compression;
+        self
+    }
+
+    pub async fn pair(mut self, code: &str) -> Result<ClientPairing> {
+        self.handshake(Version::V2).await?;
+        let (pending, pair) = PendingPairing::new(code)?;
+        let request = build_request_message(self.outgoing.version, Request::Pair(pair))?;
+        self.outgoing.framed.send(&request).await?;
+        let reply = self
+            .incoming
+            .recv_reply()
+            .await
+            .context("recv pair reply")?;
+        pending.finish(serde_json::from_slice(&reply)?)
     }
 
     pub async fn exec(
-        mut self,
+        self,
         exec: Exec,
-        mut stdin: Stdin,
-        mut stdout: Stdout,
+        stdin: Stdin,
+        stdout: Stdout,
+        stderr: Stderr,
+    ) -> Result<i32> {
+        self.run(Request::Exec(exec), stdin, stdout, stderr).await
+    }
+
+    /// Like [`RevExec::exec`], but runs whatever the agent registered as
+    /// `call.verb`.
+    pub async fn verb(
+        self,
+        call: VerbCall,
+        stdin: Stdin,
+        stdout: Stdout,
+        stderr: Stderr,
+    ) -> Result<i32> {
+        self.run(Request::Verb(call), stdin, stdout, stderr).await
+    }
+
+    /// Takes over a command whose connection was lost, with the token that
+    /// was printed for it, and carries on where that connection left off.
+    pub async fn resume(
+        mut self,
+        token: &str,
+        stdin: Stdin,
+        stdout: Stdout,
+        stderr: Stderr,
+    ) -> Result<i32> {
+        let resumed = self.send_resume(token).await?;
+        self.stream(resumed.stdin.then_some(stdin), true, stdout, stderr)
+            .await
+    }
+
+    async fn send_resume(&mut self, token: &str) -> Result<Resumed> {
+        // the token printed once the connection is lost tells what was seen
+        let (token, seq) = match token.split_once(':') {
+            Some((token, seq)) => (token, Some(seq.parse().context("malformed resume token")?)),
+            None => (token, None),
+        };
+        let capabilities = self.handshake(Version::V2).await?;
+        if !capabilities
+            .is_some_and(|capabilities| capabilities.opcodes.contains(&(OpCode::Resume as u8)))
+        {
+            bail!("the agent is too old to resume commands");
+        }
+        let resume = Resume {
+            token: token.into(),
+            seq,
+        };
+        self.outgoing
+            .start(Request::Resume(resume), self.pairing.as_ref())
+            .await
+            .context("send resume req")?;
+        let reply = self
+            .incoming
+            .recv_reply()
+            .await
+            .context("recv resume reply")?;
+        let resumed: Resumed = serde_json::from_slice(&reply)?;
+        self.resume_token = Some(token.into());
+        self.next_seq = resumed.seq;
+        self.pty = resumed.pty;
+        Ok(resumed)
+    }
+
+    /// Starts `exec` as a job that keeps running after the connection is
+    /// closed.
+    pub async fn detach(self, exec: Exec) -> Result<JobStatus> {
+        self.detach_request(Request::Exec(exec)).await
+    }
+
+    /// Like [`RevExec::detach`], but runs whatever the agent registered as
+    /// `call.verb`.
+    pub async fn detach_verb(self, call: VerbCall) -> Result<JobStatus> {
+        self.detach_request(Request::Verb(call)).await
+    }
+
+    async fn detach_request(mut self, request: Request) -> Result<JobStatus> {
+        // agents that know `Detach` all take the binary form of `Exec`
+        let request = Request::Detach(request.into_bytes()?);
+        let reply = self.job_request(OpCode::Detach, request).await?;
+        Ok(serde_json::from_slice(&reply)?)
+    }
+
+    /// The detached jobs that were started from here.
+    pub async fn jobs(mut self) -> Result<Vec<JobStatus>> {
+        let reply = self.job_request(OpCode::Jobs, Request::Jobs).await?;
+        Ok(serde_json::from_slice(&reply)?)
+    }
+
+    /// Writes out the output of the job `id`, from the oldest the agent
+    /// kept, until it exits, and returns its exit code.
+    pub async fn attach(
+        mut self,
+        id: JobId,
+        stdout: impl AsyncWrite + Unpin + Send,
+        stderr: impl AsyncWrite + Unpin + Send,
+    ) -> Result<i32> {
+        self.job_request(OpCode::Attach, Request::Attach(id))
+            .await?;
+        self.stream(None, false, stdout, stderr).await
+    }
+
+    /// Waits for the job `id` to exit; the agent forgets it then.
+    pub async fn wait(mut self, id: JobId) -> Result<JobStatus> {
+        let reply = self.job_request(OpCode::Wait, Request::Wait(id)).await?;
+        Ok(serde_json::from_slice(&reply)?)
+    }
+
+    pub async fn kill(mut self, id: JobId) -> Result<JobStatus> {
+        let reply = self.job_request(OpCode::Kill, Request::Kill(id)).await?;
+        Ok(serde_json::from_slice(&reply)?)
+    }
+
+    async fn run(
+        mut self,
+        request: Request,
+        stdin: Stdin,
+        stdout: Stdout,
         mut stderr: Stderr,
     ) -> Result<i32> {
-        self.outgoing.exec(exec).await.context("send exec req")?;
-        self.incoming.recv().await.context("recv exec reply")?;
-        self.outgoing.watch().await.context("first watch req")?;
+        self.start(request).await?;
+        if let Some(token) = &self.resume_token {
+            if std::io::stderr().is_terminal() {
+                let hint = format!(
+                    "ssh-rev: if the connection is lost, resume with `ssh-rev exec --resume {}`\n",
+                    token
+                );
+                stderr.write_all(hint.as_bytes()).await?;
+            }
+        }
+        self.stream(Some(stdin), true, stdout, stderr).await
+    }
+
+    /// Passes on stdin, if any, and the output of the command that was
+    /// started until it exits. With `signals`, the signals that would end
+    /// or stop the client are passed on to the command instead. A command
+    /// on a terminal has the local one in raw mode, and follows its size.
+    async fn stream(
+        mut self,
+        stdin: Option<Stdin>,
+        signals: bool,
+        stdout: impl AsyncWrite + Unpin + Send,
+        stderr: impl AsyncWrite + Unpin + Send,
+    ) -> Result<i32> {
+        let window = OutputWindow::new(self.outgoing.max_chunk);
+        self.outgoing
+            .watch(window.bytes())
+            .await
+            .context("first watch req")?;
 
+        let version = self.outgoing.version;
+        let credit = self.outgoing.stdin_credit();
+        let signals = signals && self.outgoing.signals;
+        // keys are only typed into a terminal that takes stdin
+        let raw_mode = match (self.pty, &stdin) {
+            (true, Some(_)) => RawMode::enter(libc::STDIN_FILENO)?,
+            _ => None,
+        };
         let outgoing = Arc::new(Mutex::new(self.outgoing));
-        let outgoing2 = outgoing.clone();
-
-        let incoming_loop_fut = async move {
-            loop {
-                let Some(event) = self.incoming.recv().await? else {
-                    continue;
-                };
-                match event {
-                    Event::Cancelled => {
-                        outgoing.lock().await.watch().await?;
-                    }
-                    Event::Stdout(bytes) => {
-                        stdout.write_all(&bytes).await?;
-                        outgoing.lock().await.watch().await?;
+        let incoming_loop_fut = match version {
+            Version::V1 => {
+                Self::incoming_loop_v1(self.incoming, outgoing.clone(), stdout, stderr).boxed()
+            }
+            Version::V2 => Self::incoming_loop_v2(
+                self.incoming,
+                outgoing.clone(),
+                window,
+                credit.clone(),
+                (self.next_seq, self.resume_token),
+                stdout,
+                stderr,
+            )
+            .boxed(),
+            Version::V3 => unreachable!("single commands are run with v2 at most"),
+        };
+        let signal_loop_fut = match signals {
+            true => Self::signal_loop(outgoing.clone(), raw_mode.as_ref()).boxed(),
+            false => future::ok(()).boxed(),
+        };
+        let resize_loop_fut = match self.pty {
+            true => Self::resize_loop(outgoing.clone()).boxed(),
+            false => future::ok(()).boxed(),
+        };
+        let stdin_loop_fut = match stdin {
+            Some(stdin) => Self::stdin_loop(outgoing, stdin, credit).boxed(),
+            None => future::ok(()).boxed(),
+        };
+        let stdin_loop_fut = future::try_join3(stdin_loop_fut, signal_loop_fut, resize_loop_fut);
+
+        // the loops borrow `raw_mode`, so they are done with before it is
+        let exit_code = match future::try_select(incoming_loop_fut, stdin_loop_fut).await {
+            Ok(Either::Left((exit_code, _))) => exit_code,
+            Ok(Either::Right((_, incoming_loop_fut))) => incoming_loop_fut.await?,
+            Err(either) => return Err(either.factor_first().0),
+        };
+        Ok(exit_code)
+    }
+
+    /// Turns the connection into one that runs several commands at once.
+    pub async fn into_mux(mut self) -> Result<Mux> {
+        self.handshake(Version::V3).await?;
+        if self.outgoing.version < Version::V3 {
+            bail!("the agent is too old to run several commands over one connection");
+        }
+        let shared = Arc::new(MuxShared {
+            framed: Mutex::new(self.outgoing.framed),
+            binary_exec: self.outgoing.binary_exec,
+            max_chunk: self.outgoing.max_chunk,
+            stdin_window: self.outgoing.stdin_window,
+            compression: self.outgoing.compression,
+            state: std::sync::Mutex::new(MuxState::new(self.outgoing.max_chunk)),
+            pairing: self.pairing,
+        });
+        tokio::spawn(Mux::read_loop(Arc::downgrade(&shared), self.incoming));
+        Ok(Mux(shared))
+    }
+
+    /// Picks the latest protocol version up to `max` that the agent speaks,
+    /// and fails early if it is not an ssh-rev agent at all. Returns `None`
+    /// for agents from before `Hello`.
+    async fn handshake(&mut self, max: Version) -> Result<Option<Capabilities>> {
+        // v2 and v1 frame `Hello` alike, so it is never sent as v3
+        for version in [Version::V2, Version::V1] {
+            if version > max {
+                continue;
+            }
+            self.outgoing.version = version;
+            self.outgoing.hello().await.context("send hello req")?;
+            let reply = self
+                .incoming
+                .recv_message()
+                .await
+                .context("recv hello reply")?;
+            match reply.message_type {
+                SSH_AGENT_SUCCESS => {
+                    let capabilities: Capabilities = serde_json::from_slice(&reply.contents)?;
+                    log::debug!("Agent capabilities: {:?}", capabilities);
+                    self.outgoing.version = capabilities
+                        .version(max)
+                        .ok_or_else(|| anyhow!("the agent speaks no known protocol version"))?;
+                    self.outgoing.binary_exec = capabilities.binary_exec;
+                    self.outgoing.max_chunk = max_chunk(capabilities.max_frame_bytes);
+                    self.outgoing.stdin_window = capabilities.stdin_window;
+                    self.outgoing.signals = capabilities.signals;
+                    self.outgoing.keepalive = capabilities.keepalive_secs.map(Duration::from_secs);
+                    if !capabilities.compression {
+                        self.outgoing.compression = None;
                     }
-                    Event::Stderr(bytes) => {
-                        stderr.write_all(&bytes).await?;
-                        outgoing.lock().await.watch().await?;
+                    return Ok(Some(capabilities));
+                }
+                // the extension is known, but the opcode is not
+                SSH_AGENT_EXTENSION_FAILURE => {
+                    self.outgoing.compression = None;
+                    return Ok(None);
+                }
+                _ => log::debug!("Agent does not know {:?}", version),
+            }
+        }
+        Err(anyhow!(
+            "the forwarded agent is not an ssh-rev agent; \
+             is `ssh-rev agent` running on the local machine, and is its socket forwarded?"
+        ))
+    }
+
+    /// Sends the request that starts the command.
+    async fn start(&mut self, request: Request) -> Result<()> {
+        let capabilities = self.handshake(Version::V2).await?;
+        if self.tty
+            && self.outgoing.version >= Version::V2
+            && capabilities.is_some_and(|capabilities| capabilities.pty)
+        {
+            let terminal = Terminal {
+                term: std::env::var("TERM").ok(),
+                size: pty::window_size().unwrap_or(DEFAULT_WINDOW_SIZE),
+            };
+            self.outgoing
+                .send(Request::Pty(terminal))
+                .await
+                .context("send pty req")?;
+            self.incoming.recv_reply().await.context("recv pty reply")?;
+            self.pty = true;
+        }
+        self.outgoing
+            .start(request, self.pairing.as_ref())
+            .await
+            .context("send exec req")?;
+        let reply = self
+            .incoming
+            .recv_reply()
+            .await
+            .context("recv exec reply")?;
+        // older agents and other versions reply with nothing
+        if !reply.is_empty() {
+            let reply: ExecReply = serde_json::from_slice(&reply)?;
+            self.resume_token = reply.resume_token;
+        }
+        Ok(())
+    }
+
+    /// Sends a request that deals with detached jobs, and returns the reply
+    /// to it.
+    async fn job_request(&mut self, opcode: OpCode, request: Request) -> Result<Bytes> {
+        let capabilities = self.handshake(Version::V2).await?;
+        if !capabilities.is_some_and(|capabilities| capabilities.opcodes.contains(&(opcode as u8)))
+        {
+            bail!("the agent is too old to run detached jobs");
+        }
+        self.outgoing
+            .start(request, self.pairing.as_ref())
+            .await
+            .context("send job req")?;
+        self.incoming.recv_reply().await.context("recv job reply")
+    }
+
+    async fn incoming_loop_v1(
+        mut incoming: Incoming,
+        outgoing: Arc<Mutex<Outgoing>>,
+        mut stdout: impl AsyncWrite + Unpin,
+        mut stderr: impl AsyncWrite + Unpin,
+    ) -> Result<i32> {
+        loop {
+            let Some(event) = incoming.recv().await? else {
+                continue;
+            };
+            if let Some(exit_code) = output(event, &mut stdout, &mut stderr).await? {
+                return Ok(exit_code);
+            }
+            outgoing.lock().await.watch(None).await?;
+        }
+    }
+
+    /// Serves v2 output from event `next_seq` on. If the connection is lost
+    /// and there is a `resume_token`, tells how to resume the command.
+    async fn incoming_loop_v2(
+        mut incoming: Incoming,
+        outgoing: Arc<Mutex<Outgoing>>,
+        mut window: OutputWindow,
+        credit: Option<Arc<Semaphore>>,
+        (mut next_seq, resume_token): (u64, Option<String>),
+        mut stdout: impl AsyncWrite + Unpin,
+        mut stderr: impl AsyncWrite + Unpin,
+    ) -> Result<i32> {
+        let dead_after = outgoing.lock().await.dead_after();
+        let mut watched_at = Instant::now();
+        loop {
+            let message = match incoming.recv_within(dead_after).await {
+                Ok(message) => message,
+                Err(err) => {
+                    if let Some(token) = &resume_token {
+                        let hint = format!(
+                            "ssh-rev: lost the connection; the command keeps running for a while, \
+                             resume it with `ssh-rev exec --resume {}:{}`\n",
+                            token, next_seq
+                        );
+                        stderr.write_all(hint.as_bytes()).await?;
                     }
-                    Event::Exited(code) => return anyhow::Ok(code),
+                    return Err(err);
+                }
+            };
+            let reply = into_reply(message)?;
+            if reply.is_empty() {
+                continue; // reply to stdin, a signal or a resize
+            }
+            let round_trip = watched_at.elapsed();
+            let batch = EventBatch::try_from(reply)?;
+            if batch.seq != next_seq {
+                return Err(anyhow!("expected event {} but got {}", next_seq, batch.seq));
+            }
+            next_seq += batch.events.len() as u64;
+            let received = batch.events.iter().map(Event::data_len).sum();
+            let written_at = Instant::now();
+            for event in batch.events {
+                if let (Event::WindowAdjust(bytes), Some(credit)) = (&event, &credit) {
+                    credit.add_permits(*bytes as usize);
+                }
+                if let Some(exit_code) = output(event, &mut stdout, &mut stderr).await? {
+                    return Ok(exit_code);
                 }
             }
+            // a terminal that takes longer than the agent is the bottleneck
+            window.update(received, written_at.elapsed() <= round_trip);
+            watched_at = Instant::now();
+            outgoing.lock().await.watch(window.bytes()).await?;
+        }
+    }
+
+    async fn stdin_loop(
+        outgoing: Arc<Mutex<Outgoing>>,
+        mut stdin: Stdin,
+        credit: Option<Arc<Semaphore>>,
+    ) -> Result<()> {
+        let chunk_size = outgoing.lock().await.stdin_chunk_size();
+        loop {
+            let mut buf = BytesMut::with_capacity(chunk_size);
+            (&mut stdin)
+                .take(chunk_size as u64)
+                .read_buf(&mut buf)
+                .await?;
+            let is_eof = buf.is_empty();
+            if let Some(credit) = &credit {
+                // wait for the command to read enough of what was sent
+                credit.acquire_many(buf.len() as u32).await?.forget();
+            }
+            let mut outgoing = outgoing.lock().await;
+            outgoing.stdin(buf.freeze()).await?;
+            if is_eof {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the agent the new size of the local terminal whenever it is
+    /// resized.
+    async fn resize_loop(outgoing: Arc<Mutex<Outgoing>>) -> Result<()> {
+        let mut resized = unix::signal(SignalKind::window_change())?;
+        while resized.recv().await.is_some() {
+            let Some(size) = pty::window_size() else {
+                continue;
+            };
+            // a lost connection is for the incoming loop to tell about
+            if let Err(err) = outgoing.lock().await.send(Request::Resize(size)).await {
+                log::debug!("Failed to resize the terminal: {}", err);
+            }
         }
-        .boxed();
-        let stdin_loop_fut = async move {
-            loop {
-                let mut buf = BytesMut::with_capacity(256);
-                stdin.read_buf(&mut buf).await?;
-                let is_eof = buf.is_empty();
-                let mut outgoing = outgoing2.lock().await;
-                outgoing.stdin(buf.freeze()).await?;
-                if is_eof {
-                    break;
+        Ok(())
+    }
+
+    /// Passes on the signals in [`Signal::ALL`] as they come. The client
+    /// stops along with the command on `SIGTSTP`, and both continue on
+    /// `SIGCONT`. A terminal in `raw_mode` gets its own settings back for
+    /// as long as the client is stopped.
+    async fn signal_loop(outgoing: Arc<Mutex<Outgoing>>, raw_mode: Option<&RawMode>) -> Result<()> {
+        let mut streams = Signal::ALL
+            .into_iter()
+            .map(|signal| Ok((signal, unix::signal(SignalKind::from_raw(signal.number()))?)))
+            .collect::<Result<Vec<_>>>()?;
+        loop {
+            let received = streams.iter_mut().map(|(signal, stream)| {
+                let signal = *signal;
+                async move {
+                    stream.recv().await;
+                    signal
+                }
+                .boxed()
+            });
+            let (signal, _, _) = future::select_all(received).await;
+            log::debug!("Passing on {:?}", signal);
+            // a lost connection is for the incoming loop to tell about
+            if let Err(err) = outgoing.lock().await.send(Request::Signal(signal)).await {
+                log::debug!("Failed to pass on {:?}: {}", signal, err);
+            }
+            match (signal, raw_mode) {
+                (Signal::Tstp, Some(raw_mode)) => raw_mode.suspend()?,
+                (Signal::Cont, Some(raw_mode)) => raw_mode.resume()?,
+                _ => {}
+            }
+            if signal == Signal::Tstp {
+                unsafe {
+                    libc::raise(libc::SIGSTOP);
                 }
             }
-            anyhow::Ok(())
         }
-        .boxed();
+    }
+}
 
-        match future::try_select(incoming_loop_fut, stdin_loop_fut).await {
-            Ok(Either::Left((exit_code, _))) => Ok(exit_code),
-            Ok(Either::Right((_, incoming_loop_fut))) => Ok(incoming_loop_fut.await?),
-            Err(either) => Err(either.factor_first().0),
+/// Writes out what `event` carries, and returns the exit code once the
+/// command is done.
+async fn output(
+    event: Event,
+    stdout: &mut (impl AsyncWrite + Unpin),
+    stderr: &mut (impl AsyncWrite + Unpin),
+) -> Result<Option<i32>> {
+    match &event {
+        Event::Cancelled | Event::WindowAdjust(_) => return Ok(None),
+        Event::Stdout(bytes) => {
+            stdout.write_all(bytes).await?;
+            return Ok(None);
+        }
+        Event::Stderr(bytes) => {
+            stderr.write_all(bytes).await?;
+            return Ok(None);
+        }
+        Event::Exited(_) => {}
+        Event::LimitExceeded(limit) => {
+            stderr
+                .write_all(format!("ssh-rev: {}\n", limit).as_bytes())
+                .await?;
+        }
+        // like shells, which keep quiet about what users interrupt
+        Event::Signaled { signal, .. } if [libc::SIGINT, libc::SIGPIPE].contains(signal) => {}
+        Event::Signaled {
+            signal,
+            core_dumped,
+        } => {
+            let core = if *core_dumped { " (core dumped)" } else { "" };
+            let message = format!("ssh-rev: command was killed by signal {}{}\n", signal, core);
+            stderr.write_all(message.as_bytes()).await?;
         }
     }
+    stdout.flush().await?;
+    stderr.flush().await?;
+    Ok(event.exit_code())
 }
 
 struct Incoming(FramedRead<OwnedReadHalf, ssh_agent::Codec>);
 
 impl Incoming {
     async fn recv(&mut self) -> Result<Option<Event>> {
-        let message = self
-            .0
+        let contents = self.recv_reply().await?;
+        if contents.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(contents.try_into()?))
+        }
+    }
+
+    async fn recv_message(&mut self) -> Result<ssh_agent::Message> {
+        self.0
             .try_next()
             .await?
-            .ok_or_else(|| anyhow!("connection was closed unexpectedly"))?;
-        match message.message_type {
-            SSH_AGENT_FAILURE => Err(anyhow!("SSH_AGENT_FAILURE")),
-            SSH_AGENT_EXTENSION_FAILURE => Err(anyhow!("SSH_AGENT_EXTENSION_FAILURE")),
-            SSH_AGENT_SUCCESS => {
-                if message.contents.is_empty() {
-                    Ok(None)
-                } else {
-                    let event = message.contents.try_into()?;
-                    Ok(Some(event))
-                }
+            .ok_or_else(|| anyhow!("connection was closed unexpectedly"))
+    }
+
+    /// Like [`Incoming::recv_message`], but gives up on an agent that sends
+    /// nothing for `limit`.
+    async fn recv_within(&mut self, limit: Option<Duration>) -> Result<ssh_agent::Message> {
+        let Some(limit) = limit else {
+            return self.recv_message().await;
+        };
+        time::timeout(limit, self.recv_message())
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "no word from the agent for {:?}; the connection is dead",
+                    limit
+                )
+            })?
+    }
+
+    async fn recv_reply(&mut self) -> Result<Bytes> {
+        into_reply(self.recv_message().await?)
+    }
+}
+
+fn into_reply(message: ssh_agent::Message) -> Result<Bytes> {
+    match message.message_type {
+        SSH_AGENT_FAILURE => Err(anyhow!("SSH_AGENT_FAILURE")),
+        SSH_AGENT_EXTENSION_FAILURE => {
+            if message.contents.is_empty() {
+                Err(anyhow!("SSH_AGENT_EXTENSION_FAILURE"))
+            } else {
+                Err(Rejection::try_from(message.contents)?.into())
             }
-            message_type => Err(anyhow!("unknown message type: {}", message_type)),
         }
+        SSH_AGENT_SUCCESS => Ok(message.contents),
+        message_type => Err(anyhow!("unknown message type: {}", message_type)),
     }
 }
 
-struct Outgoing(FramedWrite<OwnedWriteHalf, ssh_agent::Codec>);
+struct Outgoing {
+    framed: FramedWrite<OwnedWriteHalf, ssh_agent::Codec>,
+    version: Version,
+    /// Whether the agent takes `Exec` in its binary form.
+    binary_exec: bool,
+    /// The most stdin to send in one message.
+    max_chunk: usize,
+    /// Set if the agent does flow control.
+    stdin_window: Option<u32>,
+    /// Whether the agent passes signals on to the command.
+    signals: bool,
+    /// How often the agent replies to a `Watch` that waits for output, if
+    /// it sends heartbeats.
+    keepalive: Option<Duration>,
+    /// How to deflate stdin: as asked for in `Hello` until the agent turns
+    /// out to be unable to inflate it.
+    compression: Option<Compression>,
+}
 
 impl Outgoing {
-    async fn exec(&mut self, exec: Exec) -> Result<()> {
-        let request = build_request_message(Request::Exec(exec))?;
-        self.0.send(&request).await?;
+    /// Permits for as many bytes of stdin as the agent takes ahead of the
+    /// command, if it does flow control.
+    fn stdin_credit(&self) -> Option<Arc<Semaphore>> {
+        stdin_credit(self.stdin_window)
+    }
+
+    /// How long a `Watch` may go unanswered before the connection is taken
+    /// for dead, if the agent sends heartbeats.
+    fn dead_after(&self) -> Option<Duration> {
+        self.keepalive
+            .map(|keepalive| keepalive * MISSED_HEARTBEATS)
+    }
+
+    fn stdin_chunk_size(&self) -> usize {
+        // v1 agents write stdin before replying, and a large write would
+        // keep them from reading the output the command is blocked on
+        if self.version == Version::V1 {
+            return V1_STDIN_CHUNK;
+        }
+        stdin_chunk_size(self.max_chunk, self.stdin_window)
+    }
+
+    async fn start(&mut self, request: Request, pairing: Option<&ClientPairing>) -> Result<()> {
+        let request = authenticate(request, self.binary_exec, pairing)?;
+        let request = build_message(self.version, encode(request, self.binary_exec)?);
+        self.framed.send(&request).await?;
+        Ok(())
+    }
+
+    async fn hello(&mut self) -> Result<()> {
+        let hello = ClientHello {
+            flow_control: true,
+            resume: true,
+            signaled: true,
+            keepalive: true,
+            compression: self.compression,
+        };
+        let request = build_request_message(self.version, Request::Hello(hello))?;
+        self.framed.send(&request).await?;
+        Ok(())
+    }
+
+    async fn watch(&mut self, window: Option<u32>) -> Result<()> {
+        let request = build_request_message(self.version, Request::Watch(window))?;
+        self.framed.send(&request).await?;
         Ok(())
     }
 
-    async fn watch(&mut self) -> Result<()> {
-        let request = build_request_message(Request::Watch)?;
-        self.0.send(&request).await?;
+    /// Sends a request that needs neither authentication nor splitting.
+    async fn send(&mut self, request: Request) -> Result<()> {
+        let request = build_request_message(self.version, request)?;
+        self.framed.send(&request).await?;
         Ok(())
     }
 
+    /// Sends `bytes` in as many `Stdin` requests as the agent's frame limit
+    /// takes, or a single empty one for the end of input.
     async fn stdin(&mut self, bytes: Bytes) -> Result<()> {
-        let request = build_request_message(Request::Stdin(bytes))?;
-        self.0.send(&request).await?;
+        if bytes.is_empty() {
+            let request = build_request_message(self.version, Request::Stdin(bytes))?;
+            self.framed.send(&request).await?;
+            return Ok(());
+        }
+        for chunk in chunks(bytes, self.max_chunk) {
+            let request = Request::compressed_stdin(chunk, self.compression);
+            self.framed
+                .send(&build_message(self.version, request))
+                .await?;
+        }
         Ok(())
     }
 }
+
+fn stdin_credit(window: Option<u32>) -> Option<Arc<Semaphore>> {
+    window.map(|window| Arc::new(Semaphore::new(window as usize)))
+}
+
+/// The most stdin to send at once: it has to fit in a frame, and in the
+/// window so that the credit for it can ever be had.
+fn stdin_chunk_size(max_chunk: usize, window: Option<u32>) -> usize {
+    match window {
+        Some(window) => max_chunk.min(window as usize),
+        None => max_chunk,
+    }
+}
+
+/// What to tell the agent when there is no terminal to go by.
+const DEFAULT_WINDOW_SIZE: WindowSize = WindowSize { cols: 80, rows: 24 };
+
+/// The most stdin to send at once to v1 agents.
+const V1_STDIN_CHUNK: usize = 256;
+
+/// Heartbeats missed in a row that make a connection dead.
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// Smallest output window a client asks for.
+const MIN_OUTPUT_WINDOW: usize = 4 * 1024;
+/// Output window to start with; agents without windows batch as much.
+const INITIAL_OUTPUT_WINDOW: usize = 64 * 1024;
+
+/// Sizes the output window, which the agent fills a reply up to, to the
+/// link and to whoever takes the output. It doubles while replies come
+/// back at least half full, which they do more the longer the round
+/// trips, and halves while the output is taken more slowly than it comes.
+struct OutputWindow {
+    bytes: usize,
+    max: usize,
+}
+
+impl OutputWindow {
+    fn new(max: usize) -> Self {
+        Self {
+            bytes: INITIAL_OUTPUT_WINDOW.min(max),
+            max,
+        }
+    }
+
+    fn bytes(&self) -> Option<u32> {
+        Some(self.bytes as u32)
+    }
+
+    /// Adjusts the window after a reply with `received` bytes of output,
+    /// given whether the output was taken as fast as it came.
+    fn update(&mut self, received: usize, kept_up: bool) {
+        if !kept_up {
+            self.bytes = (self.bytes / 2).max(MIN_OUTPUT_WINDOW.min(self.max));
+        } else if received * 2 >= self.bytes {
+            self.bytes = (self.bytes * 2).min(self.max);
+        }
+    }
+}
+
+/// Wraps `request` in a MAC if the client is paired.
+fn authenticate(
+    request: Request,
+    binary_exec: bool,
+    pairing: Option<&ClientPairing>,
+) -> Result<Request> {
+    match pairing {
+        Some(pairing) => Ok(Request::Authenticated(
+            pairing.sign(encode(request, binary_exec)?)?,
+        )),
+        None => Ok(request),
+    }
+}
+
+fn encode(request: Request, binary_exec: bool) -> Result<Bytes> {
+    if binary_exec {
+        request.into_bytes()
+    } else {
+        request.into_legacy_bytes()
+    }
+}
+
+/// Runs several commands at once over one agent connection, which takes an
+/// agent that speaks v3. Clones share the connection.
+#[derive(Clone)]
+pub struct Mux(Arc<MuxShared>);
+
+struct MuxShared {
+    framed: Mutex<FramedWrite<OwnedWriteHalf, ssh_agent::Codec>>,
+    binary_exec: bool,
+    max_chunk: usize,
+    stdin_window: Option<u32>,
+    compression: Option<Compression>,
+    state: std::sync::Mutex<MuxState>,
+    pairing: Option<ClientPairing>,
+}
+
+struct MuxState {
+    /// What each reply in flight is for, in the order of the requests.
+    pending: VecDeque<Pending>,
+    sessions: HashMap<SessionId, SessionEntry>,
+    next_session: SessionId,
+    watching: bool,
+    window: OutputWindow,
+}
+
+impl MuxState {
+    fn new(max_chunk: usize) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            sessions: HashMap::new(),
+            next_session: 0,
+            watching: false,
+            window: OutputWindow::new(max_chunk),
+        }
+    }
+
+    /// Whether a `Watch` should be sent now: more output is asked for while
+    /// some session has less than a window of it yet to take, so that one
+    /// that takes none holds back no other.
+    fn wants_watch(&self) -> bool {
+        !self.watching
+            && self
+                .sessions
+                .values()
+                .any(|entry| entry.unread < self.window.bytes)
+    }
+}
+
+struct SessionEntry {
+    events: mpsc::UnboundedSender<Event>,
+    credit: Option<Arc<Semaphore>>,
+    /// Bytes of output handed to the session that it has not taken yet.
+    unread: usize,
+}
+
+enum Pending {
+    Reply(oneshot::Sender<Result<Bytes>>),
+    Watch,
+}
+
+impl Mux {
+    pub async fn exec(&self, exec: Exec) -> Result<MuxSession> {
+        self.start(Request::Exec(exec)).await
+    }
+
+    pub async fn verb(&self, call: VerbCall) -> Result<MuxSession> {
+        self.start(Request::Verb(call)).await
+    }
+
+    async fn start(&self, request: Request) -> Result<MuxSession> {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let credit = stdin_credit(self.0.stdin_window);
+        let session = {
+            let mut state = self.0.state.lock().unwrap();
+            let session = state.next_session;
+            state.next_session = session.wrapping_add(1);
+            // its events may arrive right after the reply
+            let entry = SessionEntry {
+                events: events_tx,
+                credit: credit.clone(),
+                unread: 0,
+            };
+            state.sessions.insert(session, entry);
+            session
+        };
+        let request = authenticate(request, self.0.binary_exec, self.0.pairing.as_ref())?;
+        if let Err(err) = self.request(session, request).await {
+            self.0.state.lock().unwrap().sessions.remove(&session);
+            return Err(err);
+        }
+        self.watch().await?;
+        Ok(MuxSession {
+            mux: self.clone(),
+            events,
+            stdin: MuxStdin {
+                mux: self.clone(),
+                session,
+                credit,
+            },
+        })
+    }
+
+    /// Sends `request` and waits for the reply to it.
+    async fn request(&self, session: SessionId, request: Request) -> Result<Bytes> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(session, request, Pending::Reply(reply_tx))
+            .await?;
+        reply_rx
+            .await
+            .map_err(|_| anyhow!("connection was closed unexpectedly"))?
+    }
+
+    async fn send(&self, session: SessionId, request: Request, pending: Pending) -> Result<()> {
+        let request = match request {
+            Request::Stdin(stdin) => Request::compressed_stdin(stdin, self.0.compression),
+            request => encode(request, self.0.binary_exec)?,
+        };
+        let message = build_session_message(session, request);
+        let mut framed = self.0.framed.lock().await;
+        self.0.state.lock().unwrap().pending.push_back(pending);
+        framed.send(&message).await?;
+        Ok(())
+    }
+
+    /// Sends a `Watch` unless one is in flight, no command is running or
+    /// the sessions have a window of output yet to take.
+    async fn watch(&self) -> Result<()> {
+        let window = {
+            let mut state = self.0.state.lock().unwrap();
+            if !state.wants_watch() {
+                return Ok(());
+            }
+            state.watching = true;
+            state.window.bytes()
+        };
+        self.send(0, Request::Watch(window), Pending::Watch).await
+    }
+
+    /// Takes note that `session` took `len` bytes of output, and returns
+    /// whether a `Watch` is due now.
+    fn taken(&self, session: SessionId, len: usize) -> bool {
+        let mut state = self.0.state.lock().unwrap();
+        // it is gone once its last event was handed to it
+        if let Some(entry) = state.sessions.get_mut(&session) {
+            entry.unread -= len;
+        }
+        len > 0 && state.wants_watch()
+    }
+
+    /// Stops handing output to `session`, and returns whether a `Watch` is
+    /// due now that what it left untaken no longer counts.
+    fn close(&self, session: SessionId) -> bool {
+        let mut state = self.0.state.lock().unwrap();
+        state.sessions.remove(&session).is_some() && state.wants_watch()
+    }
+
+    /// Hands each reply to whoever waits for it, until the connection is
+    /// closed or every [`Mux`] is dropped.
+    async fn read_loop(shared: Weak<MuxShared>, mut incoming: Incoming) {
+        let mut next_seq = 0;
+        let err = loop {
+            let message = match incoming.recv_message().await {
+                Ok(message) => message,
+                Err(err) => break err,
+            };
+            let Some(mux) = shared.upgrade().map(Mux) else {
+                return;
+            };
+            let pending = mux.0.state.lock().unwrap().pending.pop_front();
+            match pending {
+                Some(Pending::Reply(reply_tx)) => {
+                    let _ = reply_tx.send(into_reply(message));
+                }
+                Some(Pending::Watch) => {
+                    if let Err(err) = mux.dispatch(into_reply(message), &mut next_seq) {
+                        break err;
+                    }
+                    if let Err(err) = mux.watch().await {
+                        break err;
+                    }
+                }
+                None => break anyhow!("unexpected reply"),
+            }
+        };
+        log::debug!("Stopped reading replies: {}", err);
+        if let Some(shared) = shared.upgrade() {
+            let mut state = shared.state.lock().unwrap();
+            state.pending.clear();
+            state.sessions.clear();
+        }
+    }
+
+    /// Passes the events of a reply to `Watch` on to their sessions.
+    fn dispatch(&self, reply: Result<Bytes>, next_seq: &mut u64) -> Result<()> {
+        let mut state = self.0.state.lock().unwrap();
+        state.watching = false;
+        let batch = SessionEventBatch::try_from(reply?)?;
+        if batch.seq != *next_seq {
+            bail!("expected event {} but got {}", next_seq, batch.seq);
+        }
+        *next_seq += batch.events.len() as u64;
+        let received = batch.events.iter().map(|(_, event)| event.data_len()).sum();
+        let half = state.window.bytes / 2;
+        let kept_up = state.sessions.values().all(|entry| entry.unread < half);
+        state.window.update(received, kept_up);
+        for (session, event) in batch.events {
+            let is_last = event.is_last();
+            let Some(entry) = state.sessions.get_mut(&session) else {
+                continue;
+            };
+            match event {
+                Event::WindowAdjust(bytes) => {
+                    if let Some(credit) = &entry.credit {
+                        credit.add_permits(bytes as usize);
+                    }
+                }
+                event => {
+                    let len = event.data_len();
+                    if entry.events.send(event).is_ok() {
+                        entry.unread += len;
+                    }
+                }
+            }
+            if is_last {
+                state.sessions.remove(&session);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MuxSession {
+    fn drop(&mut self) {
+        let session = self.stdin.session;
+        if let (true, Ok(runtime)) = (self.mux.close(session), Handle::try_current()) {
+            let mux = self.mux.clone();
+            runtime.spawn(async move { mux.watch().await });
+        }
+    }
+}
+
+/// A command started by a [`Mux`].
+pub struct MuxSession {
+    mux: Mux,
+    events: mpsc::UnboundedReceiver<Event>,
+    stdin: MuxStdin,
+}
+
+/// The stdin of a [`MuxSession`], to write while its output is being read.
+#[derive(Clone)]
+pub struct MuxStdin {
+    mux: Mux,
+    session: SessionId,
+    credit: Option<Arc<Semaphore>>,
+}
+
+impl MuxStdin {
+    /// Writes `bytes` to the stdin of the command, or closes it if `bytes`
+    /// is empty. Large writes are split to fit the agent's frame limit, and
+    /// wait for the command to read what was sent before if the agent does
+    /// flow control.
+    pub async fn write(&self, bytes: Bytes) -> Result<()> {
+        if bytes.is_empty() {
+            self.mux
+                .request(self.session, Request::Stdin(bytes))
+                .await?;
+            return Ok(());
+        }
+        let chunk_size = stdin_chunk_size(self.mux.0.max_chunk, self.mux.0.stdin_window);
+        for chunk in chunks(bytes, chunk_size) {
+            if let Some(credit) = &self.credit {
+                credit.acquire_many(chunk.len() as u32).await?.forget();
+            }
+            self.mux
+                .request(self.session, Request::Stdin(chunk))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl MuxSession {
+    /// Like [`MuxStdin::write`]. As with a pipe, a command that writes much
+    /// output before reading all of its input needs the output read at the
+    /// same time, through [`MuxSession::stdin_writer`].
+    pub async fn stdin(&self, bytes: Bytes) -> Result<()> {
+        self.stdin.write(bytes).await
+    }
+
+    pub fn stdin_writer(&self) -> MuxStdin {
+        self.stdin.clone()
+    }
+
+    /// Sends `signal` to the command and whatever it started.
+    pub async fn signal(&self, signal: Signal) -> Result<()> {
+        self.mux
+            .request(self.stdin.session, Request::Signal(signal))
+            .await?;
+        Ok(())
+    }
+
+    /// The next event of the command; `None` after it exited or if the
+    /// connection was lost.
+    pub async fn event(&mut self) -> Option<Event> {
+        let event = self.events.recv().await?;
+        if self.mux.taken(self.stdin.session, event.data_len()) {
+            if let Err(err) = self.mux.watch().await {
+                log::debug!("Failed to watch: {}", err);
+            }
+        }
+        Some(event)
+    }
+
+    /// Writes out the output of the command until it exits, and returns its
+    /// exit code.
+    pub async fn output(
+        mut self,
+        mut stdout: impl AsyncWrite + Unpin,
+        mut stderr: impl AsyncWrite + Unpin,
+    ) -> Result<i32> {
+        while let Some(event) = self.event().await {
+            if let Some(exit_code) = output(event, &mut stdout, &mut stderr).await? {
+                return Ok(exit_code);
+            }
+        }
+        Err(anyhow!("connection was closed unexpectedly"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io, net::UnixListener};
+
+    use super::*;
+    use crate::{
+        rpc::{Limit, RejectionKind},
+        Config, RevAgent,
+    };
+
+    /// Starts an agent with `config` on a socket named after the test.
+    fn serve(name: &str, config: Config) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ssh-rev-test-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let agent = RevAgent::new(listener, None, config).unwrap();
+        tokio::spawn(agent.run());
+        path
+    }
+
+    /// Runs `exec` to the end as `ssh-rev exec` would, without stdin.
+    async fn run(path: &Path, exec: Exec) -> (i32, Vec<u8>) {
+        let mut rev_exec = RevExec::open(path).await.unwrap();
+        rev_exec.start(Request::Exec(exec)).await.unwrap();
+        let mut stdout = vec![];
+        let exit_code = rev_exec
+            .stream(None, false, &mut stdout, io::sink())
+            .await
+            .unwrap();
+        (exit_code, stdout)
+    }
+
+    #[tokio::test]
+    async fn test_mux() {
+        let path = serve("mux", Config::default());
+
+        let mux = RevExec::open(&path)
+            .await
+            .unwrap()
+            .into_mux()
+            .await
+            .unwrap();
+        let cat = mux.exec(Exec::new("cat", &[])).await.unwrap();
+        let echo = mux.exec(Exec::new("echo", &["hello"])).await.unwrap();
+        // echo is done while cat still waits for its input
+        let mut stdout = vec![];
+        let exit_code = echo.output(&mut stdout, io::sink()).await.unwrap();
+        assert_eq!((exit_code, &stdout[..]), (0, &b"hello\n"[..]));
+        cat.stdin("meow".into()).await.unwrap();
+        cat.stdin(Bytes::new()).await.unwrap();
+        let mut stdout = vec![];
+        let exit_code = cat.output(&mut stdout, io::sink()).await.unwrap();
+        assert_eq!((exit_code, &stdout[..]), (0, &b"meow"[..]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spawn_failure() {
+        let path = serve("spawn", Config::default());
+        let mux = RevExec::open(&path)
+            .await
+            .unwrap()
+            .into_mux()
+            .await
+            .unwrap();
+        // commands that cannot be started leave the connection usable
+        let err = mux
+            .exec(Exec::new("ssh-rev-missing", &[]))
+            .await
+            .err()
+            .unwrap();
+        let rejection = err.downcast_ref::<Rejection>().unwrap();
+        assert_eq!(rejection.kind, RejectionKind::Spawn);
+        assert_eq!(rejection.errno, Some(libc::ENOENT));
+        assert_eq!(rejection.exit_code(), 127);
+        let mut elsewhere = Exec::new("true", &[]);
+        elsewhere.cwd = Some("/nonexistent".into());
+        let err = mux.exec(elsewhere).await.err().unwrap();
+        let rejection = err.downcast_ref::<Rejection>().unwrap();
+        assert_eq!(
+            (rejection.kind, rejection.exit_code()),
+            (RejectionKind::Cwd, 126)
+        );
+        let echo = mux.exec(Exec::new("echo", &["still here"])).await.unwrap();
+        assert_eq!(echo.output(io::sink(), io::sink()).await.unwrap(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_transfer() {
+        // a window much smaller than the input, so that it takes credit
+        let config = toml::from_str("connection.stdin_window_bytes = 65536").unwrap();
+        let path = serve("large", config);
+
+        let mux = RevExec::open(&path)
+            .await
+            .unwrap()
+            .into_mux()
+            .await
+            .unwrap();
+        let cat = mux.exec(Exec::new("cat", &[])).await.unwrap();
+        // well over the frame limit both ways
+        let input: Bytes = (0..4 * MAX_FRAME_BYTES).map(|i| i as u8).collect();
+        let stdin = cat.stdin_writer();
+        let writer = tokio::spawn({
+            let input = input.clone();
+            async move {
+                stdin.write(input).await.unwrap();
+                stdin.write(Bytes::new()).await.unwrap();
+            }
+        });
+        let mut stdout = vec![];
+        let exit_code = cat.output(&mut stdout, io::sink()).await.unwrap();
+        assert_eq!(exit_code, 0);
+        assert!(stdout == input);
+        writer.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_signaled() {
+        let path = serve("signaled", Config::default());
+        let mux = RevExec::open(&path)
+            .await
+            .unwrap()
+            .into_mux()
+            .await
+            .unwrap();
+        let kill = mux
+            .exec(Exec::new("sh", &["-c", "kill -SEGV $$"]))
+            .await
+            .unwrap();
+        let exit_code = kill.output(io::sink(), io::sink()).await.unwrap();
+        assert_eq!(exit_code, 128 + libc::SIGSEGV);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mux_signal() {
+        let path = serve("mux-signal", Config::default());
+        let mux = RevExec::open(&path)
+            .await
+            .unwrap()
+            .into_mux()
+            .await
+            .unwrap();
+        let sleep = mux.exec(Exec::new("sleep", &["10"])).await.unwrap();
+        sleep.signal(Signal::Int).await.unwrap();
+        let exit_code = sleep.output(io::sink(), io::sink()).await.unwrap();
+        assert_eq!(exit_code, 128 + libc::SIGINT);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_untaken_output() {
+        let path = serve("untaken", Config::default());
+        let mux = RevExec::open(&path)
+            .await
+            .unwrap()
+            .into_mux()
+            .await
+            .unwrap();
+        // far more output than a window, none of it taken
+        let flood = Exec::new("sh", &["-c", "head -c 4000000 /dev/zero; sleep 30"]);
+        let flood = mux.exec(flood).await.unwrap();
+        let output = async {
+            let echo = mux.exec(Exec::new("echo", &["hello"])).await.unwrap();
+            let mut stdout = vec![];
+            let exit_code = echo.output(&mut stdout, io::sink()).await.unwrap();
+            (exit_code, stdout)
+        };
+        let (exit_code, stdout) = time::timeout(Duration::from_secs(10), output)
+            .await
+            .unwrap();
+        assert_eq!(exit_code, 0);
+        assert_eq!(stdout, b"hello\n");
+
+        // nor does what it left when dropped, wherever it was
+        drop(flood);
+        let echo = mux.exec(Exec::new("echo", &["bye"])).await.unwrap();
+        let output = echo.output(io::sink(), io::sink());
+        let exit_code = time::timeout(Duration::from_secs(10), output)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(exit_code, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_start() {
+        let dir = std::env::temp_dir().join(format!("ssh-rev-test-prompt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // asks the user only about `echo slow`, who takes a while
+        let prompt = dir.join("prompt");
+        std::fs::write(
+            &prompt,
+            "#!/bin/sh\ncase \"$1\" in *slow*) sleep 3;; esac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&prompt, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let config = toml::from_str(&format!("confirm.program = {:?}", prompt)).unwrap();
+        let path = serve("slow", config);
+        let mux = RevExec::open(&path)
+            .await
+            .unwrap()
+            .into_mux()
+            .await
+            .unwrap();
+
+        let line = dir.join("line");
+        let script = format!("read line; echo \"$line\" > {:?}", line);
+        let read = mux.exec(Exec::new("sh", &["-c", &script])).await.unwrap();
+        let slow = tokio::spawn({
+            let mux = mux.clone();
+            async move { mux.exec(Exec::new("echo", &["slow"])).await.unwrap() }
+        });
+        time::sleep(Duration::from_millis(200)).await;
+        // the reply waits for the slow one, but the command need not
+        let stdin = read.stdin_writer();
+        tokio::spawn(async move { stdin.write("hello\n".into()).await });
+        let started = Instant::now();
+        while std::fs::read(&line).ok().as_deref() != Some(b"hello\n") {
+            assert!(started.elapsed() < Duration::from_secs(2));
+            time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let exit_code = slow.await.unwrap().output(io::sink(), io::sink()).await;
+        assert_eq!(exit_code.unwrap(), 0);
+        assert_eq!(read.output(io::sink(), io::sink()).await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frame_limit() {
+        let path = serve(
+            "frames",
+            toml::from_str("connection.max_frame_bytes = 256").unwrap(),
+        );
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let mut framed = tokio_util::codec::Framed::new(stream, ssh_agent::Codec::default());
+
+        // an identity to add, for the upstream agent that is not there
+        let add_identity = ssh_agent::Message {
+            message_type: 17,
+            contents: vec![0; 1024].into(),
+        };
+        let long = "x".repeat(1024);
+        let long = Request::Exec(Exec::new("echo", &[&long]));
+        let short = Request::Exec(Exec::new("echo", &["hello"]));
+        let requests = [
+            (add_identity, SSH_AGENT_FAILURE),
+            (
+                build_request_message(Version::V1, long).unwrap(),
+                SSH_AGENT_EXTENSION_FAILURE,
+            ),
+            (
+                build_request_message(Version::V1, short).unwrap(),
+                SSH_AGENT_SUCCESS,
+            ),
+        ];
+        for (request, message_type) in requests {
+            framed.send(&request).await.unwrap();
+            let reply = framed.try_next().await.unwrap().unwrap();
+            assert_eq!(reply.message_type, message_type);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_detach() {
+        let path = serve("jobs", Config::default());
+
+        let open = || async { RevExec::open(&path).await.unwrap() };
+        let echo = Exec::new("sh", &["-c", "echo hello; sleep 0.2; echo bye"]);
+        let job = open().await.detach(echo).await.unwrap();
+        let sleep = open()
+            .await
+            .detach(Exec::new("sleep", &["30"]))
+            .await
+            .unwrap();
+        let jobs = open().await.jobs().await.unwrap();
+        assert_eq!(
+            jobs.iter().map(|job| job.id).collect::<Vec<_>>(),
+            vec![job.id, sleep.id]
+        );
+
+        // the output from before attaching is kept
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let mut stdout = vec![];
+        let exit_code = open()
+            .await
+            .attach(job.id, &mut stdout, io::sink())
+            .await
+            .unwrap();
+        assert_eq!((exit_code, &stdout[..]), (0, &b"hello\nbye\n"[..]));
+        let status = open().await.wait(job.id).await.unwrap();
+        assert_eq!(status.exit_code, Some(0));
+        assert!(open().await.wait(job.id).await.is_err());
+
+        open().await.kill(sleep.id).await.unwrap();
+        let status = open().await.wait(sleep.id).await.unwrap();
+        assert_eq!(status.signal, Some(libc::SIGTERM));
+        assert_eq!(status.exit_code, Some(128 + libc::SIGTERM));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let config = toml::from_str("connection.resume_grace_secs = 10").unwrap();
+        let path = serve("resume", config);
+
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        let echo = Exec::new("sh", &["-c", "echo hello; sleep 0.2; echo bye"]);
+        rev_exec.start(Request::Exec(echo)).await.unwrap();
+        let token = rev_exec.resume_token.clone().unwrap();
+        // lose the connection before seeing any output
+        drop(rev_exec);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        assert!(rev_exec.send_resume("0123").await.is_err());
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        let resumed = rev_exec.send_resume(&token).await.unwrap();
+        assert_eq!(resumed.seq, 0);
+        let mut stdout = vec![];
+        let exit_code = rev_exec
+            .stream(None, false, &mut stdout, io::sink())
+            .await
+            .unwrap();
+        assert_eq!((exit_code, &stdout[..]), (0, &b"hello\nbye\n"[..]));
+
+        // nothing is left to resume once the command is over
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        assert!(rev_exec.send_resume(&token).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_signal() {
+        let path = serve("signal", Config::default());
+
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        // the trap shows that the shell got it, and the output ending
+        // before `sleep` would close it that the rest of its group did
+        let script = "trap 'echo term; exit 3' TERM; sleep 5 & wait; echo missed";
+        rev_exec
+            .start(Request::Exec(Exec::new("sh", &["-c", script])))
+            .await
+            .unwrap();
+        assert!(rev_exec.outgoing.signals);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let started = Instant::now();
+        rev_exec
+            .outgoing
+            .send(Request::Signal(Signal::Term))
+            .await
+            .unwrap();
+        let mut stdout = vec![];
+        let exit_code = rev_exec
+            .stream(None, false, &mut stdout, io::sink())
+            .await
+            .unwrap();
+        assert_eq!((exit_code, &stdout[..]), (3, &b"term\n"[..]));
+        assert!(started.elapsed().as_secs() < 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pty() {
+        let path = serve("pty", Config::default());
+
+        let mut rev_exec = RevExec::open(&path).await.unwrap().with_tty(true);
+        let script = "read line; stty size; echo \"$line\" >&2; tty -s";
+        rev_exec
+            .start(Request::Exec(Exec::new("sh", &["-c", script])))
+            .await
+            .unwrap();
+        assert!(rev_exec.pty);
+        let size = WindowSize {
+            cols: 120,
+            rows: 40,
+        };
+        rev_exec.outgoing.send(Request::Resize(size)).await.unwrap();
+        rev_exec.outgoing.stdin("hey\n".into()).await.unwrap();
+        let mut stdout = vec![];
+        let exit_code = rev_exec
+            .stream(None, false, &mut stdout, io::sink())
+            .await
+            .unwrap();
+        // the terminal echoes the input, and stderr comes as stdout
+        assert_eq!(
+            (exit_code, &stdout[..]),
+            (0, &b"hey\r\n40 120\r\nhey\r\n"[..])
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pty_eof() {
+        let path = serve("pty-eof", Config::default());
+
+        // the end of stdin ends what a command reads from its terminal
+        let mut rev_exec = RevExec::open(&path).await.unwrap().with_tty(true);
+        rev_exec
+            .start(Request::Exec(Exec::new("cat", &[])))
+            .await
+            .unwrap();
+        rev_exec.outgoing.stdin("hey\n".into()).await.unwrap();
+        rev_exec.outgoing.stdin(Bytes::new()).await.unwrap();
+        let mut stdout = vec![];
+        let exit_code = tokio::time::timeout(
+            Duration::from_secs(5),
+            rev_exec.stream(None, false, &mut stdout, io::sink()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!((exit_code, &stdout[..]), (0, &b"hey\r\nhey\r\n"[..]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pty_term() {
+        // what the agent has of its own would hide what the request had
+        for (name, config, term) in [
+            ("term-allowed", "env.clear = true", "xterm-test"),
+            (
+                "term-denied",
+                "env.clear = true\nenv.deny = [\"TERM\"]",
+                "unset",
+            ),
+        ] {
+            let path = serve(name, toml::from_str(config).unwrap());
+            let mut rev_exec = RevExec::open(&path).await.unwrap();
+            rev_exec.handshake(Version::V2).await.unwrap();
+            let terminal = Terminal {
+                term: Some("xterm-test".into()),
+                size: DEFAULT_WINDOW_SIZE,
+            };
+            rev_exec
+                .outgoing
+                .send(Request::Pty(terminal))
+                .await
+                .unwrap();
+            rev_exec.incoming.recv_reply().await.unwrap();
+            let echo = Exec::new("sh", &["-c", "echo ${TERM-unset}"]);
+            rev_exec.start(Request::Exec(echo)).await.unwrap();
+            let mut stdout = vec![];
+            let exit_code = rev_exec
+                .stream(None, false, &mut stdout, io::sink())
+                .await
+                .unwrap();
+            assert_eq!(
+                (exit_code, stdout),
+                (0, format!("{}\r\n", term).into_bytes())
+            );
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        let config =
+            toml::from_str("connection.keepalive_secs = 1\nconnection.idle_timeout_secs = 2")
+                .unwrap();
+        let path = serve("keepalive", config);
+
+        // a quiet command gets heartbeats
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        let sleep = Exec::new("sleep", &["10"]);
+        rev_exec.start(Request::Exec(sleep.clone())).await.unwrap();
+        assert_eq!(rev_exec.outgoing.keepalive, Some(Duration::from_secs(1)));
+        rev_exec.outgoing.watch(None).await.unwrap();
+        let reply = rev_exec.incoming.recv_reply().await.unwrap();
+        let batch = EventBatch::try_from(reply).unwrap();
+        assert_eq!((batch.seq, batch.events.len()), (0, 0));
+        // and a client that stops asking for output is dropped
+        let started = Instant::now();
+        assert!(rev_exec.incoming.recv_message().await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        // the client gives up on an agent that misses its heartbeats
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        rev_exec.start(Request::Exec(sleep)).await.unwrap();
+        rev_exec.outgoing.keepalive = Some(Duration::from_millis(100));
+        let err = rev_exec
+            .stream(None, false, io::sink(), io::sink())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("connection is dead"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let path = serve(
+            "timeout",
+            toml::from_str("limits.timeout_secs = 1").unwrap(),
+        );
+        // the background sleep would keep the output open if it were spared
+        let started = Instant::now();
+        let (exit_code, _) = run(&path, Exec::new("sh", &["-c", "sleep 30 & wait"])).await;
+        assert_eq!(exit_code, Limit::Timeout.exit_code());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // the time is up even for a command nobody asks about
+        let mut rev_exec = RevExec::open(&path).await.unwrap();
+        let sleep = Exec::new("sh", &["-c", "sleep 30 & wait"]);
+        rev_exec.start(Request::Exec(sleep)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let started = Instant::now();
+        let exit_code = rev_exec
+            .stream(None, false, io::sink(), io::sink())
+            .await
+            .unwrap();
+        assert_eq!(exit_code, Limit::Timeout.exit_code());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kill_job() {
+        let path = serve("kill", Config::default());
+        let open = || async { RevExec::open(&path).await.unwrap() };
+        // the background sleep would keep the output open if it were spared
+        let sleep = Exec::new("sh", &["-c", "sleep 30 & wait"]);
+        let job = open().await.detach(sleep).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        open().await.kill(job.id).await.unwrap();
+        let wait = time::timeout(Duration::from_secs(10), open().await.wait(job.id));
+        let status = wait.await.unwrap().unwrap();
+        assert_eq!(status.signal, Some(libc::SIGTERM));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_limit() {
+        let config = toml::from_str("limits.max_output_bytes = 1000").unwrap();
+        let path = serve("output-limit", config);
+        let (exit_code, stdout) = run(&path, Exec::new("sh", &["-c", "yes & yes"])).await;
+        assert_eq!(exit_code, Limit::Output.exit_code());
+        assert_eq!(stdout.len(), 1000);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cpu_limit() {
+        let path = serve("cpu-limit", toml::from_str("limits.cpu_secs = 1").unwrap());
+        let (exit_code, _) = run(&path, Exec::new("sh", &["-c", "while :; do :; done"])).await;
+        assert_eq!(exit_code, Limit::Cpu.exit_code());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_output_window() {
+        let mut window = OutputWindow::new(100 * 1024);
+        assert_eq!(window.bytes, INITIAL_OUTPUT_WINDOW);
+        window.update(INITIAL_OUTPUT_WINDOW, true);
+        assert_eq!(window.bytes, 100 * 1024);
+        window.update(1024, true);
+        assert_eq!(window.bytes, 100 * 1024);
+        for _ in 0..10 {
+            window.update(100 * 1024, false);
+        }
+        assert_eq!(window.bytes, MIN_OUTPUT_WINDOW);
+    }
+}
@@ -1,24 +1,118 @@
-use std::{collections::HashMap, mem::size_of};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fmt,
+    io::{Read, Write},
+    mem::size_of,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
 use num_enum::TryFromPrimitive;
 use serde::{Deserialize, Serialize};
 
-use crate::ssh_agent::{Extension, Message, SSH_AGENTC_EXTENSION};
+use crate::{
+    connection::ConnectionConfig,
+    ssh_agent::{
+        get_string, put_string, Extension, Message, MAX_FRAME_BYTES, SSH_AGENTC_EXTENSION,
+        SSH_AGENT_EXTENSION_FAILURE,
+    },
+};
 
 pub const EXTENSION_TYPE: &[u8] = b"ssh-rev-exec.1@koba789.com";
+pub const EXTENSION_TYPE_V2: &[u8] = b"ssh-rev-exec.2@koba789.com";
+pub const EXTENSION_TYPE_V3: &[u8] = b"ssh-rev-exec.3@koba789.com";
 
-pub fn build_request_message(req: Request) -> Result<Message> {
-    let req_bytes = req.into_bytes()?;
+/// The protocol version is chosen by the extension type of each request.
+/// In v1 every reply to `Watch` carries a single [`Event`], and `Stdin`
+/// cancels a pending `Watch`. In v2 it carries an [`EventBatch`], and
+/// `Stdin` does not cancel anything. v3 is v2 with several commands per
+/// connection: every request starts with the [`SessionId`] it is for, and
+/// a `Watch`, whose session is ignored, is answered with a
+/// [`SessionEventBatch`] of events from all sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    V1,
+    V2,
+    V3,
+}
+
+impl Version {
+    pub const ALL: [Version; 3] = [Version::V1, Version::V2, Version::V3];
+
+    pub fn extension_type(self) -> &'static [u8] {
+        match self {
+            Version::V1 => EXTENSION_TYPE,
+            Version::V2 => EXTENSION_TYPE_V2,
+            Version::V3 => EXTENSION_TYPE_V3,
+        }
+    }
+
+    pub fn from_extension_type(extension_type: &[u8]) -> Option<Self> {
+        match extension_type {
+            EXTENSION_TYPE => Some(Version::V1),
+            EXTENSION_TYPE_V2 => Some(Version::V2),
+            EXTENSION_TYPE_V3 => Some(Version::V3),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> u32 {
+        match self {
+            Version::V1 => 1,
+            Version::V2 => 2,
+            Version::V3 => 3,
+        }
+    }
+
+    pub fn from_number(number: u32) -> Option<Self> {
+        match number {
+            1 => Some(Version::V1),
+            2 => Some(Version::V2),
+            3 => Some(Version::V3),
+            _ => None,
+        }
+    }
+}
+
+/// Picked by the client for each command it starts on a v3 connection.
+pub type SessionId = u32;
+
+/// Builds a request of v1 or v2. See [`build_session_message`] for v3.
+pub fn build_request_message(version: Version, req: Request) -> Result<Message> {
+    Ok(build_message(version, req.into_bytes()?))
+}
+
+/// Builds a v3 request out of an encoded [`Request`].
+pub fn build_session_message(session: SessionId, req: Bytes) -> Message {
+    let mut bytes = BytesMut::new();
+    bytes.put_u32(session);
+    bytes.put(req);
+    build_message(Version::V3, bytes.freeze())
+}
+
+/// Splits a v3 request into its session and the request proper.
+pub fn split_session(mut bytes: Bytes) -> Result<(SessionId, Bytes)> {
+    if bytes.len() < size_of::<SessionId>() {
+        return Err(anyhow!("malformed request: session must be a u32"));
+    }
+    let session = bytes.get_u32();
+    Ok((session, bytes))
+}
+
+pub fn build_message(version: Version, contents: Bytes) -> Message {
     let ext = Extension {
-        extension_type: EXTENSION_TYPE.into(),
-        contents: req_bytes,
+        extension_type: version.extension_type().into(),
+        contents,
     };
-    Ok(Message {
+    Message {
         message_type: SSH_AGENTC_EXTENSION,
         contents: ext.into(),
-    })
+    }
 }
 
 #[derive(Debug, TryFromPrimitive)]
@@ -27,35 +121,566 @@ pub enum OpCode {
     Exec = 0,
     Stdin = 1,
     Watch = 2,
+    Pair = 3,
+    Authenticated = 4,
+    Verb = 5,
+    Hello = 6,
+    Detach = 7,
+    Jobs = 8,
+    Attach = 9,
+    Wait = 10,
+    Kill = 11,
+    Resume = 12,
+    Signal = 13,
+    Pty = 14,
+    Resize = 15,
+    /// Wraps another request, deflated. It is decoded as that request.
+    Deflated = 16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Request {
     Exec(Exec),
     Stdin(Bytes),
-    Watch,
+    /// With the output window of a client that does flow control: the most
+    /// bytes of output it takes in the reply.
+    Watch(Option<u32>),
+    Pair(Pair),
+    Authenticated(Auth),
+    Verb(VerbCall),
+    Hello(ClientHello),
+    /// Wraps an `Exec` or `Verb` to run as a job that outlives the
+    /// connection. The reply is the [`JobStatus`] of the new job.
+    Detach(Bytes),
+    /// Lists the jobs of the caller.
+    Jobs,
+    /// Streams the output of a job as if it had been started on this
+    /// connection, from the oldest output the agent kept. v2 and later.
+    Attach(JobId),
+    /// Waits for a job to end and forgets it.
+    Wait(JobId),
+    Kill(JobId),
+    /// Takes over a v2 command whose connection was lost. The reply is a
+    /// [`Resumed`], after which the command is served as if it had been
+    /// started on this connection.
+    Resume(Resume),
+    /// Delivers a signal to the process group of the running command. Signals
+    /// to a command that is gone are dropped.
+    Signal(Signal),
+    /// Asks for the next command to run on a pseudo-terminal of the agent
+    /// instead of pipes. Its stderr then comes as stdout.
+    Pty(Terminal),
+    /// Resizes the terminal of the running command, if it has one.
+    Resize(WindowSize),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A command line to run. Names, values and paths are byte strings, as
+/// they are to the OS.
+#[derive(Debug, Clone)]
 pub struct Exec {
-    pub cmd: String,
-    pub args: Vec<String>,
+    pub cmd: OsString,
+    pub args: Vec<OsString>,
+    pub envs: HashMap<OsString, OsString>,
+    pub cwd: Option<PathBuf>,
+    /// Indices of the arguments that are paths on the remote host, which
+    /// the agent may translate to local ones.
+    pub path_args: Vec<usize>,
+}
+
+#[cfg(test)]
+impl Exec {
+    /// `cmd` with `args`, and nothing else.
+    pub fn new(cmd: &str, args: &[&str]) -> Self {
+        Self {
+            cmd: cmd.into(),
+            args: args.iter().map(|&arg| arg.into()).collect(),
+            envs: Default::default(),
+            cwd: None,
+            path_args: vec![],
+        }
+    }
+}
+
+/// The JSON form of [`Exec`], which can only carry UTF-8.
+#[derive(Serialize, Deserialize)]
+struct JsonExec {
+    cmd: String,
+    args: Vec<String>,
+    envs: HashMap<String, String>,
+    cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    path_args: Vec<usize>,
+}
+
+impl From<JsonExec> for Exec {
+    fn from(json: JsonExec) -> Self {
+        Self {
+            cmd: json.cmd.into(),
+            args: json.args.into_iter().map(OsString::from).collect(),
+            envs: json
+                .envs
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+            cwd: json.cwd.map(PathBuf::from),
+            path_args: json.path_args,
+        }
+    }
+}
+
+impl TryFrom<&Exec> for JsonExec {
+    type Error = anyhow::Error;
+
+    fn try_from(exec: &Exec) -> Result<Self> {
+        let utf8 = |string: &OsStr| {
+            string.to_str().map(str::to_owned).ok_or_else(|| {
+                anyhow!(
+                    "the agent is too old to take {:?}, which is not UTF-8",
+                    string
+                )
+            })
+        };
+        Ok(Self {
+            cmd: utf8(&exec.cmd)?,
+            args: exec
+                .args
+                .iter()
+                .map(|arg| utf8(arg))
+                .collect::<Result<_>>()?,
+            envs: exec
+                .envs
+                .iter()
+                .map(|(name, value)| Ok((utf8(name)?, utf8(value)?)))
+                .collect::<Result<_>>()?,
+            cwd: exec
+                .cwd
+                .as_deref()
+                .map(|cwd| utf8(cwd.as_os_str()))
+                .transpose()?,
+            path_args: exec.path_args.clone(),
+        })
+    }
+}
+
+/// Leads the binary form of [`Exec`]. The JSON form always starts with `{`.
+const EXEC_BINARY: u8 = 1;
+
+impl Exec {
+    /// Encodes as the marker byte followed by `cmd`, the `args`, the
+    /// `envs` as pairs and an optional `cwd`, all as SSH strings, and then
+    /// the `path_args`. Lists are prefixed with their length as a u32.
+    pub fn to_binary(&self, bytes: &mut BytesMut) {
+        bytes.put_u8(EXEC_BINARY);
+        put_string(bytes, self.cmd.as_bytes());
+        bytes.put_u32(self.args.len() as u32);
+        for arg in &self.args {
+            put_string(bytes, arg.as_bytes());
+        }
+        bytes.put_u32(self.envs.len() as u32);
+        for (name, value) in &self.envs {
+            put_string(bytes, name.as_bytes());
+            put_string(bytes, value.as_bytes());
+        }
+        match &self.cwd {
+            Some(cwd) => {
+                bytes.put_u8(1);
+                put_string(bytes, cwd.as_os_str().as_bytes());
+            }
+            None => bytes.put_u8(0),
+        }
+        bytes.put_u32(self.path_args.len() as u32);
+        for &index in &self.path_args {
+            bytes.put_u32(index as u32);
+        }
+    }
+
+    /// Decodes either form.
+    pub fn parse(mut bytes: Bytes) -> Result<Self> {
+        if bytes.first() != Some(&EXEC_BINARY) {
+            return Ok(serde_json::from_slice::<JsonExec>(&bytes)?.into());
+        }
+        bytes.advance(1);
+        let os_string = |bytes: &mut Bytes| -> Result<OsString> {
+            Ok(OsString::from_vec(get_string(bytes)?.to_vec()))
+        };
+        let cmd = os_string(&mut bytes)?;
+        let args = (0..get_u32(&mut bytes)?)
+            .map(|_| os_string(&mut bytes))
+            .collect::<Result<_>>()?;
+        let envs = (0..get_u32(&mut bytes)?)
+            .map(|_| Ok((os_string(&mut bytes)?, os_string(&mut bytes)?)))
+            .collect::<Result<_>>()?;
+        if bytes.is_empty() {
+            return Err(anyhow!("malformed exec: missing cwd"));
+        }
+        let cwd = match bytes.get_u8() {
+            0 => None,
+            _ => Some(PathBuf::from(os_string(&mut bytes)?)),
+        };
+        let path_args = (0..get_u32(&mut bytes)?)
+            .map(|_| Ok(get_u32(&mut bytes)? as usize))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            cmd,
+            args,
+            envs,
+            cwd,
+            path_args,
+        })
+    }
+}
+
+fn get_u32(bytes: &mut Bytes) -> Result<u32> {
+    if bytes.len() < size_of::<u32>() {
+        return Err(anyhow!("malformed request: expected a u32"));
+    }
+    Ok(bytes.get_u32())
+}
+
+fn get_u64(bytes: &mut Bytes) -> Result<u64> {
+    if bytes.len() < size_of::<u64>() {
+        return Err(anyhow!("malformed request: expected a u64"));
+    }
+    Ok(bytes.get_u64())
+}
+
+/// The contents of a successful reply to `Exec` or `Verb`. Empty unless
+/// the client can resume the command.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecReply {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resume {
+    pub token: String,
+    /// The first event the client has not seen. Without it the agent sends
+    /// again what it sent last, which the client may have seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+/// The contents of a successful reply to [`Resume`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resumed {
+    /// The number of the first event to come.
+    pub seq: u64,
+    /// Whether the command still takes stdin.
+    pub stdin: bool,
+    /// Whether the command runs on a terminal.
+    #[serde(default)]
+    pub pty: bool,
+}
+
+/// The terminal that a client runs a command from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Terminal {
+    /// `TERM` of the client, for the command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+    pub size: WindowSize,
+}
+
+/// The size of a terminal in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Numbered by the agent from 1, in the order jobs are started.
+pub type JobId = u64;
+
+/// What the agent knows about a detached job. The reply to `Jobs` is a list
+/// of them, and other job requests reply with one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub id: JobId,
+    /// The command line, for display.
+    pub command: String,
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+    /// What `ssh-rev exec` would have exited with; `None` while it runs.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub killed_for: Option<Limit>,
+    /// The signal that killed it, if one did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+}
+
+/// Invokes a verb of the agent's registry instead of a raw command line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerbCall {
+    pub verb: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    #[serde(default)]
     pub envs: HashMap<String, String>,
     pub cwd: Option<String>,
 }
 
+/// The contents of a successful reply to `Hello`. Agents from before
+/// `Hello` reply to it with an empty `SSH_AGENT_EXTENSION_FAILURE`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Protocol versions, by number.
+    pub versions: Vec<u32>,
+    pub opcodes: Vec<u8>,
+    /// Whether the agent takes [`Request::Pty`].
+    #[serde(default)]
+    pub pty: bool,
+    /// Whether the agent passes on [`Request::Signal`].
+    #[serde(default)]
+    pub signals: bool,
+    /// Whether the agent takes deflated stdin, and deflates output for
+    /// clients that ask for it in their `Hello`.
+    #[serde(default)]
+    pub compression: bool,
+    /// Whether `Exec` may be sent in its binary form.
+    #[serde(default)]
+    pub binary_exec: bool,
+    /// Longest message the agent accepts; `Stdin` is split to fit.
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    /// Bytes of stdin that a client doing flow control may send ahead of
+    /// what the command has read; `None` from agents without flow control.
+    #[serde(default)]
+    pub stdin_window: Option<u32>,
+    /// Seconds that a `Watch` waits for output before the agent replies
+    /// with an empty batch, to clients that take heartbeats; `None` from
+    /// agents that send none.
+    #[serde(default)]
+    pub keepalive_secs: Option<u64>,
+    pub agent_version: String,
+    /// As in `std::env::consts::OS`, e.g. `linux` or `macos`.
+    pub os: String,
+}
+
+impl Capabilities {
+    /// What this build of the agent supports with the settings of
+    /// `connection`.
+    pub fn local(connection: &ConnectionConfig) -> Self {
+        Self {
+            versions: Version::ALL
+                .iter()
+                .map(|version| version.number())
+                .collect(),
+            opcodes: vec![
+                OpCode::Exec as u8,
+                OpCode::Stdin as u8,
+                OpCode::Watch as u8,
+                OpCode::Pair as u8,
+                OpCode::Authenticated as u8,
+                OpCode::Verb as u8,
+                OpCode::Hello as u8,
+                OpCode::Detach as u8,
+                OpCode::Jobs as u8,
+                OpCode::Attach as u8,
+                OpCode::Wait as u8,
+                OpCode::Kill as u8,
+                OpCode::Resume as u8,
+                OpCode::Signal as u8,
+                OpCode::Pty as u8,
+                OpCode::Resize as u8,
+                OpCode::Deflated as u8,
+            ],
+            pty: true,
+            signals: true,
+            compression: true,
+            binary_exec: true,
+            max_frame_bytes: connection.max_frame_bytes,
+            stdin_window: Some(connection.stdin_window_bytes),
+            keepalive_secs: connection.keepalive().map(|keepalive| keepalive.as_secs()),
+            agent_version: env!("CARGO_PKG_VERSION").into(),
+            os: std::env::consts::OS.into(),
+        }
+    }
+
+    /// The latest version up to `max` that both sides know.
+    pub fn version(&self, max: Version) -> Option<Version> {
+        self.versions
+            .iter()
+            .filter_map(|&number| Version::from_number(number))
+            .filter(|&version| version <= max)
+            .max()
+    }
+}
+
+fn default_max_frame_bytes() -> usize {
+    MAX_FRAME_BYTES
+}
+
+/// What the client tells the agent along with `Hello`. Agents from before
+/// it ignore it, and clients from before it send nothing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// Whether the client keeps to [`Capabilities::stdin_window`] and takes
+    /// [`Event::WindowAdjust`].
+    #[serde(default)]
+    pub flow_control: bool,
+    /// Whether the client can resume v2 commands with the token of an
+    /// [`ExecReply`].
+    #[serde(default)]
+    pub resume: bool,
+    /// Whether the client takes [`Event::Signaled`]. Others are told of a
+    /// command killed by a signal as `Exited` with the exit code a shell
+    /// would report.
+    #[serde(default)]
+    pub signaled: bool,
+    /// Whether the client takes an empty batch in reply to `Watch` as a
+    /// heartbeat. Only such clients have their connection dropped when
+    /// they go silent.
+    #[serde(default)]
+    pub keepalive: bool,
+    /// How the client wants output deflated, if at all. It deflates stdin
+    /// alike if the agent has [`Capabilities::compression`].
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+/// Which chunks of stdin and output are sent deflated. Either way, a chunk
+/// that does not get smaller is sent as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Only chunks as large as those of bulk transfers, which leaves typing
+    /// and interactive output alone.
+    Auto,
+    /// Every chunk longer than a few bytes.
+    Always,
+}
+
+impl Compression {
+    /// The shortest chunk worth deflating.
+    fn min_bytes(self) -> usize {
+        match self {
+            Compression::Auto => 4 * 1024,
+            Compression::Always => 64,
+        }
+    }
+
+    /// `encoded`, a request or event that carries `len` bytes of stdin or
+    /// output, deflated behind `code` if that is worth it.
+    fn apply(self, code: u8, encoded: Bytes, len: usize) -> Bytes {
+        if len < self.min_bytes() {
+            return encoded;
+        }
+        // the fastest level, so that a fast link is not held up instead
+        let mut encoder = DeflateEncoder::new(
+            BytesMut::from([code].as_slice()).writer(),
+            flate2::Compression::fast(),
+        );
+        let deflated = match encoder.write_all(&encoded).and_then(|()| encoder.finish()) {
+            Ok(writer) => writer.into_inner(),
+            Err(_) => return encoded,
+        };
+        if deflated.len() < encoded.len() {
+            deflated.freeze()
+        } else {
+            encoded
+        }
+    }
+}
+
+/// Inflates what was wrapped behind `code`, which fit in a frame of
+/// `max_frame` bytes before it was deflated and must not be wrapped again.
+fn inflate(code: u8, deflated: &[u8], max_frame: usize) -> Result<Bytes> {
+    let mut inflated = vec![];
+    DeflateDecoder::new(deflated)
+        .take(max_frame as u64 + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() > max_frame {
+        return Err(anyhow!("malformed: inflates to more than a frame"));
+    }
+    if inflated.first() == Some(&code) {
+        return Err(anyhow!("malformed: deflated twice"));
+    }
+    Ok(inflated.into())
+}
+
+/// Asks the agent to pair with a pairing code shown on the local machine.
+/// Binary values are base64 encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pair {
+    pub client_id: String,
+    pub nonce: String,
+    /// HMAC of the client id and nonce keyed by the pairing code.
+    pub proof: String,
+}
+
+/// The contents of a successful reply to [`Pair`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PairReply {
+    pub nonce: String,
+    /// HMAC keyed by the derived secret, proving the agent knew the code.
+    pub proof: String,
+}
+
+/// Wraps another request together with a MAC computed by a paired client.
+#[derive(Debug, Clone)]
+pub struct Auth {
+    pub client_id: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub nonce: Bytes,
+    pub inner: Bytes,
+    /// HMAC over [`Auth::signed_payload`] keyed by the pairing secret.
+    pub mac: Bytes,
+}
+
+impl Auth {
+    pub fn signed_payload(client_id: &str, timestamp: u64, nonce: &[u8], inner: &[u8]) -> Bytes {
+        let mut bytes = BytesMut::new();
+        put_string(&mut bytes, client_id.as_bytes());
+        bytes.put_u64(timestamp);
+        put_string(&mut bytes, nonce);
+        put_string(&mut bytes, inner);
+        bytes.freeze()
+    }
+}
+
 impl Request {
     pub fn into_bytes(self) -> Result<Bytes> {
         match self {
             Request::Exec(exec) => Self::exec(&exec),
             Request::Stdin(stdin) => Ok(Self::stdin(stdin)),
-            Request::Watch => Ok(Self::watch()),
+            Request::Watch(window) => Ok(Self::watch(window)),
+            Request::Pair(pair) => Self::pair(&pair),
+            Request::Authenticated(auth) => Ok(Self::authenticated(&auth)),
+            Request::Verb(call) => Self::verb(&call),
+            Request::Hello(hello) => Self::hello(&hello),
+            Request::Detach(inner) => Ok(Self::detach(inner)),
+            Request::Jobs => Ok(Bytes::from([OpCode::Jobs as u8].as_slice())),
+            Request::Attach(id) => Ok(Self::job(OpCode::Attach, id)),
+            Request::Wait(id) => Ok(Self::job(OpCode::Wait, id)),
+            Request::Kill(id) => Ok(Self::job(OpCode::Kill, id)),
+            Request::Resume(resume) => Self::resume(&resume),
+            Request::Signal(signal) => Ok(Bytes::copy_from_slice(&[
+                OpCode::Signal as u8,
+                signal as u8,
+            ])),
+            Request::Pty(terminal) => Self::pty(&terminal),
+            Request::Resize(size) => Ok(Self::resize(size)),
         }
     }
 
     pub fn exec(exec: &Exec) -> Result<Bytes> {
         let mut bytes = BytesMut::from([OpCode::Exec as u8].as_slice());
-        serde_json::to_writer((&mut bytes).writer(), exec)?;
+        exec.to_binary(&mut bytes);
+        Ok(bytes.freeze())
+    }
+
+    /// Like [`Request::into_bytes`], but with `Exec` in the JSON form that
+    /// agents from before [`Capabilities::binary_exec`] expect.
+    pub fn into_legacy_bytes(self) -> Result<Bytes> {
+        let Request::Exec(exec) = &self else {
+            return self.into_bytes();
+        };
+        let mut bytes = BytesMut::from([OpCode::Exec as u8].as_slice());
+        serde_json::to_writer((&mut bytes).writer(), &JsonExec::try_from(exec)?)?;
         Ok(bytes.freeze())
     }
 
@@ -65,27 +690,271 @@ impl Request {
         bytes.freeze()
     }
 
-    pub fn watch() -> Bytes {
-        Bytes::from([OpCode::Watch as u8].as_slice())
+    /// Encodes `Stdin`, deflated as `compression` says.
+    pub fn compressed_stdin(stdin: Bytes, compression: Option<Compression>) -> Bytes {
+        let len = stdin.len();
+        let encoded = Self::stdin(stdin);
+        match compression {
+            Some(compression) => compression.apply(OpCode::Deflated as u8, encoded, len),
+            None => encoded,
+        }
+    }
+
+    pub fn watch(window: Option<u32>) -> Bytes {
+        let mut bytes = BytesMut::from([OpCode::Watch as u8].as_slice());
+        if let Some(window) = window {
+            bytes.put_u32(window);
+        }
+        bytes.freeze()
+    }
+
+    pub fn pair(pair: &Pair) -> Result<Bytes> {
+        let mut bytes = BytesMut::from([OpCode::Pair as u8].as_slice());
+        serde_json::to_writer((&mut bytes).writer(), pair)?;
+        Ok(bytes.freeze())
+    }
+
+    pub fn verb(call: &VerbCall) -> Result<Bytes> {
+        let mut bytes = BytesMut::from([OpCode::Verb as u8].as_slice());
+        serde_json::to_writer((&mut bytes).writer(), call)?;
+        Ok(bytes.freeze())
+    }
+
+    pub fn hello(hello: &ClientHello) -> Result<Bytes> {
+        let mut bytes = BytesMut::from([OpCode::Hello as u8].as_slice());
+        serde_json::to_writer((&mut bytes).writer(), hello)?;
+        Ok(bytes.freeze())
+    }
+
+    pub fn detach(inner: Bytes) -> Bytes {
+        let mut bytes = BytesMut::from([OpCode::Detach as u8].as_slice());
+        bytes.put(inner);
+        bytes.freeze()
+    }
+
+    /// Encodes a request about the job `id`.
+    pub fn job(opcode: OpCode, id: JobId) -> Bytes {
+        let mut bytes = BytesMut::from([opcode as u8].as_slice());
+        bytes.put_u64(id);
+        bytes.freeze()
+    }
+
+    pub fn resume(resume: &Resume) -> Result<Bytes> {
+        let mut bytes = BytesMut::from([OpCode::Resume as u8].as_slice());
+        serde_json::to_writer((&mut bytes).writer(), resume)?;
+        Ok(bytes.freeze())
+    }
+
+    pub fn pty(terminal: &Terminal) -> Result<Bytes> {
+        let mut bytes = BytesMut::from([OpCode::Pty as u8].as_slice());
+        serde_json::to_writer((&mut bytes).writer(), terminal)?;
+        Ok(bytes.freeze())
+    }
+
+    pub fn resize(size: WindowSize) -> Bytes {
+        let mut bytes = BytesMut::from([OpCode::Resize as u8].as_slice());
+        bytes.put_u16(size.cols);
+        bytes.put_u16(size.rows);
+        bytes.freeze()
+    }
+
+    pub fn authenticated(auth: &Auth) -> Bytes {
+        let mut bytes = BytesMut::from([OpCode::Authenticated as u8].as_slice());
+        bytes.put(Auth::signed_payload(
+            &auth.client_id,
+            auth.timestamp,
+            &auth.nonce,
+            &auth.inner,
+        ));
+        put_string(&mut bytes, &auth.mac);
+        bytes.freeze()
     }
 }
 
 impl TryFrom<Bytes> for Request {
     type Error = anyhow::Error;
 
-    fn try_from(mut bytes: Bytes) -> Result<Self, Self::Error> {
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        Request::decode(bytes, MAX_FRAME_BYTES)
+    }
+}
+
+impl Request {
+    /// Like [`Request::try_from`], but for an agent whose frames are at
+    /// most `max_frame` bytes, which a deflated request can't inflate past.
+    pub fn decode(mut bytes: Bytes, max_frame: usize) -> Result<Self> {
         if bytes.is_empty() {
             return Err(anyhow!("content must not be empty"));
         }
         let code = bytes.split_to(1);
         match OpCode::try_from(code[0])? {
-            OpCode::Exec => Ok(Request::Exec(serde_json::from_slice(&bytes)?)),
+            OpCode::Exec => Ok(Request::Exec(Exec::parse(bytes)?)),
             OpCode::Stdin => Ok(Request::Stdin(bytes)),
-            OpCode::Watch => Ok(Request::Watch),
+            OpCode::Watch => match bytes.len() {
+                0 => Ok(Request::Watch(None)),
+                4 => Ok(Request::Watch(Some(bytes.get_u32()))),
+                _ => Err(anyhow!("malformed request: window must be a u32")),
+            },
+            OpCode::Pair => Ok(Request::Pair(serde_json::from_slice(&bytes)?)),
+            OpCode::Authenticated => {
+                let client_id = String::from_utf8(get_string(&mut bytes)?.to_vec())?;
+                if bytes.len() < size_of::<u64>() {
+                    return Err(anyhow!("malformed request: timestamp must be a u64"));
+                }
+                let timestamp = bytes.get_u64();
+                let nonce = get_string(&mut bytes)?;
+                let inner = get_string(&mut bytes)?;
+                let mac = get_string(&mut bytes)?;
+                Ok(Request::Authenticated(Auth {
+                    client_id,
+                    timestamp,
+                    nonce,
+                    inner,
+                    mac,
+                }))
+            }
+            OpCode::Verb => Ok(Request::Verb(serde_json::from_slice(&bytes)?)),
+            OpCode::Hello if bytes.is_empty() => Ok(Request::Hello(ClientHello::default())),
+            OpCode::Hello => Ok(Request::Hello(serde_json::from_slice(&bytes)?)),
+            OpCode::Detach => Ok(Request::Detach(bytes)),
+            OpCode::Jobs => Ok(Request::Jobs),
+            OpCode::Attach => Ok(Request::Attach(get_u64(&mut bytes)?)),
+            OpCode::Wait => Ok(Request::Wait(get_u64(&mut bytes)?)),
+            OpCode::Kill => Ok(Request::Kill(get_u64(&mut bytes)?)),
+            OpCode::Resume => Ok(Request::Resume(serde_json::from_slice(&bytes)?)),
+            OpCode::Signal => {
+                if bytes.is_empty() {
+                    return Err(anyhow!("malformed request: signal must be a u8"));
+                }
+                Ok(Request::Signal(Signal::try_from(bytes.get_u8())?))
+            }
+            OpCode::Pty => Ok(Request::Pty(serde_json::from_slice(&bytes)?)),
+            OpCode::Resize => {
+                if bytes.len() != 4 {
+                    return Err(anyhow!("malformed request: size must be two u16"));
+                }
+                let cols = bytes.get_u16();
+                let rows = bytes.get_u16();
+                Ok(Request::Resize(WindowSize { cols, rows }))
+            }
+            OpCode::Deflated => Request::decode(
+                inflate(OpCode::Deflated as u8, &bytes, max_frame)?,
+                max_frame,
+            ),
+        }
+    }
+}
+
+/// Sent as the contents of `SSH_AGENT_EXTENSION_FAILURE` when the agent
+/// refuses a request on purpose, or cannot run the command, so that clients
+/// can tell it apart from a plain failure.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rejection {
+    pub kind: RejectionKind,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// Set for refusals that may succeed if the request is sent again later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+    /// The error number of the system call that failed, if one did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errno: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionKind {
+    Policy,
+    DeniedByUser,
+    Unpaired,
+    Env,
+    /// The agent is running too many commands; try again later.
+    Busy,
+    /// The verb is unknown or its parameters are invalid.
+    Verb,
+    /// The job does not exist, or was started by someone else.
+    UnknownJob,
+    /// Nothing can be resumed with the token: it is wrong, or the grace
+    /// period is over.
+    UnknownToken,
+    /// The command could not be started, e.g. because it does not exist.
+    Spawn,
+    /// The working directory does not exist or is no directory.
+    Cwd,
+    /// The agent failed to read the output of the command or to wait for
+    /// it, and killed it.
+    Failed,
+    #[serde(other)]
+    Unknown,
+}
+
+impl Rejection {
+    /// A rejection that names no rule, is not to be retried and comes from
+    /// no failed system call.
+    pub fn new(kind: RejectionKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            rule: None,
+            retry_after_ms: None,
+            errno: None,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.kind == RejectionKind::Busy
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after_ms.map(Duration::from_millis)
+    }
+
+    /// What `ssh-rev exec` exits with: 126 or 127 like a shell that cannot
+    /// run a command, otherwise a code from sysexits.h.
+    pub fn exit_code(&self) -> i32 {
+        match self.kind {
+            RejectionKind::Spawn if self.errno == Some(libc::ENOENT) => 127,
+            RejectionKind::Spawn | RejectionKind::Cwd => 126,
+            // EX_TEMPFAIL
+            RejectionKind::Busy => 75,
+            // EX_NOPERM
+            RejectionKind::Policy
+            | RejectionKind::DeniedByUser
+            | RejectionKind::Unpaired
+            | RejectionKind::Env => 77,
+            // EX_USAGE
+            RejectionKind::Verb | RejectionKind::UnknownJob | RejectionKind::UnknownToken => 64,
+            // EX_IOERR
+            RejectionKind::Failed => 74,
+            RejectionKind::Unknown => 1,
         }
     }
+
+    pub fn into_message(self) -> Result<Message> {
+        Ok(Message {
+            message_type: SSH_AGENT_EXTENSION_FAILURE,
+            contents: serde_json::to_vec(&self)?.into(),
+        })
+    }
 }
 
+impl TryFrom<Bytes> for Rejection {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
 #[derive(TryFromPrimitive)]
 #[repr(u8)]
 pub enum EventCode {
@@ -93,22 +962,243 @@ pub enum EventCode {
     Stdout = 1,
     Stderr = 2,
     Exited = 3,
+    LimitExceeded = 4,
+    WindowAdjust = 5,
+    Signaled = 6,
+    /// Wraps another event, deflated. It is decoded as that event.
+    Deflated = 7,
 }
 
+#[derive(Clone)]
 pub enum Event {
     Cancelled,
     Stdout(Bytes),
     Stderr(Bytes),
     Exited(i32),
+    LimitExceeded(Limit),
+    /// The command read this many more bytes of stdin, so the client may
+    /// send as many more. Only sent to clients that do flow control.
+    WindowAdjust(u32),
+    /// The command was killed by `signal`.
+    Signaled {
+        signal: i32,
+        core_dumped: bool,
+    },
+}
+
+/// The contents of a successful reply to `Watch` in v2: consecutive events
+/// numbered from `seq`. A batch may be empty when the agent replies early
+/// to keep the replies to later requests flowing.
+#[derive(Default)]
+pub struct EventBatch {
+    pub seq: u64,
+    pub events: Vec<Event>,
+}
+
+impl EventBatch {
+    pub fn into_bytes(self) -> Bytes {
+        self.into_compressed_bytes(None)
+    }
+
+    /// Encodes the batch with output deflated as `compression` says.
+    pub fn into_compressed_bytes(self, compression: Option<Compression>) -> Bytes {
+        let mut bytes = BytesMut::new();
+        bytes.put_u64(self.seq);
+        bytes.put_u32(self.events.len() as u32);
+        for event in self.events {
+            put_string(&mut bytes, &event.into_compressed_bytes(compression));
+        }
+        bytes.freeze()
+    }
+}
+
+impl TryFrom<Bytes> for EventBatch {
+    type Error = anyhow::Error;
+
+    fn try_from(mut bytes: Bytes) -> Result<Self, Self::Error> {
+        if bytes.len() < size_of::<u64>() + size_of::<u32>() {
+            return Err(anyhow!("malformed event batch"));
+        }
+        let seq = bytes.get_u64();
+        let count = bytes.get_u32();
+        let events = (0..count)
+            .map(|_| Event::try_from(get_string(&mut bytes)?))
+            .collect::<Result<_>>()?;
+        Ok(Self { seq, events })
+    }
+}
+
+/// The contents of a successful reply to `Watch` in v3: like
+/// [`EventBatch`], but each event comes with the session it belongs to.
+#[derive(Default)]
+pub struct SessionEventBatch {
+    pub seq: u64,
+    pub events: Vec<(SessionId, Event)>,
+}
+
+impl SessionEventBatch {
+    /// Encodes the batch with output deflated as `compression` says.
+    pub fn into_compressed_bytes(self, compression: Option<Compression>) -> Bytes {
+        let mut bytes = BytesMut::new();
+        bytes.put_u64(self.seq);
+        bytes.put_u32(self.events.len() as u32);
+        for (session, event) in self.events {
+            bytes.put_u32(session);
+            put_string(&mut bytes, &event.into_compressed_bytes(compression));
+        }
+        bytes.freeze()
+    }
+}
+
+impl TryFrom<Bytes> for SessionEventBatch {
+    type Error = anyhow::Error;
+
+    fn try_from(mut bytes: Bytes) -> Result<Self, Self::Error> {
+        if bytes.len() < size_of::<u64>() + size_of::<u32>() {
+            return Err(anyhow!("malformed event batch"));
+        }
+        let seq = bytes.get_u64();
+        let count = bytes.get_u32();
+        let mut events = vec![];
+        for _ in 0..count {
+            if bytes.len() < size_of::<SessionId>() {
+                return Err(anyhow!("malformed event batch: session must be a u32"));
+            }
+            let session = bytes.get_u32();
+            events.push((session, Event::try_from(get_string(&mut bytes)?)?));
+        }
+        Ok(Self { seq, events })
+    }
+}
+
+/// A signal that clients pass on to the command. Numbered apart from
+/// either platform, since their numbers differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Signal {
+    Hup = 0,
+    Int = 1,
+    Quit = 2,
+    Term = 3,
+    Tstp = 4,
+    Cont = 5,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 6] = [
+        Signal::Hup,
+        Signal::Int,
+        Signal::Quit,
+        Signal::Term,
+        Signal::Tstp,
+        Signal::Cont,
+    ];
+
+    /// The number of the signal on this platform.
+    pub fn number(self) -> i32 {
+        match self {
+            Signal::Hup => libc::SIGHUP,
+            Signal::Int => libc::SIGINT,
+            Signal::Quit => libc::SIGQUIT,
+            Signal::Term => libc::SIGTERM,
+            Signal::Tstp => libc::SIGTSTP,
+            Signal::Cont => libc::SIGCONT,
+        }
+    }
+}
+
+/// A resource limit that got a command killed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, Serialize, Deserialize)]
+#[repr(u8)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    Timeout = 0,
+    Cpu = 1,
+    Output = 2,
+}
+
+impl Limit {
+    /// What `ssh-rev exec` exits with: 124 like timeout(1), otherwise as if
+    /// killed by `SIGXCPU` or `SIGXFSZ`.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Limit::Timeout => 124,
+            Limit::Cpu => 128 + 24,
+            Limit::Output => 128 + 25,
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = match self {
+            Limit::Timeout => "its time limit",
+            Limit::Cpu => "its CPU time limit",
+            Limit::Output => "its output size limit",
+        };
+        write!(f, "command was killed for exceeding {}", limit)
+    }
 }
 
 impl Event {
+    /// Bytes of output the event carries.
+    pub fn data_len(&self) -> usize {
+        match self {
+            Event::Stdout(bytes) | Event::Stderr(bytes) => bytes.len(),
+            _ => 0,
+        }
+    }
+
+    /// Whether the event is the last of a command.
+    pub fn is_last(&self) -> bool {
+        matches!(
+            self,
+            Event::Exited(_) | Event::LimitExceeded(_) | Event::Signaled { .. }
+        )
+    }
+
+    /// What `ssh-rev exec` exits with after the event, if it is the last.
+    /// A signal makes it 128 plus the signal, as in shells.
+    pub fn exit_code(&self) -> Option<i32> {
+        match *self {
+            Event::Exited(code) => Some(code),
+            Event::LimitExceeded(limit) => Some(limit.exit_code()),
+            Event::Signaled { signal, .. } => Some(128 + signal),
+            _ => None,
+        }
+    }
+
+    /// The event as told to clients that do not take [`Event::Signaled`].
+    pub fn without_signal(self) -> Event {
+        match self {
+            Event::Signaled { .. } => Event::Exited(self.exit_code().unwrap()),
+            event => event,
+        }
+    }
+
     pub fn into_bytes(self) -> Bytes {
         match self {
             Event::Cancelled => Self::cancelled(),
             Event::Stdout(stdout) => Self::stdout(&stdout),
             Event::Stderr(stderr) => Self::stderr(&stderr),
             Event::Exited(status) => Self::exited(status),
+            Event::LimitExceeded(limit) => Self::limit_exceeded(limit),
+            Event::WindowAdjust(bytes) => Self::window_adjust(bytes),
+            Event::Signaled {
+                signal,
+                core_dumped,
+            } => Self::signaled(signal, core_dumped),
+        }
+    }
+
+    /// Like [`Event::into_bytes`], but with output deflated as
+    /// `compression` says.
+    pub fn into_compressed_bytes(self, compression: Option<Compression>) -> Bytes {
+        let len = self.data_len();
+        let encoded = self.into_bytes();
+        match compression {
+            Some(compression) => compression.apply(EventCode::Deflated as u8, encoded, len),
+            None => encoded,
         }
     }
 
@@ -133,6 +1223,23 @@ impl Event {
         bytes.put_i32(status);
         bytes.freeze()
     }
+
+    pub fn limit_exceeded(limit: Limit) -> Bytes {
+        Bytes::copy_from_slice(&[EventCode::LimitExceeded as u8, limit as u8])
+    }
+
+    pub fn window_adjust(bytes: u32) -> Bytes {
+        let mut encoded = BytesMut::from([EventCode::WindowAdjust as u8].as_slice());
+        encoded.put_u32(bytes);
+        encoded.freeze()
+    }
+
+    pub fn signaled(signal: i32, core_dumped: bool) -> Bytes {
+        let mut bytes = BytesMut::from([EventCode::Signaled as u8].as_slice());
+        bytes.put_i32(signal);
+        bytes.put_u8(core_dumped as u8);
+        bytes.freeze()
+    }
 }
 
 impl TryFrom<Bytes> for Event {
@@ -153,6 +1260,32 @@ impl TryFrom<Bytes> for Event {
                 }
                 Ok(Event::Exited(bytes.get_i32()))
             }
+            EventCode::LimitExceeded => {
+                if bytes.is_empty() {
+                    return Err(anyhow!("malformed event: limit must be a u8"));
+                }
+                Ok(Event::LimitExceeded(Limit::try_from(bytes.get_u8())?))
+            }
+            EventCode::WindowAdjust => {
+                if bytes.len() < size_of::<u32>() {
+                    return Err(anyhow!("malformed event: window adjustment must be a u32"));
+                }
+                Ok(Event::WindowAdjust(bytes.get_u32()))
+            }
+            EventCode::Signaled => {
+                if bytes.len() < size_of::<i32>() + 1 {
+                    return Err(anyhow!(
+                        "malformed event: signal must be an i32 and a core dump flag"
+                    ));
+                }
+                Ok(Event::Signaled {
+                    signal: bytes.get_i32(),
+                    core_dumped: bytes.get_u8() != 0,
+                })
+            }
+            EventCode::Deflated => {
+                Event::try_from(inflate(EventCode::Deflated as u8, &bytes, MAX_FRAME_BYTES)?)
+            }
         }
     }
 }
@@ -166,4 +1299,160 @@ mod tests {
         let content_bytes = Event::stdout(b"hello");
         assert_eq!(b"\x01hello", &*content_bytes);
     }
+
+    #[test]
+    fn test_signaled() {
+        let event = Event::try_from(Event::signaled(libc::SIGSEGV, true)).unwrap();
+        assert!(matches!(
+            event,
+            Event::Signaled {
+                signal: libc::SIGSEGV,
+                core_dumped: true
+            }
+        ));
+        assert_eq!(event.exit_code(), Some(139));
+        assert!(matches!(event.without_signal(), Event::Exited(139)));
+    }
+
+    #[test]
+    fn test_compression() {
+        let text = Bytes::from("a line of build output\n".repeat(1000));
+        let deflated = Request::compressed_stdin(text.clone(), Some(Compression::Auto));
+        assert_eq!(deflated[0], OpCode::Deflated as u8);
+        assert!(deflated.len() < text.len() / 10);
+        assert!(
+            matches!(Request::try_from(deflated).unwrap(), Request::Stdin(stdin) if stdin == text)
+        );
+
+        // typing is left alone unless asked for, and so is what does not shrink
+        let typed = Request::compressed_stdin("ls\n".into(), Some(Compression::Always));
+        assert_eq!(&typed[..], b"\x01ls\n");
+        let interactive = Event::Stdout(text.slice(..1000));
+        let encoded = interactive.into_compressed_bytes(Some(Compression::Auto));
+        assert_eq!(encoded[0], EventCode::Stdout as u8);
+        let mut noise = vec![0; 8 * 1024];
+        getrandom::getrandom(&mut noise).unwrap();
+        let encoded = Event::Stdout(noise.into()).into_compressed_bytes(Some(Compression::Auto));
+        assert_eq!(encoded[0], EventCode::Stdout as u8);
+
+        let encoded = Event::Stderr(text.clone()).into_compressed_bytes(Some(Compression::Auto));
+        assert_eq!(encoded[0], EventCode::Deflated as u8);
+        assert!(
+            matches!(Event::try_from(encoded).unwrap(), Event::Stderr(stderr) if stderr == text)
+        );
+
+        // nothing inflates beyond what a frame could have carried
+        let zeros = Event::stdout(&vec![0; MAX_FRAME_BYTES]);
+        let bomb = Compression::Always.apply(EventCode::Deflated as u8, zeros, MAX_FRAME_BYTES);
+        assert_eq!(bomb[0], EventCode::Deflated as u8);
+        assert!(Event::try_from(bomb).is_err());
+        let stdin = Request::compressed_stdin(vec![0; 1024].into(), Some(Compression::Always));
+        assert_eq!(stdin[0], OpCode::Deflated as u8);
+        assert!(Request::decode(stdin.clone(), 1024).is_err());
+        assert!(Request::decode(stdin, 1025).is_ok());
+    }
+
+    #[test]
+    fn test_exec_encodings() {
+        let exec = Exec {
+            args: vec![OsString::from_vec(b"caf\xe9.txt".to_vec())],
+            envs: [("LANG".into(), "C".into())].into_iter().collect(),
+            cwd: Some("/tmp".into()),
+            path_args: vec![0],
+            ..Exec::new("cat", &[])
+        };
+        let Request::Exec(decoded) = Request::try_from(Request::exec(&exec).unwrap()).unwrap()
+        else {
+            panic!("not an exec");
+        };
+        assert_eq!(decoded.args, exec.args);
+        assert_eq!(decoded.envs, exec.envs);
+        assert_eq!(decoded.cwd, exec.cwd);
+        assert_eq!(decoded.path_args, exec.path_args);
+        assert!(Request::Exec(exec).into_legacy_bytes().is_err());
+
+        let json =
+            Bytes::from_static(b"\x00{\"cmd\":\"ls\",\"args\":[\"-l\"],\"envs\":{},\"cwd\":null}");
+        let Request::Exec(decoded) = Request::try_from(json).unwrap() else {
+            panic!("not an exec");
+        };
+        assert_eq!(
+            (decoded.cmd, decoded.args),
+            ("ls".into(), vec!["-l".into()])
+        );
+    }
+
+    #[test]
+    fn test_rejection_exit_code() {
+        let exit_code = |kind, errno| {
+            Rejection {
+                errno,
+                ..Rejection::new(kind, "")
+            }
+            .exit_code()
+        };
+        assert_eq!(exit_code(RejectionKind::Spawn, Some(libc::ENOENT)), 127);
+        assert_eq!(exit_code(RejectionKind::Spawn, Some(libc::EACCES)), 126);
+        assert_eq!(exit_code(RejectionKind::Cwd, Some(libc::ENOENT)), 126);
+        assert_eq!(exit_code(RejectionKind::Busy, None), 75);
+        for kind in [
+            RejectionKind::Policy,
+            RejectionKind::DeniedByUser,
+            RejectionKind::Unpaired,
+            RejectionKind::Env,
+        ] {
+            assert_eq!(exit_code(kind, None), 77);
+        }
+        for kind in [
+            RejectionKind::Verb,
+            RejectionKind::UnknownJob,
+            RejectionKind::UnknownToken,
+        ] {
+            assert_eq!(exit_code(kind, None), 64);
+        }
+        assert_eq!(exit_code(RejectionKind::Failed, Some(libc::EIO)), 74);
+        // kinds from newer agents
+        let rejection = Rejection::try_from(Bytes::from(r#"{"kind":"new","message":""}"#));
+        assert_eq!(rejection.unwrap().exit_code(), 1);
+    }
+
+    #[test]
+    fn test_capabilities_version() {
+        let mut capabilities = Capabilities::local(&ConnectionConfig::default());
+        assert_eq!(capabilities.version(Version::V3), Some(Version::V3));
+        assert_eq!(capabilities.version(Version::V2), Some(Version::V2));
+        capabilities.versions = vec![1, 99];
+        assert_eq!(capabilities.version(Version::V3), Some(Version::V1));
+        capabilities.versions = vec![99];
+        assert_eq!(capabilities.version(Version::V3), None);
+    }
+
+    #[test]
+    fn test_event_batch() {
+        let batch = EventBatch {
+            seq: 7,
+            events: vec![Event::Stdout("hello".into()), Event::Exited(3)],
+        };
+        let batch = EventBatch::try_from(batch.into_bytes()).unwrap();
+        assert_eq!(batch.seq, 7);
+        assert!(
+            matches!(&batch.events[..], [Event::Stdout(out), Event::Exited(3)] if out == "hello")
+        );
+
+        let batch = SessionEventBatch {
+            seq: 7,
+            events: vec![
+                (1, Event::Stdout("hello".into())),
+                (1, Event::WindowAdjust(5)),
+                (0, Event::Exited(3)),
+            ],
+        };
+        let batch = SessionEventBatch::try_from(batch.into_compressed_bytes(None)).unwrap();
+        assert_eq!(batch.seq, 7);
+        assert!(matches!(
+            &batch.events[..],
+            [(1, Event::Stdout(out)), (1, Event::WindowAdjust(5)), (0, Event::Exited(3))]
+                if out == "hello"
+        ));
+    }
 }
@@ -18,6 +18,13 @@ impl Message {
         }
     }
 
+    pub fn success() -> Self {
+        Self {
+            message_type: SSH_AGENT_SUCCESS,
+            contents: Bytes::new(),
+        }
+    }
+
     pub fn extension_failure() -> Self {
         Self {
             message_type: SSH_AGENT_EXTENSION_FAILURE,
@@ -50,6 +57,80 @@ impl TryFrom<Bytes> for Extension {
     }
 }
 
+/// `session-bind@openssh.com`, sent by OpenSSH clients to tell the agent
+/// which host a connection is bound to.
+/// https://github.com/openssh/openssh-portable/blob/master/PROTOCOL.agent
+pub struct SessionBind {
+    pub host_key: Bytes,
+    pub session_id: Bytes,
+    pub signature: Bytes,
+    pub is_forwarding: bool,
+}
+
+impl TryFrom<Bytes> for SessionBind {
+    type Error = anyhow::Error;
+
+    fn try_from(mut bytes: Bytes) -> Result<Self, Self::Error> {
+        let host_key = get_string(&mut bytes)?;
+        let session_id = get_string(&mut bytes)?;
+        let signature = get_string(&mut bytes)?;
+        if bytes.is_empty() {
+            return Err(anyhow!("session-bind is missing is_forwarding"));
+        }
+        let is_forwarding = bytes.get_u8() != 0;
+        Ok(Self {
+            host_key,
+            session_id,
+            signature,
+            is_forwarding,
+        })
+    }
+}
+
+/// The contents of a successful reply to the `query` extension: the
+/// extension types the agent supports.
+/// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#section-4.7.1
+pub struct QueryReply(pub Vec<Bytes>);
+
+impl TryFrom<Bytes> for QueryReply {
+    type Error = anyhow::Error;
+
+    fn try_from(mut bytes: Bytes) -> Result<Self, Self::Error> {
+        let mut extension_types = vec![];
+        while !bytes.is_empty() {
+            extension_types.push(get_string(&mut bytes)?);
+        }
+        Ok(Self(extension_types))
+    }
+}
+
+impl From<QueryReply> for Bytes {
+    fn from(reply: QueryReply) -> Self {
+        let mut bytes = BytesMut::new();
+        for extension_type in &reply.0 {
+            put_string(&mut bytes, extension_type);
+        }
+        bytes.freeze()
+    }
+}
+
+/// Reads an SSH `string`: a u32 length followed by that many bytes.
+pub fn get_string(bytes: &mut Bytes) -> anyhow::Result<Bytes> {
+    if bytes.len() < size_of::<u32>() {
+        return Err(anyhow!("string is too short for its length"));
+    }
+    let len = bytes.get_u32() as usize;
+    if bytes.len() < len {
+        return Err(anyhow!("length of string is mismatch"));
+    }
+    Ok(bytes.split_to(len))
+}
+
+pub fn put_string(bytes: &mut BytesMut, string: &[u8]) {
+    bytes.put_u32(string.len() as u32);
+    bytes.put_slice(string);
+}
+
 impl From<Extension> for Bytes {
     fn from(ext: Extension) -> Self {
         let mut bytes = BytesMut::with_capacity(4 + ext.extension_type.len() + ext.contents.len());
@@ -60,7 +141,32 @@ impl From<Extension> for Bytes {
     }
 }
 
-pub struct Codec;
+/// OpenSSH's `AGENT_MAX_LEN`: agents and clients drop connections that send
+/// longer messages.
+pub const MAX_FRAME_BYTES: usize = 256 * 1024;
+
+/// Room for the headers of a message that carries a chunk of data, so that
+/// chunks of `max_frame - FRAME_OVERHEAD` bytes always fit in a frame.
+pub const FRAME_OVERHEAD: usize = 64;
+
+/// Frames messages with their u32 length, refusing frames longer than
+/// `max_frame` bytes in either direction.
+pub struct Codec {
+    max_frame: usize,
+}
+
+impl Codec {
+    pub fn new(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(MAX_FRAME_BYTES)
+    }
+}
+
 impl Decoder for Codec {
     type Item = Message;
     type Error = io::Error;
@@ -72,12 +178,24 @@ impl Decoder for Codec {
         }
         let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
         if len == 0 {
+            return Err(io::Error::other("message length must not be zero"));
+        }
+        if len > self.max_frame {
+            log::warn!(
+                "Refused a message of {} bytes; the limit is {}",
+                len,
+                self.max_frame
+            );
             return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "message length must not be zero",
+                io::ErrorKind::InvalidData,
+                format!(
+                    "message of {} bytes exceeds the limit of {}",
+                    len, self.max_frame
+                ),
             ));
         }
         if src.len() < len + size_of::<u32>() {
+            src.reserve(len + size_of::<u32>() - src.len());
             return Ok(None);
         }
         src.advance(4);
@@ -95,16 +213,88 @@ impl<'a> Encoder<&'a Message> for Codec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: &'a Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let len = (msg.contents.len() + 1) as u32;
-        let len_bytes = len.to_be_bytes();
-        dst.put_slice(&len_bytes);
+        let len = msg.contents.len() + 1;
+        if len > self.max_frame {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message of {} bytes exceeds the limit of {}",
+                    len, self.max_frame
+                ),
+            ));
+        }
+        dst.put_u32(len as u32);
         dst.put_u8(msg.message_type);
         dst.put_slice(&msg.contents);
         Ok(())
     }
 }
 
+/// The largest chunk of data to put in a message when the peer accepts
+/// frames of up to `max_frame` bytes.
+pub fn max_chunk(max_frame: usize) -> usize {
+    max_frame
+        .min(MAX_FRAME_BYTES)
+        .saturating_sub(FRAME_OVERHEAD)
+        .max(1)
+}
+
+/// Splits `bytes` into chunks of at most `size` bytes.
+pub fn chunks(mut bytes: Bytes, size: usize) -> impl Iterator<Item = Bytes> {
+    std::iter::from_fn(move || (!bytes.is_empty()).then(|| bytes.split_to(size.min(bytes.len()))))
+}
+
 pub const SSH_AGENT_FAILURE: u8 = 5;
 pub const SSH_AGENT_SUCCESS: u8 = 6;
 pub const SSH_AGENTC_EXTENSION: u8 = 27;
 pub const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
+pub const SESSION_BIND_EXTENSION: &[u8] = b"session-bind@openssh.com";
+pub const QUERY_EXTENSION: &[u8] = b"query";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_max_frame() {
+        let mut codec = Codec::new(16);
+        let mut dst = BytesMut::new();
+        let message = Message {
+            message_type: SSH_AGENT_SUCCESS,
+            contents: Bytes::from_static(&[0; 15]),
+        };
+        codec.encode(&message, &mut dst).unwrap();
+        assert_eq!(codec.decode(&mut dst).unwrap().unwrap().contents.len(), 15);
+
+        let message = Message {
+            message_type: SSH_AGENT_SUCCESS,
+            contents: Bytes::from_static(&[0; 16]),
+        };
+        assert!(codec.encode(&message, &mut dst).is_err());
+        // the length alone is enough to refuse it
+        let mut src = BytesMut::from(&17u32.to_be_bytes()[..]);
+        assert!(codec.decode(&mut src).is_err());
+
+        let chunks: Vec<_> = chunks(Bytes::from_static(b"abcde"), 2).collect();
+        assert_eq!(chunks, vec!["ab", "cd", "e"]);
+    }
+
+    #[test]
+    fn test_session_bind() {
+        let mut bytes = BytesMut::new();
+        put_string(&mut bytes, b"host key");
+        put_string(&mut bytes, b"session id");
+        put_string(&mut bytes, b"signature");
+        let without_flag = bytes.clone().freeze();
+        bytes.put_u8(1);
+        let bind = SessionBind::try_from(bytes.freeze()).unwrap();
+        assert_eq!(&bind.host_key[..], b"host key");
+        assert_eq!(&bind.session_id[..], b"session id");
+        assert_eq!(&bind.signature[..], b"signature");
+        assert!(bind.is_forwarding);
+
+        assert!(SessionBind::try_from(without_flag.clone()).is_err());
+        assert!(SessionBind::try_from(without_flag.slice(..20)).is_err());
+    }
+}
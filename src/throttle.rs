@@ -0,0 +1,303 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    sync::Notify,
+    time::{self, Instant},
+};
+
+use crate::{
+    caller::Caller,
+    policy,
+    rpc::{Exec, Rejection, RejectionKind},
+};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Bounds how many commands run at once and how often they are started.
+/// Requests over a limit are refused as busy, which clients may retry.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Commands running at once across all callers.
+    pub max_running: Option<usize>,
+    /// Commands running at once for a single caller. See [`caller_key`] for
+    /// who counts as one.
+    pub max_running_per_caller: Option<usize>,
+    /// Commands started per minute across all callers.
+    pub max_per_minute: Option<usize>,
+    /// Commands started per minute for a single caller.
+    pub max_per_minute_per_caller: Option<usize>,
+    /// How long a request waits for a running command to finish before it
+    /// is refused as busy.
+    #[serde(default)]
+    pub queue_secs: u64,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+}
+
+/// Commands of the same group never run at the same time.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub name: String,
+    /// Program names or absolute paths, matched like policy rules do.
+    pub program: Vec<String>,
+}
+
+pub struct Throttle {
+    config: ThrottleConfig,
+    state: Mutex<State>,
+    released: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    running_per_caller: HashMap<String, usize>,
+    busy_groups: HashSet<String>,
+    /// Start times within the last [`RATE_WINDOW`], oldest first.
+    started: VecDeque<(Instant, String)>,
+}
+
+/// Held for as long as a command runs.
+pub struct Permit {
+    throttle: Arc<Throttle>,
+    caller: String,
+    groups: Vec<String>,
+}
+
+enum Refusal {
+    /// Another command has to finish first.
+    Full(String),
+    /// Too many commands were started recently.
+    Rate(String, Duration),
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+            released: Notify::new(),
+        }
+    }
+
+    pub async fn acquire(
+        self: &Arc<Self>,
+        exec: &Exec,
+        program: Option<&Path>,
+        caller: &Caller,
+    ) -> Result<Permit, Rejection> {
+        let key = caller_key(caller);
+        let groups: Vec<String> = self
+            .config
+            .groups
+            .iter()
+            .filter(|group| policy::matches_program(&group.program, &exec.cmd, program))
+            .map(|group| group.name.clone())
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(self.config.queue_secs);
+        loop {
+            // register before checking so that a release in between is not missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            let reason = match self.try_acquire(&key, &groups) {
+                Ok(()) => {
+                    return Ok(Permit {
+                        throttle: self.clone(),
+                        caller: key,
+                        groups,
+                    })
+                }
+                Err(Refusal::Rate(reason, retry_after)) => return Err(busy(reason, retry_after)),
+                Err(Refusal::Full(reason)) => reason,
+            };
+            if time::timeout_at(deadline, released).await.is_err() {
+                return Err(busy(reason, Duration::from_secs(1)));
+            }
+        }
+    }
+
+    fn try_acquire(&self, key: &str, groups: &[String]) -> Result<(), Refusal> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        while let Some(&(started, _)) = state.started.front() {
+            if now.duration_since(started) < RATE_WINDOW {
+                break;
+            }
+            state.started.pop_front();
+        }
+
+        let running_by_caller = state.running_per_caller.get(key).copied().unwrap_or(0);
+        if let Some(max) = self.config.max_running {
+            if state.running >= max {
+                return Err(Refusal::Full(format!(
+                    "too many commands are running (limit {})",
+                    max
+                )));
+            }
+        }
+        if let Some(max) = self.config.max_running_per_caller {
+            if running_by_caller >= max {
+                return Err(Refusal::Full(format!(
+                    "too many commands are running for {} (limit {})",
+                    key, max
+                )));
+            }
+        }
+        if let Some(group) = groups
+            .iter()
+            .find(|&group| state.busy_groups.contains(group))
+        {
+            return Err(Refusal::Full(format!(
+                "another command of group {} is running",
+                group
+            )));
+        }
+        if let Some(max) = self.config.max_per_minute {
+            if state.started.len() >= max {
+                let (oldest, _) = state.started[state.started.len() - max];
+                return Err(Refusal::Rate(
+                    format!("more than {} commands were started in the last minute", max),
+                    RATE_WINDOW - now.duration_since(oldest),
+                ));
+            }
+        }
+        if let Some(max) = self.config.max_per_minute_per_caller {
+            let by_caller: Vec<Instant> = state
+                .started
+                .iter()
+                .filter(|(_, started_by)| started_by == key)
+                .map(|&(started, _)| started)
+                .collect();
+            if by_caller.len() >= max {
+                let oldest = by_caller[by_caller.len() - max];
+                return Err(Refusal::Rate(
+                    format!(
+                        "more than {} commands were started for {} in the last minute",
+                        max, key
+                    ),
+                    RATE_WINDOW - now.duration_since(oldest),
+                ));
+            }
+        }
+
+        state.running += 1;
+        *state.running_per_caller.entry(key.to_owned()).or_default() += 1;
+        state.busy_groups.extend(groups.iter().cloned());
+        state.started.push_back((now, key.to_owned()));
+        Ok(())
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.throttle.state.lock().unwrap();
+        state.running -= 1;
+        if let Some(running) = state.running_per_caller.get_mut(&self.caller) {
+            *running -= 1;
+            if *running == 0 {
+                state.running_per_caller.remove(&self.caller);
+            }
+        }
+        for group in &self.groups {
+            state.busy_groups.remove(group);
+        }
+        drop(state);
+        self.throttle.released.notify_waiters();
+    }
+}
+
+/// Who per-caller limits are counted against: the paired client if there
+/// is one, otherwise the remote host, otherwise the local user. Every
+/// connection through a forwarded agent comes from the local user's `ssh`,
+/// so without pairing or session-bind all callers share the `uid` bucket,
+/// and the per-caller limits act as a second set of global ones.
+fn caller_key(caller: &Caller) -> String {
+    if let Some(client_id) = &caller.paired_client {
+        return format!("client {}", client_id);
+    }
+    if let Some(host) = caller.remote_host() {
+        return format!("host {}", host.fingerprint);
+    }
+    match caller.uid {
+        Some(uid) => format!("uid {}", uid),
+        None => "unknown caller".into(),
+    }
+}
+
+fn busy(message: String, retry_after: Duration) -> Rejection {
+    Rejection {
+        retry_after_ms: Some(retry_after.as_millis() as u64),
+        ..Rejection::new(RejectionKind::Busy, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_groups_and_running_limits() {
+        let throttle = Arc::new(Throttle::new(ThrottleConfig {
+            max_running_per_caller: Some(2),
+            groups: vec![Group {
+                name: "clipboard".into(),
+                program: vec!["pbcopy".into()],
+            }],
+            ..Default::default()
+        }));
+        let caller = Caller::default();
+        let first = throttle
+            .acquire(&Exec::new("pbcopy", &[]), None, &caller)
+            .await
+            .unwrap();
+        let rejection = throttle
+            .acquire(&Exec::new("pbcopy", &[]), None, &caller)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(rejection.kind, RejectionKind::Busy);
+        let _second = throttle
+            .acquire(&Exec::new("ls", &[]), None, &caller)
+            .await
+            .unwrap();
+        assert!(throttle
+            .acquire(&Exec::new("ls", &[]), None, &caller)
+            .await
+            .is_err());
+        drop(first);
+        throttle
+            .acquire(&Exec::new("pbcopy", &[]), None, &caller)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let throttle = Arc::new(Throttle::new(ThrottleConfig {
+            max_per_minute: Some(1),
+            ..Default::default()
+        }));
+        let caller = Caller::default();
+        drop(
+            throttle
+                .acquire(&Exec::new("ls", &[]), None, &caller)
+                .await
+                .unwrap(),
+        );
+        let rejection = throttle
+            .acquire(&Exec::new("ls", &[]), None, &caller)
+            .await
+            .err()
+            .unwrap();
+        assert!(rejection.retry_after_ms.unwrap() > 59_000);
+    }
+}
@@ -0,0 +1,424 @@
+use std::{collections::BTreeMap, ffi::OsString, path::PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use crate::{
+    policy::Pattern,
+    rpc::{Exec, Rejection, RejectionKind, VerbCall},
+};
+
+/// Named actions that remote hosts can invoke without knowing which local
+/// program implements them. Being in the registry is what allows a verb,
+/// but the rules of the command policy still apply to what it expands to.
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Verbs(BTreeMap<String, Verb>);
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawVerb")]
+pub struct Verb {
+    pub program: String,
+    pub args: Vec<Template>,
+    pub params: BTreeMap<String, Param>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawVerb {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    params: BTreeMap<String, Param>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Param {
+    #[serde(rename = "type", default)]
+    pub kind: ParamType,
+    /// Value used when the caller leaves the parameter out.
+    pub default: Option<String>,
+    /// Whether the caller may leave the parameter out even without a
+    /// default; arguments that refer to it are then omitted.
+    #[serde(default)]
+    pub optional: bool,
+    /// Must fully match the value, on top of the checks of `type`.
+    pub pattern: Option<Pattern>,
+    /// The accepted values of a `choice`.
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// The accepted schemes of a `url`.
+    #[serde(default = "default_schemes")]
+    pub schemes: Vec<String>,
+}
+
+fn default_schemes() -> Vec<String> {
+    vec!["http".into(), "https".into()]
+}
+
+/// Values of the free-form types, `string`, `path` and `url`, may not
+/// start with `-` where they start an argument, so that they cannot be
+/// taken for an option, unless the argument comes after a `--`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamType {
+    #[default]
+    String,
+    Int,
+    Bool,
+    /// A file path.
+    Path,
+    Url,
+    Choice,
+}
+
+/// An argument with `{name}` placeholders; `{{` and `}}` stand for literal
+/// braces.
+#[derive(Debug)]
+pub struct Template(Vec<Segment>);
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+impl TryFrom<RawVerb> for Verb {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawVerb) -> Result<Self> {
+        let args = raw
+            .args
+            .iter()
+            .map(|arg| Template::parse(arg))
+            .collect::<Result<Vec<_>>>()?;
+        for name in args.iter().flat_map(Template::params) {
+            if !raw.params.contains_key(name) {
+                bail!("argument refers to undefined parameter `{}`", name);
+            }
+        }
+        for (name, param) in &raw.params {
+            if param.kind == ParamType::Choice && param.values.is_empty() {
+                bail!("choice parameter `{}` has no values", name);
+            }
+        }
+        Ok(Self {
+            program: raw.program,
+            args,
+            params: raw.params,
+        })
+    }
+}
+
+impl Verbs {
+    /// Turns `call` into the command line it stands for, after checking
+    /// its parameters.
+    pub fn expand(&self, call: &VerbCall) -> Result<Exec, Rejection> {
+        let verb = self
+            .0
+            .get(&call.verb)
+            .ok_or_else(|| rejection(format!("unknown verb `{}`", call.verb)))?;
+        if let Some(name) = call
+            .params
+            .keys()
+            .find(|name| !verb.params.contains_key(*name))
+        {
+            return Err(rejection(format!(
+                "verb `{}` has no parameter `{}`",
+                call.verb, name
+            )));
+        }
+        let mut values = BTreeMap::new();
+        for (name, param) in &verb.params {
+            let value = match call.params.get(name).or(param.default.as_ref()) {
+                Some(value) => value,
+                None if param.optional => continue,
+                None => {
+                    return Err(rejection(format!(
+                        "verb `{}` requires parameter `{}`",
+                        call.verb, name
+                    )))
+                }
+            };
+            param.check(value).map_err(|err| {
+                rejection(format!(
+                    "invalid parameter `{}` of verb `{}`: {}",
+                    name, call.verb, err
+                ))
+            })?;
+            values.insert(name.as_str(), value.as_str());
+        }
+        let mut args = vec![];
+        let mut path_args = vec![];
+        let mut after_separator = false;
+        for template in &verb.args {
+            let Some(arg) = template.expand(&values) else {
+                continue;
+            };
+            if !after_separator && arg.starts_with('-') {
+                if let Some(name) = template.leading_free_form(&verb.params) {
+                    return Err(rejection(format!(
+                        "invalid parameter `{}` of verb `{}`: must not start with `-`",
+                        name, call.verb
+                    )));
+                }
+            }
+            after_separator |= template.is_separator();
+            if template.is_path(&verb.params) {
+                path_args.push(args.len());
+            }
+            args.push(arg);
+        }
+        Ok(Exec {
+            cmd: verb.program.clone().into(),
+            args: args.into_iter().map(OsString::from).collect(),
+            envs: call
+                .envs
+                .iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+            cwd: call.cwd.as_ref().map(PathBuf::from),
+            path_args,
+        })
+    }
+}
+
+impl Param {
+    fn check(&self, value: &str) -> Result<()> {
+        if value.contains('\0') {
+            bail!("must not contain NUL");
+        }
+        match self.kind {
+            ParamType::String => {}
+            ParamType::Int => {
+                value
+                    .parse::<i64>()
+                    .map_err(|_| anyhow!("not an integer"))?;
+            }
+            ParamType::Bool => {
+                if value != "true" && value != "false" {
+                    bail!("must be `true` or `false`");
+                }
+            }
+            ParamType::Path => {
+                if value.is_empty() {
+                    bail!("not a path");
+                }
+            }
+            ParamType::Url => {
+                let scheme = value
+                    .split_once(':')
+                    .map(|(scheme, _)| scheme.to_ascii_lowercase())
+                    .ok_or_else(|| anyhow!("not a URL"))?;
+                if !self.schemes.contains(&scheme) {
+                    bail!("URL scheme `{}` is not allowed", scheme);
+                }
+            }
+            ParamType::Choice => {
+                if !self.values.iter().any(|choice| choice == value) {
+                    bail!("must be one of {}", self.values.join(", "));
+                }
+            }
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(value.as_bytes()) {
+                bail!("does not match the pattern");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Template {
+    fn parse(template: &str) -> Result<Self> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("unclosed placeholder in `{}`", template),
+                        }
+                    }
+                    if name.is_empty() {
+                        bail!("empty placeholder in `{}`", template);
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Param(name));
+                }
+                '}' => bail!("unmatched `}}` in `{}`", template),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self(segments))
+    }
+
+    fn params(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|segment| match segment {
+            Segment::Param(name) => Some(name.as_str()),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    /// Whether the whole argument is a single `path` parameter.
+    fn is_path(&self, params: &BTreeMap<String, Param>) -> bool {
+        match self.0.as_slice() {
+            [Segment::Param(name)] => params
+                .get(name)
+                .is_some_and(|param| param.kind == ParamType::Path),
+            _ => false,
+        }
+    }
+
+    /// The parameter the argument starts with, if it is of a free-form
+    /// type.
+    fn leading_free_form<'a>(&'a self, params: &BTreeMap<String, Param>) -> Option<&'a str> {
+        let Some(Segment::Param(name)) = self.0.first() else {
+            return None;
+        };
+        let kind = params.get(name)?.kind;
+        matches!(kind, ParamType::String | ParamType::Path | ParamType::Url).then_some(name)
+    }
+
+    /// Whether the argument is a literal `--`, after which nothing is
+    /// taken for an option.
+    fn is_separator(&self) -> bool {
+        matches!(self.0.as_slice(), [Segment::Literal(literal)] if literal == "--")
+    }
+
+    /// Returns `None` if a parameter it refers to was left out.
+    fn expand(&self, values: &BTreeMap<&str, &str>) -> Option<String> {
+        let mut arg = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => arg.push_str(literal),
+                Segment::Param(name) => arg.push_str(values.get(name.as_str())?),
+            }
+        }
+        Some(arg)
+    }
+}
+
+fn rejection(message: String) -> Rejection {
+    Rejection::new(RejectionKind::Verb, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(verb: &str, params: &[(&str, &str)]) -> VerbCall {
+        VerbCall {
+            verb: verb.into(),
+            params: params
+                .iter()
+                .map(|&(name, value)| (name.into(), value.into()))
+                .collect(),
+            envs: Default::default(),
+            cwd: None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let verbs: Verbs = toml::from_str(
+            r#"
+            [open-url]
+            program = "xdg-open"
+            args = ["{url}"]
+            params.url.type = "url"
+
+            [notify]
+            program = "notify-send"
+            args = ["--urgency={urgency}", "{title}", "{body}"]
+            params.title = {}
+            params.body = { optional = true }
+            params.urgency = { type = "choice", values = ["low", "normal"], default = "normal" }
+            "#,
+        )
+        .unwrap();
+        let exec = verbs
+            .expand(&call("open-url", &[("url", "https://example.com")]))
+            .unwrap();
+        assert_eq!(exec.cmd, "xdg-open");
+        assert_eq!(exec.args, vec!["https://example.com"]);
+        assert!(verbs
+            .expand(&call("open-url", &[("url", "file:///etc/passwd")]))
+            .is_err());
+        let exec = verbs.expand(&call("notify", &[("title", "hi")])).unwrap();
+        assert_eq!(exec.args, vec!["--urgency=normal", "hi"]);
+        assert!(verbs
+            .expand(&call("notify", &[("title", "hi"), ("urgency", "high")]))
+            .is_err());
+        assert!(verbs.expand(&call("notify", &[])).is_err());
+        assert!(verbs.expand(&call("rm", &[])).is_err());
+    }
+
+    #[test]
+    fn test_leading_dash() {
+        let verbs: Verbs = toml::from_str(
+            r#"
+            [clone]
+            program = "git"
+            args = ["clone", "{url}", "{dir}"]
+            params.url.schemes = ["https", "ssh"]
+            params.url.type = "url"
+            params.dir.type = "path"
+
+            [grep]
+            program = "grep"
+            args = ["--color={color}", "--", "{pattern}", "{file}"]
+            params.color = {}
+            params.pattern = {}
+            params.file.type = "path"
+            "#,
+        )
+        .unwrap();
+        let clone = |dir| call("clone", &[("url", "https://example.com/r"), ("dir", dir)]);
+        assert!(verbs.expand(&clone("repo")).is_ok());
+        let rejection = verbs.expand(&clone("--upload-pack=touch x")).unwrap_err();
+        assert_eq!(rejection.kind, RejectionKind::Verb);
+        let upload_pack = call("clone", &[("url", "--upload-pack=x"), ("dir", "repo")]);
+        assert!(verbs.expand(&upload_pack).is_err());
+
+        // after `--` and inside an argument, a dash is only data
+        let grep = call(
+            "grep",
+            &[("color", "-never"), ("pattern", "-v"), ("file", "-")],
+        );
+        let exec = verbs.expand(&grep).unwrap();
+        assert_eq!(exec.args, vec!["--color=-never", "--", "-v", "-"]);
+    }
+
+    #[test]
+    fn test_undefined_parameter() {
+        let result: Result<Verbs, _> = toml::from_str(
+            r#"
+            [copy]
+            program = "pbcopy"
+            args = ["{oops}"]
+            "#,
+        );
+        assert!(result.is_err());
+    }
+}